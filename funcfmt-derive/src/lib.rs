@@ -0,0 +1,208 @@
+//! Procedural macros for `funcfmt`. These are re-exported by `funcfmt` with the `derive` and
+//! `macros` features, and should be used from there.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream};
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Expr, Fields, Index, LitStr, Member, Path, Token,
+};
+
+mod parse;
+
+/// Derive `funcfmt::FormatKeys`, generating a `FormatMap<Self>` with one key per field.
+///
+/// Each field's key is its name (or index, for tuple structs), and its callback formats the field
+/// with `Display`. Fields can be customized with `#[funcfmt(...)]`:
+///
+/// - `rename = "name"`: Use `name` as the key instead of the field name.
+/// - `skip`: Don't generate a key for this field.
+/// - `with = path`: Call `path(&field)` instead of using `Display`. The function must return an
+///   `Option<String>`, just like a regular callback.
+#[proc_macro_derive(FormatKeys, attributes(funcfmt))]
+pub fn derive_format_keys(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct FieldOpts {
+    rename: Option<String>,
+    skip: bool,
+    with: Option<Path>,
+}
+
+fn parse_field_opts(field: &syn::Field) -> Result<FieldOpts, Error> {
+    let mut opts = FieldOpts {
+        rename: None,
+        skip: false,
+        with: None,
+    };
+    for attr in &field.attrs {
+        if !attr.path().is_ident("funcfmt") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                opts.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("skip") {
+                opts.skip = true;
+            } else if meta.path.is_ident("with") {
+                opts.with = Some(meta.value()?.parse::<Path>()?);
+            } else {
+                return Err(meta.error("unknown funcfmt attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(opts)
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new_spanned(
+                input,
+                "FormatKeys can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut inserts = Vec::new();
+    for (idx, field) in fields.iter().enumerate() {
+        let opts = parse_field_opts(field)?;
+        if opts.skip {
+            continue;
+        }
+
+        let (member, default_key) = match (&field.ident, fields) {
+            (Some(ident), Fields::Named(_)) => {
+                (Member::Named(ident.clone()), ident.unraw().to_string())
+            }
+            _ => (Member::Unnamed(Index::from(idx)), idx.to_string()),
+        };
+        let key = opts.rename.unwrap_or(default_key);
+        if keys.contains(&key) {
+            return Err(Error::new_spanned(field, format!("duplicate key '{key}'")));
+        }
+
+        let value = match opts.with {
+            Some(path) => quote!(#path(&data.#member)),
+            None => quote! {
+                ::core::option::Option::Some(
+                    ::funcfmt::__private::ToString::to_string(&data.#member)
+                )
+            },
+        };
+        inserts.push(quote! {
+            let cb: ::funcfmt::FormatterCallback<Self> =
+                ::funcfmt::__private::Arc::new(|data: &Self| #value);
+            map.insert(::core::convert::Into::into(#key), cb);
+        });
+        keys.push(key);
+    }
+
+    // Callbacks are stored as `'static` trait objects, so their type parameters must be too
+    let name = &input.ident;
+    let mut generics = input.generics.clone();
+    let params: Vec<_> = generics.type_params().map(|p| p.ident.clone()).collect();
+    let where_clause = generics.make_where_clause();
+    for param in params {
+        where_clause
+            .predicates
+            .push(syn::parse_quote!(#param: 'static));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let nr = keys.len();
+    Ok(quote! {
+        impl #impl_generics ::funcfmt::FormatKeys for #name #ty_generics #where_clause {
+            fn format_map() -> ::funcfmt::FormatMap<Self> {
+                let mut map = ::funcfmt::FormatMap::with_capacity_and_hasher(
+                    #nr,
+                    ::core::default::Default::default(),
+                );
+                #(#inserts)*
+                map
+            }
+        }
+    })
+}
+
+struct TemplateInput {
+    tmpl: LitStr,
+    map: Expr,
+    keys: Option<Expr>,
+}
+
+impl Parse for TemplateInput {
+    fn parse(input: ParseStream<'_>) -> Result<Self, Error> {
+        let tmpl = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let map = input.parse()?;
+        let mut keys = None;
+        if input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let name: syn::Ident = input.parse()?;
+            if name != "keys" {
+                return Err(Error::new(name.span(), "expected `keys = ...`"));
+            }
+            input.parse::<Token![=]>()?;
+            keys = Some(input.parse()?);
+            input.parse::<Option<Token![,]>>()?;
+        }
+        Ok(Self { tmpl, map, keys })
+    }
+}
+
+/// Parse a template at compile time, expanding to code which builds its `FormatPieces<T>` from a
+/// map without parsing anything at runtime. See `funcfmt::template!` for details.
+#[proc_macro]
+pub fn template(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as TemplateInput);
+    expand_template(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand_template(input: &TemplateInput) -> Result<TokenStream2, Error> {
+    let tmpl = input.tmpl.value();
+    let tokens = parse::tokenize(&tmpl).map_err(|msg| Error::new(input.tmpl.span(), msg))?;
+
+    let mut names = Vec::new();
+    let mut quoted = Vec::with_capacity(tokens.len());
+    for token in &tokens {
+        let (start, end) = (token.span.start, token.span.end);
+        let text = &token.text;
+        if token.is_key {
+            parse::used_keys(text, &mut names).map_err(|msg| Error::new(input.tmpl.span(), msg))?;
+            quoted.push(quote! {
+                ::funcfmt::TemplateToken::Key {
+                    name: ::funcfmt::__private::Cow::Borrowed(#text),
+                    span: #start..#end,
+                }
+            });
+        } else {
+            quoted.push(
+                quote!(::funcfmt::TemplateToken::Verbatim { text: #text, span: #start..#end }),
+            );
+        }
+    }
+
+    let check = input.keys.as_ref().map(|keys| {
+        quote! {
+            const _: () = ::funcfmt::__private::assert_keys_known(#keys, &[#(#names),*]);
+        }
+    });
+
+    let map = &input.map;
+    Ok(quote! {
+        {
+            #check
+            ::funcfmt::__private::from_tokens(&#map, &[#(#quoted),*])
+        }
+    })
+}
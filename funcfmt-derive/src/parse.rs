@@ -0,0 +1,215 @@
+use std::ops::Range;
+
+/// A template token, as in `funcfmt::TemplateToken`.
+pub struct Token {
+    pub is_key: bool,
+    pub text: String,
+    pub span: Range<usize>,
+}
+
+/// Split a template into verbatim text and keys, with exactly the same semantics as funcfmt's own
+/// tokenizer. Performance doesn't matter here, so this just walks the bytes one by one.
+pub fn tokenize(tmpl: &str) -> Result<Vec<Token>, &'static str> {
+    const IMBALANCED: &str = "imbalanced brackets in template";
+
+    let bytes = tmpl.as_bytes();
+    let mut out = Vec::new();
+    let mut verb_start = 0;
+    let mut pos = 0;
+    let mut trim_next = false;
+
+    let push_verb = |out: &mut Vec<Token>, start: usize, end: usize, trim: (bool, bool)| {
+        let mut text = &tmpl[start..end];
+        let mut start = start;
+        if trim.0 {
+            let trimmed = text.trim_start();
+            start += text.len() - trimmed.len();
+            text = trimmed;
+        }
+        if trim.1 {
+            text = text.trim_end();
+        }
+        if !text.is_empty() {
+            out.push(Token {
+                is_key: false,
+                text: text.to_string(),
+                span: start..start + text.len(),
+            });
+        }
+    };
+
+    while pos < bytes.len() {
+        let cur = bytes[pos];
+        if cur != b'{' && cur != b'}' {
+            pos += 1;
+            continue;
+        }
+        match (cur, bytes.get(pos + 1).copied()) {
+            (b'{', Some(b'{')) | (b'}', Some(b'}')) => {
+                push_verb(&mut out, verb_start, pos, (trim_next, false));
+                trim_next = false;
+                verb_start = pos + 1;
+                pos += 2;
+            }
+            (b'{', _) => {
+                let key_start = pos + 1;
+                let mut key_end = key_start;
+                let mut depth = 0usize;
+                loop {
+                    match bytes.get(key_end) {
+                        Some(b'}') if depth == 0 => break,
+                        Some(b'}') => {
+                            depth -= 1;
+                            key_end += 1;
+                        }
+                        // Brackets may only nest in the branches of a conditional
+                        Some(b'{')
+                            if depth > 0 || find_condition(&tmpl[key_start..key_end]).is_some() =>
+                        {
+                            depth += 1;
+                            key_end += 1;
+                        }
+                        Some(b'{') | None => return Err(IMBALANCED),
+                        Some(b'\\') if matches!(bytes.get(key_end + 1), Some(b) if is_key_escapable(b)) =>
+                        {
+                            key_end += 2;
+                        }
+                        Some(_) => key_end += 1,
+                    }
+                }
+                let (name, trim_before, trim_after) = split_trim_markers(&tmpl[key_start..key_end]);
+                // Conditionals are kept exactly as written
+                let name = match find_condition(name) {
+                    Some(_) => name.to_string(),
+                    None => unescape_key(name),
+                };
+                push_verb(&mut out, verb_start, pos, (trim_next, trim_before));
+                out.push(Token {
+                    is_key: true,
+                    text: name,
+                    span: pos..key_end + 1,
+                });
+                trim_next = trim_after;
+                verb_start = key_end + 1;
+                pos = verb_start;
+            }
+            (_, None) => break,
+            (_, Some(_)) => return Err(IMBALANCED),
+        }
+    }
+
+    push_verb(&mut out, verb_start, bytes.len(), (trim_next, false));
+    Ok(out)
+}
+
+/// Split trim markers like `{- key -}` from the contents of a key.
+fn split_trim_markers(raw: &str) -> (&str, bool, bool) {
+    let (name, before) = match raw.strip_prefix('-') {
+        Some(rest) if rest.starts_with(char::is_whitespace) => (rest.trim_start(), true),
+        _ => (raw, false),
+    };
+    match name.strip_suffix('-') {
+        Some(rest) if rest.ends_with(char::is_whitespace) => (rest.trim_end(), before, true),
+        _ => (name, before, false),
+    }
+}
+
+fn is_key_escapable(b: &u8) -> bool {
+    matches!(b, b'{' | b'}' | b'\\')
+}
+
+/// The index of the first unescaped `?` in a key, which makes it a conditional like
+/// `{key?then:else}`.
+pub fn find_condition(key: &str) -> Option<usize> {
+    let bytes = key.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'?' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Push every key that `name` uses to `out`: for a conditional, its condition key and any keys
+/// nested in its branches, and otherwise `name` itself.
+pub fn used_keys(name: &str, out: &mut Vec<String>) -> Result<(), &'static str> {
+    let (key, then, otherwise) = match split_conditional(name) {
+        Some(parts) => parts,
+        None => {
+            out.push(name.to_string());
+            return Ok(());
+        }
+    };
+    out.push(key);
+    for branch in [then, otherwise] {
+        for token in tokenize(&branch)? {
+            if token.is_key {
+                used_keys(&token.text, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Split a conditional key into its key and branches, as in funcfmt's own parser, or return
+/// `None` if `name` isn't a conditional. The branches are converted to regular template syntax.
+pub fn split_conditional(name: &str) -> Option<(String, String, String)> {
+    let q = find_condition(name)?;
+    let mut branches = [String::new(), String::new()];
+    let mut cur = 0;
+    let mut depth = 0usize;
+    let mut chars = name[q + 1..].chars();
+    while let Some(c) = chars.next() {
+        let out = &mut branches[cur];
+        if depth > 0 {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '\\' => match chars.next() {
+                Some('{') => out.push_str("{{"),
+                Some('}') => out.push_str("}}"),
+                Some(next @ (':' | '?' | '\\')) => out.push(next),
+                Some(next) => {
+                    out.push('\\');
+                    out.push(next);
+                }
+                None => out.push('\\'),
+            },
+            '{' => {
+                depth += 1;
+                out.push(c);
+            }
+            ':' if cur == 0 => cur = 1,
+            _ => out.push(c),
+        }
+    }
+    let [then, otherwise] = branches;
+    Some((unescape_key(&name[..q]), then, otherwise))
+}
+
+/// Remove backslash escapes like `\{` from a key name.
+fn unescape_key(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek().filter(|n| matches!(n, '{' | '}' | '\\')) {
+                out.push(next);
+                chars.next();
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    out
+}
@@ -0,0 +1,126 @@
+#ifndef FUNCFMT_H
+#define FUNCFMT_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit by hand. */
+
+#include <stddef.h>
+
+/**
+ * The result of a call through the C interface.
+ */
+typedef enum FuncfmtStatus {
+  /**
+   * The call succeeded.
+   */
+  FUNCFMT_STATUS_OK = 0,
+  /**
+   * A required pointer argument was null.
+   */
+  FUNCFMT_STATUS_NULL_POINTER = 1,
+  /**
+   * A string argument was not valid UTF-8.
+   */
+  FUNCFMT_STATUS_INVALID_UTF8 = 2,
+  /**
+   * The template couldn't be parsed, for example because its brackets were imbalanced.
+   */
+  FUNCFMT_STATUS_INVALID_TEMPLATE = 3,
+  /**
+   * The template used a key which wasn't given to `funcfmt_compile`.
+   */
+  FUNCFMT_STATUS_UNKNOWN_KEY = 4,
+  /**
+   * A key used by the template had a null value when rendering.
+   */
+  FUNCFMT_STATUS_NO_DATA = 5,
+  /**
+   * The number of values given to `funcfmt_render` didn't match the number of keys the
+   * template was compiled with.
+   */
+  FUNCFMT_STATUS_VALUE_COUNT = 6,
+  /**
+   * Any other error.
+   */
+  FUNCFMT_STATUS_OTHER = 7,
+} FuncfmtStatus;
+
+/**
+ * A processed template, created by `funcfmt_compile` and freed by `funcfmt_template_free`.
+ *
+ * The data it is rendered with is the value for each key, by position.
+ */
+typedef struct FuncfmtTemplate FuncfmtTemplate;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Process `tmpl`, which may use the `nkeys` key names in `keys`, and store the result in `out`.
+ *
+ * On failure, `out` is left unchanged, and if `error` isn't null, a message describing the
+ * failure is stored in it, which must be freed with `funcfmt_string_free`.
+ *
+ * # Safety
+ *
+ * `tmpl` and each of the strings in `keys` must be NUL-terminated, and `keys` must point to
+ * `nkeys` strings. `out` must be valid for writes, and so must `error` unless it's null.
+ */
+FuncfmtStatus funcfmt_compile(const char *tmpl,
+                              const char *const *keys,
+                              size_t nkeys,
+                              FuncfmtTemplate **out,
+                              char **error);
+
+/**
+ * Render `tmpl` with `values`, which holds the value for each key passed to `funcfmt_compile`, in
+ * the same order. A null value means that key has no data. The output is stored in `out`, and
+ * must be freed with `funcfmt_string_free`.
+ *
+ * On failure, `out` is left unchanged, and if `error` isn't null, a message describing the
+ * failure is stored in it, which must be freed with `funcfmt_string_free`.
+ *
+ * # Safety
+ *
+ * `tmpl` must have been returned by `funcfmt_compile` and not yet freed. `values` must point to
+ * `nvalues` pointers, each of which is null or NUL-terminated. `out` must be valid for writes,
+ * and so must `error` unless it's null.
+ */
+FuncfmtStatus funcfmt_render(const FuncfmtTemplate *tmpl,
+                             const char *const *values,
+                             size_t nvalues,
+                             char **out,
+                             char **error);
+
+/**
+ * Free a template returned by `funcfmt_compile`. Does nothing if `tmpl` is null.
+ *
+ * # Safety
+ *
+ * `tmpl` must be null, or have been returned by `funcfmt_compile` and not yet freed.
+ */
+void funcfmt_template_free(FuncfmtTemplate *tmpl);
+
+/**
+ * Free a string returned through the C interface. Does nothing if `s` is null.
+ *
+ * # Safety
+ *
+ * `s` must be null, or have been returned by a function in this module and not yet freed.
+ */
+void funcfmt_string_free(char *s);
+
+/**
+ * A static, NUL-terminated description of `status`, which must not be freed.
+ *
+ * This takes the numeric value of a `FuncfmtStatus`, so that values this library doesn't know
+ * about, for example from a newer header, are described as an unknown status rather than being
+ * undefined behaviour.
+ */
+const char *funcfmt_status_str(int status);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FUNCFMT_H */
@@ -0,0 +1,246 @@
+//! Render a template from the command line, with keys bound from JSON, `key=value` pairs, or the
+//! environment.
+
+use funcfmt::providers::{env_formatters, process_formatters};
+use funcfmt::{CompileOptions, JsonLookup, KeyLookup, Render, ToFormatPieces};
+use serde_json::{Map, Value};
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+use std::{env, fmt, fs};
+
+const USAGE: &str = "\
+Usage: funcfmt [OPTIONS] TEMPLATE
+
+Render TEMPLATE and print the result.
+
+Keys are resolved as paths into the bound data, like {user.name} or {items.0}.
+{env.NAME}, {pid}, and {hostname} are always available, as is {now:FORMAT}
+when built with the `time` feature. These take precedence over bound data.
+
+Options:
+  -j, --json FILE     Bind data from the JSON in FILE, or stdin if FILE is -
+  -k, --kv            Bind key=value lines from stdin
+  -e, --env           Bind environment variables as top-level keys
+  -s, --strict        Reject likely mistakes in the template
+  -i, --ignore-case   Match keys case-insensitively
+  -n, --no-newline    Don't print a newline after the output
+  -h, --help          Print this help
+
+When several sources are given, key=value pairs take precedence over JSON,
+which takes precedence over environment variables.";
+
+/// The command line options.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Args {
+    template: String,
+    json: Option<String>,
+    kv: bool,
+    env: bool,
+    strict: bool,
+    ignore_case: bool,
+    no_newline: bool,
+    help: bool,
+}
+
+/// A failure, along with the exit code to use for it.
+#[derive(Debug, PartialEq, Eq)]
+enum CliError {
+    /// The command line was invalid.
+    Usage(String),
+    /// The input couldn't be read or parsed, or the template couldn't be rendered.
+    Failed(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage(msg) | Self::Failed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<funcfmt::Error> for CliError {
+    fn from(err: funcfmt::Error) -> Self {
+        Self::Failed(err.to_string())
+    }
+}
+
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args, CliError> {
+    let mut out = Args::default();
+    let mut template = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-j" | "--json" => {
+                let file = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage(format!("{arg} requires a file")))?;
+                out.json = Some(file);
+            }
+            "-k" | "--kv" => out.kv = true,
+            "-e" | "--env" => out.env = true,
+            "-s" | "--strict" => out.strict = true,
+            "-i" | "--ignore-case" => out.ignore_case = true,
+            "-n" | "--no-newline" => out.no_newline = true,
+            "-h" | "--help" => out.help = true,
+            "--" => {
+                template = args.next();
+                break;
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(CliError::Usage(format!("unknown option: {arg}")));
+            }
+            _ if template.is_none() => template = Some(arg),
+            _ => return Err(CliError::Usage(format!("unexpected argument: {arg}"))),
+        }
+    }
+    if let Some(extra) = args.next() {
+        return Err(CliError::Usage(format!("unexpected argument: {extra}")));
+    }
+    match template {
+        Some(template) => out.template = template,
+        None if out.help => {}
+        None => return Err(CliError::Usage("no template given".to_string())),
+    }
+    if out.kv && out.json.as_deref() == Some("-") {
+        return Err(CliError::Usage(
+            "--kv and --json - can't both read stdin".to_string(),
+        ));
+    }
+    Ok(out)
+}
+
+/// Parse `key=value` lines into `data`. Blank lines and lines starting with `#` are ignored.
+fn parse_kv(input: &str, data: &mut Map<String, Value>) -> Result<(), CliError> {
+    for (idx, line) in input.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| CliError::Failed(format!("line {}: expected key=value", idx + 1)))?;
+        data.insert(key.trim_end().to_string(), Value::String(value.to_string()));
+    }
+    Ok(())
+}
+
+fn read_stdin() -> Result<String, CliError> {
+    let mut input = String::new();
+    io::stdin()
+        .read_to_string(&mut input)
+        .map_err(|err| CliError::Failed(format!("failed to read stdin: {err}")))?;
+    Ok(input)
+}
+
+/// Gather the data from every source requested in `args`, or `None` if there are none.
+fn load_data(args: &Args) -> Result<Option<Value>, CliError> {
+    let json = match args.json.as_deref() {
+        Some("-") => Some(read_stdin()?),
+        Some(path) => Some(
+            fs::read_to_string(path)
+                .map_err(|err| CliError::Failed(format!("failed to read {path}: {err}")))?,
+        ),
+        None => None,
+    };
+    let json: Option<Value> = json
+        .map(|json| serde_json::from_str(&json))
+        .transpose()
+        .map_err(|err| CliError::Failed(format!("invalid JSON: {err}")))?;
+    if !args.env && !args.kv {
+        return Ok(json);
+    }
+
+    let mut data = Map::new();
+    if args.env {
+        data.extend(env::vars().map(|(k, v)| (k, Value::String(v))));
+    }
+    match json {
+        Some(Value::Object(obj)) => data.extend(obj),
+        Some(_) => {
+            return Err(CliError::Failed(
+                "JSON must be an object to combine with other sources".to_string(),
+            ))
+        }
+        None => {}
+    }
+    if args.kv {
+        parse_kv(&read_stdin()?, &mut data)?;
+    }
+    Ok(Some(Value::Object(data)))
+}
+
+/// Render the template, with the built-in keys taking precedence over `data`, if any.
+fn render_with<L: KeyLookup<Value>>(
+    builtins: L,
+    args: &Args,
+    data: Option<Value>,
+) -> Result<String, CliError> {
+    let opts = CompileOptions::new()
+        .strict(args.strict)
+        .case_insensitive(args.ignore_case);
+    let out = match data {
+        Some(data) => (builtins, JsonLookup)
+            .to_format_pieces_with(&args.template, &opts)?
+            .render(&data)?,
+        None => builtins
+            .to_format_pieces_with(&args.template, &opts)?
+            .render(&Value::Null)?,
+    };
+    Ok(out)
+}
+
+fn run(args: &Args) -> Result<String, CliError> {
+    let data = load_data(args)?;
+    #[cfg(feature = "time")]
+    let builtins = (
+        process_formatters(),
+        env_formatters(),
+        funcfmt::providers::datetime_formatters(),
+    );
+    #[cfg(not(feature = "time"))]
+    let builtins = (process_formatters(), env_formatters());
+    render_with(builtins, args, data)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("funcfmt: {err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    if args.help {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    match run(&args) {
+        Ok(out) => {
+            let mut stdout = io::stdout().lock();
+            let res = if args.no_newline {
+                stdout.write_all(out.as_bytes())
+            } else {
+                writeln!(stdout, "{out}")
+            };
+            match res.and_then(|()| stdout.flush()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(err) => {
+                    eprintln!("funcfmt: failed to write output: {err}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Err(err) => {
+            eprintln!("funcfmt: {err}");
+            match err {
+                CliError::Usage(_) => ExitCode::from(2),
+                CliError::Failed(_) => ExitCode::FAILURE,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod main_test;
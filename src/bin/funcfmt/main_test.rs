@@ -0,0 +1,63 @@
+use super::*;
+
+fn args(list: &[&str]) -> Result<Args, CliError> {
+    parse_args(list.iter().map(|s| s.to_string()))
+}
+
+#[test]
+fn parse_args_flags() {
+    assert_eq!(
+        args(&["-j", "data.json", "--strict", "-n", "{a}"]),
+        Ok(Args {
+            template: "{a}".to_string(),
+            json: Some("data.json".to_string()),
+            strict: true,
+            no_newline: true,
+            ..Args::default()
+        })
+    );
+    assert_eq!(
+        args(&["--", "-{a}"]).map(|a| a.template),
+        Ok("-{a}".to_string())
+    );
+    assert_eq!(args(&["-h"]).map(|a| a.help), Ok(true));
+}
+
+#[test]
+fn parse_args_errors() {
+    assert!(matches!(args(&[]), Err(CliError::Usage(_))));
+    assert!(matches!(args(&["--json"]), Err(CliError::Usage(_))));
+    assert!(matches!(args(&["-x", "{a}"]), Err(CliError::Usage(_))));
+    assert!(matches!(args(&["{a}", "{b}"]), Err(CliError::Usage(_))));
+    assert!(matches!(
+        args(&["--kv", "--json", "-", "{a}"]),
+        Err(CliError::Usage(_))
+    ));
+}
+
+#[test]
+fn kv_pairs() {
+    let mut data = Map::new();
+    parse_kv("# comment\nname = world\n\nurl=a=b\n", &mut data).unwrap();
+    assert_eq!(data["name"], Value::String(" world".to_string()));
+    assert_eq!(data["url"], Value::String("a=b".to_string()));
+
+    assert_eq!(
+        parse_kv("a=1\nnope\n", &mut data),
+        Err(CliError::Failed("line 2: expected key=value".to_string()))
+    );
+}
+
+#[test]
+fn render_json_and_builtins() {
+    let args = args(&["{user.name} {pid}"]).unwrap();
+    let data = serde_json::json!({"user": {"name": "chris"}});
+    assert_eq!(
+        render_with(process_formatters(), &args, Some(data)),
+        Ok(format!("chris {}", std::process::id()))
+    );
+    assert!(matches!(
+        render_with(process_formatters(), &args, None),
+        Err(CliError::Failed(_))
+    ));
+}
@@ -0,0 +1,342 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+#[cfg(feature = "std")]
+use std::ffi::OsString;
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+
+use crate::compiled::CompiledTemplate;
+use crate::render::{PieceRef, PieceSource};
+use crate::{
+    keys_eq_ignore_case, Error, FnvHashMap, FormatPieces, Formatter, FormatterCallback, KeyLookup,
+    KeyString, SameBindings,
+};
+
+/// A callback returning raw bytes rather than a `String`, for output which may not be valid UTF-8.
+pub type BytesCallback<T> = Arc<dyn Fn(&T) -> Option<Vec<u8>> + Send + Sync>;
+
+/// A formatter whose lookup also provided a callback returning raw bytes, such as one from a
+/// `BytesMap<T>`. `RenderBytes` outputs the bytes unmodified, and everything else uses the
+/// `String` from the formatter's callback.
+pub struct BytesFormatter<T: ?Sized> {
+    formatter: Formatter<T>,
+    bytes: BytesCallback<T>,
+}
+
+impl<T: ?Sized> BytesFormatter<T> {
+    /// Create a formatter which renders as `formatter`, except with `RenderBytes`, where it
+    /// outputs the result of `bytes`.
+    pub fn new(formatter: Formatter<T>, bytes: BytesCallback<T>) -> Self {
+        Self { formatter, bytes }
+    }
+
+    /// The formatter used when rendering to a `String`.
+    pub fn formatter(&self) -> &Formatter<T> {
+        &self.formatter
+    }
+
+    /// The callback returning raw bytes.
+    pub fn bytes(&self) -> &BytesCallback<T> {
+        &self.bytes
+    }
+}
+
+// Derived Clone would needlessly require `T: Clone`
+impl<T: ?Sized> Clone for BytesFormatter<T> {
+    fn clone(&self) -> Self {
+        Self {
+            formatter: self.formatter.clone(),
+            bytes: Arc::clone(&self.bytes),
+        }
+    }
+}
+
+/// Compared by key only, like `Formatter<T>`. Use `SameBindings` to also compare the callbacks.
+impl<T: ?Sized> PartialEq for BytesFormatter<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.formatter == other.formatter
+    }
+}
+impl<T: ?Sized> Eq for BytesFormatter<T> {}
+
+impl<T: ?Sized> Hash for BytesFormatter<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.formatter.hash(state);
+    }
+}
+
+impl<T: ?Sized> SameBindings for BytesFormatter<T> {
+    fn same_bindings(&self, other: &Self) -> bool {
+        self.formatter.same_bindings(&other.formatter) && Arc::ptr_eq(&self.bytes, &other.bytes)
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for BytesFormatter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BytesFormatter(key: {})", self.formatter.key)
+    }
+}
+
+/// A map of keys to callbacks returning raw bytes, for use with `RenderBytes`.
+///
+/// With `RenderBytes::render_bytes`, the bytes are output as-is. With `Render`, they are converted
+/// to UTF-8 lossily, replacing invalid sequences with `U+FFFD`. `BytesMap<T>` implements
+/// `KeyLookup<T>`, so it can be combined with other sources of callbacks using a tuple.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{BytesMap, Render, RenderBytes, ToFormatPieces};
+///
+/// let mut bmap = BytesMap::new();
+/// bmap.insert("name", |data: &Vec<u8>| Some(data.clone()));
+///
+/// let fp = bmap.to_format_pieces("<{name}>").unwrap();
+/// let data = b"caf\xe9".to_vec();
+/// assert_eq!(fp.render_bytes(&data), Ok(b"<caf\xe9>".to_vec()));
+/// assert_eq!(fp.render(&data), Ok("<caf\u{fffd}>".to_string()));
+/// ```
+pub struct BytesMap<T: ?Sized> {
+    map: FnvHashMap<KeyString, BytesCallback<T>>,
+}
+
+impl<T: ?Sized> BytesMap<T> {
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Self {
+            map: FnvHashMap::default(),
+        }
+    }
+
+    /// Register `cb` for `key`, replacing any existing callback. The callback can return anything
+    /// which converts into a `Vec<u8>`.
+    pub fn insert<K, V, F>(&mut self, key: K, cb: F)
+    where
+        K: Into<KeyString>,
+        V: Into<Vec<u8>>,
+        F: Fn(&T) -> Option<V> + Send + Sync + 'static,
+    {
+        let cb: BytesCallback<T> = Arc::new(move |data| cb(data).map(Into::into));
+        self.map.insert(key.into(), cb);
+    }
+
+    /// Like `insert`, but for a callback returning an `OsString`, such as a file name. With
+    /// `RenderBytes::render_osstring`, the output is kept intact even if it isn't valid Unicode.
+    ///
+    /// On Unix, the `OsString` is used as raw bytes. Elsewhere, it is converted to UTF-8 lossily,
+    /// since its representation isn't exposed.
+    #[cfg(feature = "std")]
+    pub fn insert_os<K, V, F>(&mut self, key: K, cb: F)
+    where
+        K: Into<KeyString>,
+        V: Into<OsString>,
+        F: Fn(&T) -> Option<V> + Send + Sync + 'static,
+    {
+        let cb: BytesCallback<T> = Arc::new(move |data| Some(os_to_bytes(cb(data)?.into())));
+        self.map.insert(key.into(), cb);
+    }
+
+    /// The callback registered for `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&BytesCallback<T>> {
+        self.map.get(key)
+    }
+}
+
+impl<T: ?Sized> Default for BytesMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> Clone for BytesMap<T> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for BytesMap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.map.keys()).finish()
+    }
+}
+
+impl<T: ?Sized> BytesMap<T> {
+    /// The registered key matching `key` case-insensitively, preferring an exact match, and
+    /// otherwise the one which sorts first, so that the result doesn't depend on iteration order.
+    fn find_ignore_case(&self, key: &str) -> Option<&KeyString> {
+        match self.map.get_key_value(key) {
+            Some((k, _)) => Some(k),
+            None => self
+                .map
+                .keys()
+                .filter(|k| keys_eq_ignore_case(k, key))
+                .min(),
+        }
+    }
+}
+
+impl<T: ?Sized + 'static> KeyLookup<T> for BytesMap<T> {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        let cb = Arc::clone(self.map.get(key)?);
+        Some(Arc::new(move |data| {
+            Some(String::from_utf8_lossy(&cb(data)?).into_owned())
+        }))
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.lookup(self.find_ignore_case(key)?)
+    }
+
+    fn lookup_bytes(&self, key: &str) -> Option<BytesCallback<T>> {
+        self.map.get(key).cloned()
+    }
+
+    fn lookup_bytes_ignore_case(&self, key: &str) -> Option<BytesCallback<T>> {
+        self.lookup_bytes(self.find_ignore_case(key)?)
+    }
+}
+
+/// A trait for rendering format pieces into raw bytes, which unlike `Render` don't have to be
+/// valid UTF-8.
+///
+/// Keys whose lookup provided a `BytesCallback<T>`, such as those from a `BytesMap<T>`, output
+/// their bytes unmodified. All other keys output their `String` as UTF-8.
+pub trait RenderBytes<T: ?Sized> {
+    /// Given some data, render the given format pieces into a `Vec<u8>`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, BytesMap, RenderBytes, ToFormatPieces};
+    ///
+    /// let fmap = fm!{"len" => |data: &Vec<u8>| Some(data.len().to_string())};
+    /// let mut bmap = BytesMap::new();
+    /// bmap.insert("raw", |data: &Vec<u8>| Some(data.clone()));
+    ///
+    /// let fp = (fmap, bmap).to_format_pieces("{raw}: {len}").unwrap();
+    /// assert_eq!(fp.render_bytes(&vec![0xff, 0xfe]), Ok(b"\xff\xfe: 2".to_vec()));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// - `Error::NoData` if the callback returns `None`
+    /// - `Error::Overflow` if internal capacity calculation overflows
+    fn render_bytes(&self, data: &T) -> Result<Vec<u8>, Error>;
+
+    /// Like `render_bytes`, but producing an `OsString`, so that the output of callbacks
+    /// registered with `BytesMap::insert_os` is kept intact.
+    ///
+    /// On Unix, the bytes are used as-is. Elsewhere, they are converted to UTF-8 lossily.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{BytesMap, RenderBytes, ToFormatPieces};
+    /// use std::path::{Path, PathBuf};
+    ///
+    /// let mut bmap = BytesMap::new();
+    /// bmap.insert_os("stem", |p: &PathBuf| p.file_stem().map(|s| s.to_os_string()));
+    /// bmap.insert_os("ext", |p: &PathBuf| p.extension().map(|s| s.to_os_string()));
+    ///
+    /// let fp = bmap.to_format_pieces("out/{stem}-small.{ext}").unwrap();
+    /// let path = fp.render_path(&PathBuf::from("in/photo.jpg")).unwrap();
+    /// assert_eq!(path, Path::new("out/photo-small.jpg"));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// The same as for `render_bytes`.
+    #[cfg(feature = "std")]
+    fn render_osstring(&self, data: &T) -> Result<OsString, Error> {
+        self.render_bytes(data).map(bytes_to_os)
+    }
+
+    /// Like `render_osstring`, but producing a `PathBuf`.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `render_bytes`.
+    #[cfg(feature = "std")]
+    fn render_path(&self, data: &T) -> Result<PathBuf, Error> {
+        self.render_osstring(data).map(PathBuf::from)
+    }
+}
+
+/// The bytes of `s`: its raw representation on Unix, and lossy UTF-8 elsewhere.
+#[cfg(feature = "std")]
+fn os_to_bytes(s: OsString) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        std::os::unix::ffi::OsStringExt::into_vec(s)
+    }
+    #[cfg(not(unix))]
+    {
+        s.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// The inverse of `os_to_bytes`.
+#[cfg(feature = "std")]
+fn bytes_to_os(bytes: Vec<u8>) -> OsString {
+    #[cfg(unix)]
+    {
+        std::os::unix::ffi::OsStringExt::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(&bytes).into_owned().into()
+    }
+}
+
+/// Render each piece in turn into `out`, preferring byte callbacks where available.
+fn render_bytes_into<T: ?Sized, P: PieceSource<T> + ?Sized>(
+    pieces: &P,
+    data: &T,
+    out: &mut Vec<u8>,
+) -> Result<(), Error> {
+    for idx in 0..pieces.piece_count() {
+        match pieces.piece(idx) {
+            PieceRef::Verbatim(s) => out.extend_from_slice(s.as_bytes()),
+            PieceRef::Formatter(f) => {
+                let val = (f.cb)(data).ok_or_else(|| Error::NoData(f.key.clone()))?;
+                out.extend_from_slice(val.as_bytes());
+            }
+            PieceRef::Bytes(b) => {
+                let f = b.formatter();
+                let val = (b.bytes())(data).ok_or_else(|| Error::NoData(f.key.clone()))?;
+                out.extend_from_slice(&val);
+            }
+            PieceRef::Conditional(c) => {
+                let taken = (c.condition().cb)(data).is_some();
+                render_bytes_into(c.branch(taken), data, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn render_bytes<T: ?Sized, P: PieceSource<T> + ?Sized>(
+    pieces: &P,
+    data: &T,
+) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(pieces.capacity_hint()?);
+    render_bytes_into(pieces, data, &mut out)?;
+    Ok(out)
+}
+
+impl<T: ?Sized> RenderBytes<T> for FormatPieces<T> {
+    fn render_bytes(&self, data: &T) -> Result<Vec<u8>, Error> {
+        render_bytes(self, data)
+    }
+}
+
+impl<T: ?Sized> RenderBytes<T> for CompiledTemplate<T> {
+    fn render_bytes(&self, data: &T) -> Result<Vec<u8>, Error> {
+        render_bytes(self, data)
+    }
+}
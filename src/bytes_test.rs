@@ -0,0 +1,98 @@
+use super::*;
+
+fn lookup() -> (FormatMap<Vec<u8>>, BytesMap<Vec<u8>>) {
+    let fmap: FormatMap<Vec<u8>> = fm! {
+        "len" => |data: &Vec<u8>| Some(data.len().to_string()),
+        "none" => |_| None,
+    };
+    let mut bmap = BytesMap::new();
+    bmap.insert("raw", |data: &Vec<u8>| Some(data.clone()));
+    bmap.insert("empty", |data: &Vec<u8>| {
+        if data.is_empty() {
+            None
+        } else {
+            Some(&b"\xff"[..])
+        }
+    });
+    (fmap, bmap)
+}
+
+#[test]
+fn render_bytes_keeps_invalid_utf8() {
+    let data = b"a\xffb".to_vec();
+    let fp = lookup().to_format_pieces("[{raw}] {len}").unwrap();
+    assert_eq!(fp.render_bytes(&data), Ok(b"[a\xffb] 3".to_vec()));
+    assert_eq!(fp.render(&data), Ok("[a\u{fffd}b] 3".to_string()));
+
+    let ct = CompiledTemplate::compile(&lookup(), "{raw}{raw}").unwrap();
+    assert_eq!(ct.render_bytes(&data), Ok(b"a\xffba\xffb".to_vec()));
+
+    let fp = lookup().to_format_pieces("{none}").unwrap();
+    assert_eq!(fp.render_bytes(&data), Err(Error::NoData("none".into())));
+}
+
+#[test]
+fn render_bytes_conditionals_and_edits() {
+    let mut fp = lookup()
+        .to_format_pieces("{empty?<{raw}>:-}{empty}")
+        .unwrap();
+    assert_eq!(
+        fp.render_bytes(&b"\xfe".to_vec()),
+        Ok(b"<\xfe>\xff".to_vec())
+    );
+    let fp2 = lookup().to_format_pieces("{empty?<{raw}>:-}").unwrap();
+    assert_eq!(fp2.render_bytes(&vec![]), Ok(b"-".to_vec()));
+
+    // Replacing the callback also replaces its raw bytes
+    fp.replace_key("empty", Arc::new(|_| Some("x".to_string())));
+    assert_eq!(fp.render_bytes(&b"\xfe".to_vec()), Ok(b"<\xfe>x".to_vec()));
+}
+
+#[cfg(unix)]
+#[test]
+fn render_osstring_keeps_non_unicode_names() {
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    let mut bmap = BytesMap::new();
+    bmap.insert_os("name", |data: &OsString| Some(data.clone()));
+    bmap.insert_os("none", |_: &OsString| None::<OsString>);
+    let fp = bmap.to_format_pieces("dir/{name}.bak").unwrap();
+
+    let name = OsStr::from_bytes(b"caf\xe9").to_os_string();
+    assert_eq!(
+        fp.render_osstring(&name),
+        Ok(OsStr::from_bytes(b"dir/caf\xe9.bak").to_os_string())
+    );
+    assert_eq!(
+        fp.render_path(&name),
+        Ok(PathBuf::from(OsStr::from_bytes(b"dir/caf\xe9.bak")))
+    );
+    assert_eq!(
+        fp.render_path(&OsString::from("x")).unwrap(),
+        Path::new("dir/x.bak")
+    );
+
+    let fp = bmap.to_format_pieces("{none}").unwrap();
+    assert_eq!(fp.render_path(&name), Err(Error::NoData("none".into())));
+}
+
+#[test]
+fn render_bytes_case_insensitive() {
+    let opts = CompileOptions::new().case_insensitive(true);
+    let data = vec![0xff];
+
+    let (_, bmap) = lookup();
+    let fp = bmap.to_format_pieces_with("{RAW}", &opts).unwrap();
+    assert_eq!(fp.render_bytes(&data), Ok(vec![0xff]));
+
+    let fp = lookup()
+        .to_format_pieces_with("{Len} {RAW} {Empty?{EMPTY}}", &opts)
+        .unwrap();
+    assert_eq!(fp.render_bytes(&data), Ok(b"1 \xff \xff".to_vec()));
+
+    let lookup = namespace("b", bmap, |data: &Vec<u8>| data);
+    let fp = lookup.to_format_pieces_with("{b.Raw}", &opts).unwrap();
+    assert_eq!(fp.render_bytes(&data), Ok(vec![0xff]));
+}
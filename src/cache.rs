@@ -0,0 +1,166 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt;
+use std::sync::{Mutex, PoisonError};
+
+use crate::{Error, FnvHashMap, FormatMap, FormatPieces, KeyLookup, KeyString, ToFormatPieces};
+
+struct Entry<T: ?Sized> {
+    pieces: Arc<FormatPieces<T>>,
+    /// The value of `Lru::tick` when this entry was last used, which is its key in `Lru::order`.
+    last_used: u64,
+}
+
+struct Lru<T: ?Sized> {
+    entries: FnvHashMap<KeyString, Entry<T>>,
+    /// The key of every entry by when it was last used, so the oldest is found without a scan.
+    order: BTreeMap<u64, KeyString>,
+    tick: u64,
+}
+
+impl<T: ?Sized> Lru<T> {
+    /// Advance the clock, returning a tick which no entry has used yet.
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Mark the entry for `tmpl` as the most recently used, if there is one.
+    fn touch(&mut self, tmpl: &str) -> Option<&Entry<T>> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(tmpl)?;
+        if let Some(key) = self.order.remove(&entry.last_used) {
+            self.order.insert(tick, key);
+        }
+        entry.last_used = tick;
+        Some(entry)
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+/// Memoizes processed templates by their text, keeping up to a fixed number of the most recently
+/// used ones.
+///
+/// This is useful when the same few templates, such as ones supplied by users, are rendered over
+/// and over, and processing them every time is wasteful. The cache can be shared between threads.
+/// Templates which fail to process are not cached.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, FormatMap, Render, TemplateCache};
+/// use std::sync::Arc;
+///
+/// let fmap: FormatMap<String> = fm!{"name" => |data: &String| Some(data.clone())};
+/// let cache = TemplateCache::new(fmap, 64);
+///
+/// let fp = cache.get("Hello, {name}!").unwrap();
+/// assert_eq!(fp.render(&String::from("world")), Ok("Hello, world!".to_string()));
+///
+/// // The second lookup reuses the processed template
+/// assert!(Arc::ptr_eq(&fp, &cache.get("Hello, {name}!").unwrap()));
+/// ```
+pub struct TemplateCache<T: ?Sized, L = FormatMap<T>> {
+    map: L,
+    capacity: usize,
+    lru: Mutex<Lru<T>>,
+}
+
+impl<T: ?Sized, L: KeyLookup<T>> TemplateCache<T, L> {
+    /// Create a cache processing templates against `map`, which keeps up to `capacity` templates.
+    /// With a capacity of zero, nothing is cached.
+    pub fn new(map: L, capacity: usize) -> Self {
+        Self {
+            map,
+            capacity,
+            lru: Mutex::new(Lru {
+                entries: FnvHashMap::default(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// The processed form of `tmpl`, from the cache if possible. Otherwise, it is processed and
+    /// added to the cache, evicting the least recently used template if the cache is full.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `ToFormatPieces::to_format_pieces`.
+    pub fn get(&self, tmpl: &str) -> Result<Arc<FormatPieces<T>>, Error> {
+        if let Some(entry) = self.lock().touch(tmpl) {
+            return Ok(Arc::clone(&entry.pieces));
+        }
+
+        // Process without holding the lock, so that other templates can be served meanwhile. If
+        // another thread processes the same template at once, the last one to finish wins.
+        let pieces = Arc::new(self.map.to_format_pieces(tmpl)?);
+        if self.capacity == 0 {
+            return Ok(pieces);
+        }
+
+        let mut lru = self.lock();
+        let lru = &mut *lru;
+        if lru.entries.len() >= self.capacity && !lru.entries.contains_key(tmpl) {
+            let oldest = lru.order.keys().next().copied();
+            if let Some(oldest) = oldest.and_then(|tick| lru.order.remove(&tick)) {
+                lru.entries.remove(&oldest);
+            }
+        }
+        let last_used = lru.next_tick();
+        let entry = Entry {
+            pieces: Arc::clone(&pieces),
+            last_used,
+        };
+        if let Some(old) = lru.entries.insert(tmpl.into(), entry) {
+            lru.order.remove(&old.last_used);
+        }
+        lru.order.insert(last_used, tmpl.into());
+        Ok(pieces)
+    }
+}
+
+impl<T: ?Sized, L> TemplateCache<T, L> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru<T>> {
+        // The cache is never left inconsistent by a panic, so poisoning can be ignored
+        self.lru.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The map templates are processed against.
+    pub fn map(&self) -> &L {
+        &self.map
+    }
+
+    /// The maximum number of templates kept.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of templates currently cached.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Whether no templates are currently cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove every cached template, for example after the map has changed.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+impl<T: ?Sized, L> fmt::Debug for TemplateCache<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TemplateCache")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
@@ -0,0 +1,62 @@
+use super::*;
+
+fn cache(capacity: usize) -> TemplateCache<String> {
+    let fmap: FormatMap<String> = fm! {"foo" => |data: &String| Some(data.clone())};
+    TemplateCache::new(fmap, capacity)
+}
+
+#[test]
+fn evicts_least_recently_used() {
+    let cache = cache(2);
+    let a = cache.get("a{foo}").unwrap();
+    let b = cache.get("b{foo}").unwrap();
+    // Touch a, so that b is the oldest
+    assert!(Arc::ptr_eq(&a, &cache.get("a{foo}").unwrap()));
+    cache.get("c{foo}").unwrap();
+    assert_eq!(cache.len(), 2);
+
+    assert!(Arc::ptr_eq(&a, &cache.get("a{foo}").unwrap()));
+    assert!(!Arc::ptr_eq(&b, &cache.get("b{foo}").unwrap()));
+    assert_eq!(
+        cache.get("b{foo}").unwrap().render(&"x".into()),
+        Ok("bx".to_string())
+    );
+
+    cache.clear();
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn errors_and_zero_capacity_are_not_cached() {
+    let small = cache(2);
+    assert_eq!(small.get("{nope}"), Err(Error::UnknownKey("nope".into())));
+    assert!(small.is_empty());
+
+    let none = cache(0);
+    let a = none.get("{foo}").unwrap();
+    assert!(!Arc::ptr_eq(&a, &none.get("{foo}").unwrap()));
+    assert!(none.is_empty());
+}
+
+#[test]
+fn eviction_follows_use_order() {
+    let cache = cache(3);
+    let tmpls = ["a{foo}", "b{foo}", "c{foo}"];
+    let kept: Vec<_> = tmpls.iter().map(|t| cache.get(t).unwrap()).collect();
+    // Use them in reverse, so that c is now the oldest, then b, then a
+    for tmpl in tmpls.iter().rev() {
+        cache.get(tmpl).unwrap();
+    }
+    cache.get("d{foo}").unwrap();
+    cache.get("e{foo}").unwrap();
+
+    assert_eq!(cache.len(), 3);
+    assert!(Arc::ptr_eq(&kept[0], &cache.get("a{foo}").unwrap()));
+    assert!(!Arc::ptr_eq(&kept[1], &cache.get("b{foo}").unwrap()));
+    // Adding b back evicted d, and touching e leaves a as the oldest
+    let e = cache.get("e{foo}").unwrap();
+    cache.get("f{foo}").unwrap();
+    assert!(Arc::ptr_eq(&e, &cache.get("e{foo}").unwrap()));
+    assert!(!Arc::ptr_eq(&kept[0], &cache.get("a{foo}").unwrap()));
+    assert_eq!(cache.len(), 3);
+}
@@ -0,0 +1,253 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{Error, FormatPiece, FormatPieces, Formatter, KeyString};
+
+/// Decides whether a stable key's cached output can be reused for the next data item.
+trait Fingerprint<T: ?Sized>: Send {
+    /// Whether `data` has the same fingerprint as the data seen on the previous call, remembering
+    /// the new fingerprint either way.
+    fn unchanged(&mut self, data: &T) -> bool;
+}
+
+struct ByFingerprint<F, K> {
+    f: F,
+    last: Option<K>,
+}
+
+impl<T: ?Sized, F, K> Fingerprint<T> for ByFingerprint<F, K>
+where
+    F: Fn(&T) -> K + Send,
+    K: PartialEq + Send,
+{
+    fn unchanged(&mut self, data: &T) -> bool {
+        let fingerprint = (self.f)(data);
+        let unchanged = self.last.as_ref() == Some(&fingerprint);
+        self.last = Some(fingerprint);
+        unchanged
+    }
+}
+
+struct StableKey<T: ?Sized> {
+    key: KeyString,
+    fingerprint: Box<dyn Fingerprint<T>>,
+    /// The output of the callback for the last fingerprint, if it has been called since.
+    cached: Option<Option<String>>,
+}
+
+/// Rolling estimates of the output size of each piece, used to size the output buffer.
+pub(crate) struct SizeHints {
+    /// The estimate for each piece, or `None` for callbacks which haven't produced output yet.
+    hints: Vec<Option<usize>>,
+    /// The sum of all estimates.
+    pub(crate) total: usize,
+}
+
+impl SizeHints {
+    /// Ballpark guess for a callback's output, large enough to usually avoid extra allocations.
+    const GUESS: usize = 16;
+
+    /// Start with the exact size of each piece of verbatim text, and a guess for everything else.
+    fn new<T: ?Sized>(pieces: &[FormatPiece<T>]) -> Self {
+        let hints: Vec<_> = pieces
+            .iter()
+            .map(|piece| match piece {
+                FormatPiece::Verbatim(s) => Some(s.len()),
+                _ => None,
+            })
+            .collect();
+        let total = hints.iter().fold(0usize, |acc, hint| {
+            acc.saturating_add(hint.unwrap_or(Self::GUESS))
+        });
+        Self { hints, total }
+    }
+
+    /// Fold the output size of the piece at `idx` into its estimate.
+    fn record(&mut self, idx: usize, len: usize) {
+        let hint = &mut self.hints[idx];
+        let old = hint.unwrap_or(Self::GUESS);
+        // Exponential moving average with a weight of 1/8 for the new value. The step is rounded
+        // away from zero so that a constant output size converges on exactly that size.
+        let step = |diff: usize| diff / 8 + usize::from(diff % 8 != 0);
+        let new = match *hint {
+            None => len,
+            Some(old) if len > old => old.saturating_add(step(len - old)),
+            Some(old) => old - step(old - len),
+        };
+        *hint = Some(new);
+        self.total = self.total.saturating_sub(old).saturating_add(new);
+    }
+}
+
+/// Renders a stream of data items, reusing the output of "stable" keys from the previous item
+/// instead of calling their callbacks again.
+///
+/// Each stable key has a fingerprint function, which should be much cheaper than the callback
+/// itself. While consecutive items have the same fingerprint, the key's output from the first of
+/// them is reused. For batches sorted by a field like a date or a camera model, this avoids most
+/// calls to the callbacks for keys derived from that field.
+///
+/// Stable keys are matched by name, including inside the branches of conditionals. The condition
+/// of a conditional itself is always evaluated.
+///
+/// The output buffer is sized from the exact length of the template's verbatim text, plus a
+/// rolling average of each other piece's output size over previous renders.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, CachedRenderer, FormatMap, ToFormatPieces};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// struct Photo { day: u32, name: &'static str }
+///
+/// let calls = Arc::new(AtomicUsize::new(0));
+/// let calls_ = Arc::clone(&calls);
+/// let fmap: FormatMap<Photo> = fm!{
+///     "date" => move |p: &Photo| {
+///         calls_.fetch_add(1, Ordering::Relaxed);
+///         Some(format!("2024-01-{:02}", p.day))
+///     },
+///     "name" => |p: &Photo| Some(p.name.to_string()),
+/// };
+///
+/// let fp = fmap.to_format_pieces("{date}/{name}").unwrap();
+/// let mut renderer = CachedRenderer::new(fp).stable("date", |p: &Photo| p.day);
+///
+/// let photos = [
+///     Photo { day: 1, name: "a" },
+///     Photo { day: 1, name: "b" },
+///     Photo { day: 2, name: "c" },
+/// ];
+/// let out: Vec<_> = photos.iter().map(|p| renderer.render(p).unwrap()).collect();
+/// assert_eq!(out, ["2024-01-01/a", "2024-01-01/b", "2024-01-02/c"]);
+/// assert_eq!(calls.load(Ordering::Relaxed), 2);
+/// ```
+pub struct CachedRenderer<T: ?Sized> {
+    pieces: FormatPieces<T>,
+    stable: Vec<StableKey<T>>,
+    pub(crate) sizes: SizeHints,
+}
+
+impl<T: ?Sized> CachedRenderer<T> {
+    /// Create a renderer for `pieces`, with no stable keys.
+    pub fn new(pieces: FormatPieces<T>) -> Self {
+        Self {
+            sizes: SizeHints::new(&pieces),
+            pieces,
+            stable: Vec::new(),
+        }
+    }
+
+    /// Mark `key` as stable: its output is reused for as long as `fingerprint` returns an equal
+    /// value for consecutive data items. Marking a key again replaces its fingerprint.
+    pub fn stable<K, F>(mut self, key: &str, fingerprint: F) -> Self
+    where
+        F: Fn(&T) -> K + Send + 'static,
+        K: PartialEq + Send + 'static,
+    {
+        self.stable.retain(|s| s.key != key);
+        self.stable.push(StableKey {
+            key: key.into(),
+            fingerprint: Box::new(ByFingerprint {
+                f: fingerprint,
+                last: None,
+            }),
+            cached: None,
+        });
+        self
+    }
+
+    /// Forget all cached output, so that the next render calls every callback.
+    pub fn reset(&mut self) {
+        for stable in &mut self.stable {
+            stable.cached = None;
+        }
+    }
+
+    /// Render the pieces with `data`, reusing cached output for stable keys whose fingerprint is
+    /// unchanged since the previous call.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `Render::render`. Failing renders still update the cache.
+    pub fn render(&mut self, data: &T) -> Result<String, Error> {
+        // Fingerprints are checked once per render, so that a key used several times is
+        // consistent within it
+        for stable in &mut self.stable {
+            if !stable.fingerprint.unchanged(data) {
+                stable.cached = None;
+            }
+        }
+        let mut out = String::with_capacity(self.sizes.total);
+        for (idx, piece) in self.pieces.iter().enumerate() {
+            let start = out.len();
+            render_cached_piece(piece, data, &mut self.stable, &mut out)?;
+            if !matches!(piece, FormatPiece::Verbatim(_)) {
+                self.sizes.record(idx, out.len() - start);
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// The output of `f`, taken from the cache if it is a stable key.
+fn call_cached<T: ?Sized>(
+    f: &Formatter<T>,
+    data: &T,
+    stable: &mut [StableKey<T>],
+) -> Option<String> {
+    match stable.iter_mut().find(|s| s.key == f.key) {
+        Some(s) => s.cached.get_or_insert_with(|| (f.cb)(data)).clone(),
+        None => (f.cb)(data),
+    }
+}
+
+fn render_cached<T: ?Sized>(
+    pieces: &[FormatPiece<T>],
+    data: &T,
+    stable: &mut [StableKey<T>],
+    out: &mut String,
+) -> Result<(), Error> {
+    pieces
+        .iter()
+        .try_for_each(|piece| render_cached_piece(piece, data, stable, out))
+}
+
+fn render_cached_piece<T: ?Sized>(
+    piece: &FormatPiece<T>,
+    data: &T,
+    stable: &mut [StableKey<T>],
+    out: &mut String,
+) -> Result<(), Error> {
+    let f = match piece {
+        FormatPiece::Verbatim(s) => {
+            out.push_str(s);
+            return Ok(());
+        }
+        FormatPiece::Conditional(c) => {
+            let taken = (c.condition().cb)(data).is_some();
+            return render_cached(c.branch(taken), data, stable, out);
+        }
+        FormatPiece::Formatter(f) => f,
+        FormatPiece::Bytes(b) => b.formatter(),
+    };
+    let val = call_cached(f, data, stable).ok_or_else(|| Error::NoData(f.key.clone()))?;
+    out.push_str(&val);
+    Ok(())
+}
+
+impl<T: ?Sized> fmt::Debug for CachedRenderer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedRenderer")
+            .field("pieces", &self.pieces.len())
+            .field(
+                "stable",
+                &self.stable.iter().map(|s| &s.key).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
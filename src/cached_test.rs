@@ -0,0 +1,92 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Item {
+    group: u32,
+    name: &'static str,
+}
+
+fn counting_map(calls: &Arc<AtomicUsize>) -> FormatMap<Item> {
+    let calls = Arc::clone(calls);
+    fm! {
+        "group" => move |i: &Item| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            if i.group == 0 { None } else { Some(format!("g{}", i.group)) }
+        },
+        "name" => |i: &Item| Some(i.name.to_string()),
+    }
+}
+
+#[test]
+fn stable_keys_reuse_output() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let fp = counting_map(&calls)
+        .to_format_pieces("{group}/{name} {group?({group}):-}")
+        .unwrap();
+    let mut renderer = CachedRenderer::new(fp).stable("group", |i: &Item| i.group);
+
+    let items = [
+        Item {
+            group: 1,
+            name: "a",
+        },
+        Item {
+            group: 1,
+            name: "b",
+        },
+        Item {
+            group: 2,
+            name: "c",
+        },
+        Item {
+            group: 1,
+            name: "d",
+        },
+    ];
+    let out: Vec<_> = items.iter().map(|i| renderer.render(i).unwrap()).collect();
+    assert_eq!(out, ["g1/a (g1)", "g1/b (g1)", "g2/c (g2)", "g1/d (g1)"]);
+    // One call per group change for the stable key, plus the condition on every render
+    assert_eq!(calls.load(Ordering::Relaxed), 3 + 4);
+
+    renderer.reset();
+    renderer.render(&items[3]).unwrap();
+    assert_eq!(calls.load(Ordering::Relaxed), 3 + 4 + 2);
+}
+
+#[test]
+fn stable_no_data_is_cached() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let fp = counting_map(&calls).to_format_pieces("{group}").unwrap();
+    let mut renderer = CachedRenderer::new(fp).stable("group", |i: &Item| i.group);
+    for _ in 0..3 {
+        assert_eq!(
+            renderer.render(&Item { group: 0, name: "" }),
+            Err(Error::NoData("group".into()))
+        );
+    }
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn capacity_converges_on_output_size() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let fp = counting_map(&calls)
+        .to_format_pieces("[{group}] {name}")
+        .unwrap();
+    let mut renderer = CachedRenderer::new(fp);
+    assert_eq!(renderer.sizes.total, "[] ".len() + 2 * 16);
+
+    let name = "a name long enough to shrink from";
+    let out = renderer.render(&Item { group: 1, name }).unwrap();
+    assert_eq!(renderer.sizes.total, out.len());
+
+    for _ in 0..64 {
+        renderer
+            .render(&Item {
+                group: 2,
+                name: "abc",
+            })
+            .unwrap();
+    }
+    assert_eq!(renderer.sizes.total, "[g2] abc".len());
+}
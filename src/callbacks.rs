@@ -0,0 +1,178 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+
+use crate::context::Memo;
+use crate::{keys_eq_ignore_case, FnvHashMap, FormatterCallback, KeyLookup, KeyString};
+
+/// A callback which is also given the argument after the `:` in its key, like `5` in `{pad:5}`.
+pub type ArgCallback<T> = Arc<dyn Fn(&T, &str) -> Option<String> + Send + Sync>;
+
+/// A callback of any flavor, as stored in a `CallbackMap<T>`. `fm!` wraps each of its entries in
+/// one of these.
+pub enum Callback<T: ?Sized> {
+    /// A callback given only the data, like those in a `FormatMap<T>`. Fallible callbacks are
+    /// stored like this once adapted with `fallible`.
+    Plain(FormatterCallback<T>),
+    /// A callback given the data and the argument from the key.
+    WithArg(ArgCallback<T>),
+}
+
+// Derived Clone would needlessly require `T: Clone`
+impl<T: ?Sized> Clone for Callback<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Plain(cb) => Self::Plain(Arc::clone(cb)),
+            Self::WithArg(cb) => Self::WithArg(Arc::clone(cb)),
+        }
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Callback<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain(_) => f.write_str("Plain"),
+            Self::WithArg(_) => f.write_str("WithArg"),
+        }
+    }
+}
+
+/// A mapping of keys to callbacks of any flavor, as constructed by `fm!` when some of its
+/// callbacks take an argument.
+///
+/// A key registered with a `Callback::WithArg` is used in templates as `{key:arg}`, and the
+/// callback is given `arg`. Keys containing `:` which are registered with a `Callback::Plain` are
+/// matched as a whole first.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, CallbackMap, Render, ToFormatPieces};
+///
+/// let fmap: CallbackMap<String> = fm!{
+///     "raw" => |data: &String| Some(data.clone()),
+///     "pad" => with_arg |data: &String, width: &str| {
+///         let width = width.parse().ok()?;
+///         Some(format!("{data:>width$}"))
+///     },
+/// };
+///
+/// let fp = fmap.to_format_pieces("{raw} [{pad:4}]").unwrap();
+/// assert_eq!(fp.render(&String::from("ab")), Ok("ab [  ab]".to_string()));
+/// ```
+pub struct CallbackMap<T: ?Sized> {
+    map: FnvHashMap<KeyString, Callback<T>>,
+    /// Argument callbacks bound to the argument in each key, along with the callback each calls.
+    bound: Memo<(ArgCallback<T>, FormatterCallback<T>)>,
+}
+
+impl<T: ?Sized> CallbackMap<T> {
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Self {
+            map: FnvHashMap::default(),
+            bound: Memo::new(),
+        }
+    }
+
+    /// Register `cb` for `key`, returning the callback previously registered for it, if any.
+    pub fn insert<K: Into<KeyString>>(&mut self, key: K, cb: Callback<T>) -> Option<Callback<T>> {
+        self.map.insert(key.into(), cb)
+    }
+
+    /// The callback registered for `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&Callback<T>> {
+        self.map.get(key)
+    }
+
+    /// Unregister `key`, returning its callback, if any.
+    pub fn remove(&mut self, key: &str) -> Option<Callback<T>> {
+        self.map.remove(key)
+    }
+
+    /// The number of keys registered.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether no keys are registered.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl<T: ?Sized + 'static> CallbackMap<T> {
+    /// Bind `cb` to `arg`, reusing the last binding for `key` if it calls the same callback.
+    fn bind(&self, key: &str, cb: &ArgCallback<T>, arg: &str) -> FormatterCallback<T> {
+        let (_, bound) = self.bound.get_or_insert(
+            key,
+            |(inner, _)| Arc::ptr_eq(inner, cb),
+            || {
+                let inner = Arc::clone(cb);
+                let arg = String::from(arg);
+                (Arc::clone(cb), Arc::new(move |data| inner(data, &arg)))
+            },
+        );
+        bound
+    }
+
+    /// The callback for `key`, given the registered callback for its name, if it is of the right
+    /// flavor.
+    fn resolve<'a, F>(&'a self, key: &str, found: F) -> Option<FormatterCallback<T>>
+    where
+        F: Fn(&str, bool) -> Option<&'a Callback<T>>,
+    {
+        if let Some(Callback::Plain(cb)) = found(key, false) {
+            return Some(Arc::clone(cb));
+        }
+        let (name, arg) = key.split_once(':')?;
+        match found(name, true)? {
+            Callback::WithArg(cb) => Some(self.bind(key, cb, arg)),
+            Callback::Plain(_) => None,
+        }
+    }
+}
+
+impl<T: ?Sized + 'static> KeyLookup<T> for CallbackMap<T> {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.resolve(key, |name, _| self.map.get(name))
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        // Prefer an exact match, and otherwise take the matching key which sorts first, so that
+        // the result doesn't depend on iteration order
+        self.lookup(key).or_else(|| {
+            self.resolve(key, |name, with_arg| {
+                self.map
+                    .iter()
+                    .filter(|(k, cb)| {
+                        matches!(cb, Callback::WithArg(_)) == with_arg
+                            && keys_eq_ignore_case(k, name)
+                    })
+                    .min_by(|(a, _), (b, _)| a.cmp(b))
+                    .map(|(_, cb)| cb)
+            })
+        })
+    }
+}
+
+impl<T: ?Sized> Default for CallbackMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> Clone for CallbackMap<T> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            bound: self.bound.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for CallbackMap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.map.iter()).finish()
+    }
+}
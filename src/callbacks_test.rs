@@ -0,0 +1,61 @@
+use super::*;
+
+fn cmap() -> CallbackMap<String> {
+    fm! {
+        "Name" => |data: &String| Some(data.clone()),
+        "pad:x" => |_: &String| Some("literal".to_string()),
+        "Pad" => with_arg |data: &String, width: &str| {
+            let width = width.parse().ok()?;
+            Some(format!("{data:>width$}"))
+        },
+    }
+}
+
+#[test]
+fn plain_keys_containing_colon_take_precedence() {
+    let fp = cmap().to_format_pieces("{pad:x}|{Pad:3}").unwrap();
+    assert_eq!(fp.render(&"a".to_string()), Ok("literal|  a".to_string()));
+}
+
+#[test]
+fn case_insensitive_with_arg() {
+    let opts = CompileOptions::new().case_insensitive(true);
+    let fp = cmap()
+        .to_format_pieces_with("{name} {PAD:3} {pad:x}", &opts)
+        .unwrap();
+    assert_eq!(fp.render(&"a".to_string()), Ok("a   a literal".to_string()));
+    assert_eq!(
+        cmap().to_format_pieces_with("{name:3}", &opts),
+        Err(Error::UnknownKey("name:3".into()))
+    );
+}
+
+#[test]
+fn bound_callbacks_are_reused() {
+    let fmap = cmap();
+    let tmpl = "{Pad:3} {Pad:4}";
+    let mut fp = fmap.to_format_pieces(tmpl).unwrap();
+    assert!(fp.same_bindings(&fmap.to_format_pieces(tmpl).unwrap()));
+    assert!(fp.refresh(&fmap).unwrap().is_empty());
+    assert_eq!(fp.render(&"a".to_string()), Ok("  a    a".to_string()));
+
+    let mut replaced = fmap.clone();
+    replaced.insert(
+        "Pad",
+        Callback::WithArg(Arc::new(|_: &String, width: &str| Some(width.to_string()))),
+    );
+    assert!(!fp.same_bindings(&replaced.to_format_pieces(tmpl).unwrap()));
+    fp.refresh(&replaced).unwrap();
+    assert_eq!(fp.render(&"a".to_string()), Ok("3 4".to_string()));
+}
+
+#[test]
+fn insert_and_remove() {
+    let mut fmap = cmap();
+    assert_eq!(fmap.len(), 3);
+    assert!(matches!(fmap.get("Pad"), Some(Callback::WithArg(_))));
+    assert!(matches!(fmap.remove("Name"), Some(Callback::Plain(_))));
+    assert!(fmap.get("Name").is_none());
+    assert_eq!(fmap.len(), 2);
+    assert!(CallbackMap::<String>::default().is_empty());
+}
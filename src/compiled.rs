@@ -0,0 +1,270 @@
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::ControlFlow;
+
+use crate::parse::tokenize;
+use crate::render::{impl_render, walk, PieceRef, PieceSource};
+use crate::{
+    resolve, CompileOptions, Error, FnvHashMap, FormatPiece, FormatPieces, KeyLookup, PieceOutput,
+    SameBindings, TemplateToken, Walk,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum CompiledPiece {
+    /// A range of bytes in `CompiledTemplate::text`.
+    Verbatim { start: u32, end: u32 },
+    /// An index into `CompiledTemplate::formatters`, which is never verbatim text.
+    Formatter(u32),
+}
+
+/// A compact alternative to `FormatPieces<T>`, which stores each distinct callback once in a
+/// frozen table and refers to it by index.
+///
+/// Compared to `FormatPieces<T>`, this clones one `Arc` per distinct key rather than one per
+/// occurrence, stores all verbatim text in a single contiguous buffer, and uses small fixed-size
+/// pieces. This improves cache locality for templates with many placeholders. It implements
+/// `Render<T>` in exactly the same way.
+///
+/// Like `FormatPieces<T>`, it is always `Send`, `Sync`, and `Clone`, and cloning shares the
+/// callbacks.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, CompiledTemplate, Render};
+///
+/// let fmap = fm!{"foo" => |data: &String| Some(data.clone())};
+/// let ct = CompiledTemplate::compile(&fmap, "{foo}, {foo}, and {foo}").unwrap();
+/// assert_eq!(ct.keys().collect::<Vec<_>>(), vec!["foo"]);
+/// assert_eq!(ct.render(&String::from("x")), Ok("x, x, and x".to_string()));
+/// ```
+pub struct CompiledTemplate<T: ?Sized> {
+    text: String,
+    formatters: Vec<FormatPiece<T>>,
+    pieces: Vec<CompiledPiece>,
+    /// The number of pieces which aren't verbatim text, for sizing the rendered output.
+    keyed: usize,
+}
+
+fn to_index(idx: usize) -> Result<u32, Error> {
+    u32::try_from(idx).map_err(|_| Error::Overflow)
+}
+
+impl<T: ?Sized> CompiledTemplate<T> {
+    fn with_capacity(len: usize) -> Self {
+        Self {
+            text: String::with_capacity(len),
+            formatters: Vec::new(),
+            pieces: Vec::new(),
+            keyed: 0,
+        }
+    }
+
+    fn push_verbatim(&mut self, text: &str) -> Result<(), Error> {
+        let start = to_index(self.text.len())?;
+        self.text.push_str(text);
+        let end = to_index(self.text.len())?;
+        self.pieces.push(CompiledPiece::Verbatim { start, end });
+        Ok(())
+    }
+
+    /// Push a formatter piece for `key`, adding the piece returned by `make` for the key to the
+    /// table if it isn't there already. `index` maps keys to their position in the table.
+    fn push_formatter<'a, F>(
+        &mut self,
+        index: &mut FnvHashMap<Cow<'a, str>, u32>,
+        key: Cow<'a, str>,
+        make: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(&str) -> Result<FormatPiece<T>, Error>,
+    {
+        let idx = match index.get(&*key) {
+            Some(&idx) => idx,
+            None => {
+                let idx = to_index(self.formatters.len())?;
+                self.formatters.push(make(&key)?);
+                index.insert(key, idx);
+                idx
+            }
+        };
+        self.pieces.push(CompiledPiece::Formatter(idx));
+        self.keyed += 1;
+        Ok(())
+    }
+
+    /// Process the template `tmpl` using `map`, with the same syntax and semantics as
+    /// `ToFormatPieces::to_format_pieces`.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::ImbalancedBrackets` if `tmpl` contains imbalanced brackets (use `{{` and `}}` to
+    ///   escape)
+    /// - `Error::Overflow` if the template is too large to index
+    /// - `Error::UnknownKey` if a requested key has no associated callback
+    pub fn compile<L, S>(map: &L, tmpl: S) -> Result<Self, Error>
+    where
+        L: KeyLookup<T> + ?Sized,
+        S: AsRef<str>,
+    {
+        Self::compile_with(map, tmpl, &CompileOptions::default())
+    }
+
+    /// Like `compile`, but processing the template according to `opts`.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `compile`.
+    pub fn compile_with<L, S>(map: &L, tmpl: S, opts: &CompileOptions) -> Result<Self, Error>
+    where
+        L: KeyLookup<T> + ?Sized,
+        S: AsRef<str>,
+    {
+        let tmpl = tmpl.as_ref();
+        let mut out = Self::with_capacity(tmpl.len());
+        let mut index = FnvHashMap::default();
+        tokenize(tmpl, opts, |token| match token {
+            TemplateToken::Verbatim { text, .. } => out.push_verbatim(text),
+            TemplateToken::Key { name, .. } => {
+                let res =
+                    out.push_formatter(&mut index, name.clone(), |name| resolve(map, name, opts));
+                match res {
+                    Err(Error::UnknownKey(key)) => match opts.unknown_key_text(&name) {
+                        Some(text) => out.push_verbatim(&text),
+                        None => Err(Error::UnknownKey(key)),
+                    },
+                    res => res,
+                }
+            }
+        })?;
+        Ok(out)
+    }
+
+    /// Convert already processed `FormatPieces<T>` into the compact representation. Formatters
+    /// with the same key share the callbacks of the first occurrence.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::Overflow` if the pieces are too large to index
+    pub fn from_pieces(pieces: &FormatPieces<T>) -> Result<Self, Error> {
+        let mut out = Self::with_capacity(0);
+        let mut index = FnvHashMap::default();
+        for piece in pieces {
+            match (piece, piece.key()) {
+                (FormatPiece::Verbatim(s), _) => out.push_verbatim(s)?,
+                (_, key) => {
+                    let key = Cow::Borrowed(key.map_or("", |key| key.as_str()));
+                    out.push_formatter(&mut index, key, |_| Ok(piece.clone()))?;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// The distinct keys used by this template, in order of first use.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.formatters
+            .iter()
+            .filter_map(|f| f.key().map(|key| key.as_str()))
+    }
+
+    /// The number of pieces in this template.
+    pub fn len(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Whether this template has no pieces at all, and so always renders to an empty string.
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+}
+
+impl<T: ?Sized> PieceSource<T> for CompiledTemplate<T> {
+    fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    fn piece(&self, idx: usize) -> PieceRef<'_, T> {
+        match self.pieces[idx] {
+            CompiledPiece::Verbatim { start, end } => {
+                PieceRef::Verbatim(&self.text[start as usize..end as usize])
+            }
+            CompiledPiece::Formatter(idx) => (&self.formatters[idx as usize]).into(),
+        }
+    }
+
+    fn capacity_hint(&self) -> Result<usize, Error> {
+        // All verbatim text is already in one buffer, so only the callbacks' output is a guess
+        self.keyed
+            .checked_mul(16)
+            .and_then(|len| len.checked_add(self.text.len()))
+            .ok_or(Error::Overflow)
+    }
+}
+
+impl_render!(CompiledTemplate<T>);
+
+impl<T: ?Sized> Walk<T> for CompiledTemplate<T> {
+    fn walk<B, F>(&self, data: &T, visitor: F) -> Result<ControlFlow<B>, Error>
+    where
+        F: FnMut(PieceOutput<'_>) -> ControlFlow<B>,
+    {
+        walk(self, data, visitor)
+    }
+}
+
+// Derived Clone would needlessly require `T: Clone`
+impl<T: ?Sized> Clone for CompiledTemplate<T> {
+    fn clone(&self) -> Self {
+        Self {
+            text: self.text.clone(),
+            formatters: self.formatters.clone(),
+            pieces: self.pieces.clone(),
+            keyed: self.keyed,
+        }
+    }
+}
+
+/// Templates are compared by content only. Use `SameBindings` to also compare their callbacks.
+impl<T: ?Sized> PartialEq for CompiledTemplate<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+            && self.pieces == other.pieces
+            && self.formatters == other.formatters
+    }
+}
+impl<T: ?Sized> Eq for CompiledTemplate<T> {}
+
+impl<T: ?Sized> Hash for CompiledTemplate<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.text.hash(state);
+        self.pieces.hash(state);
+        self.formatters.hash(state);
+    }
+}
+
+impl<T: ?Sized> SameBindings for CompiledTemplate<T> {
+    fn same_bindings(&self, other: &Self) -> bool {
+        self.text == other.text
+            && self.pieces == other.pieces
+            && self.formatters.len() == other.formatters.len()
+            && self
+                .formatters
+                .iter()
+                .zip(&other.formatters)
+                .all(|(a, b)| a.same_bindings(b))
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for CompiledTemplate<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompiledTemplate")
+            .field("text", &self.text)
+            .field("formatters", &self.formatters)
+            .field("pieces", &self.pieces)
+            .finish()
+    }
+}
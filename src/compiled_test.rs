@@ -0,0 +1,65 @@
+use super::*;
+use once_cell::sync::Lazy;
+
+static FORMATTERS: Lazy<FormatMap<String>> = Lazy::new(|| {
+    fm! {
+        "foo" => |e| Some(format!("{e} foo {e}")),
+        "bar" => |e| Some(format!("{e} bar {e}")),
+        "nodata" => |_| None,
+    }
+});
+
+#[test]
+fn matches_format_pieces() {
+    let inp = String::from("x");
+    for tmpl in ["", "一", "{foo}", "一{foo}二{{bar}}{bar}{foo}", "}}{{"] {
+        let fp = FORMATTERS.to_format_pieces(tmpl).unwrap();
+        let ct = CompiledTemplate::compile(&*FORMATTERS, tmpl).unwrap();
+        let from_fp = CompiledTemplate::from_pieces(&fp).unwrap();
+        assert_eq!(ct.len(), fp.len(), "{tmpl}");
+        assert_eq!(ct.render(&inp), fp.render(&inp), "{tmpl}");
+        assert_eq!(from_fp.render(&inp), fp.render(&inp), "{tmpl}");
+    }
+}
+
+#[test]
+fn callbacks_deduplicated() {
+    let ct = CompiledTemplate::compile(&*FORMATTERS, "{bar}{foo}{bar}{foo}").unwrap();
+    assert_eq!(ct.keys().collect::<Vec<_>>(), vec!["bar", "foo"]);
+    assert_eq!(ct.len(), 4);
+}
+
+#[test]
+fn errors_match_format_pieces() {
+    assert_eq!(
+        CompiledTemplate::compile(&*FORMATTERS, "{baz}").map(|_| ()),
+        Err(Error::UnknownKey("baz".into()))
+    );
+    assert_eq!(
+        CompiledTemplate::compile(&*FORMATTERS, "{foo").map(|_| ()),
+        Err(Error::ImbalancedBrackets)
+    );
+
+    let ct = CompiledTemplate::compile(&*FORMATTERS, "{foo}{nodata}").unwrap();
+    assert_eq!(
+        ct.render(&String::new()),
+        Err(Error::NoData("nodata".into()))
+    );
+}
+
+#[test]
+fn clone_renders_identically() {
+    let ct = CompiledTemplate::compile(&*FORMATTERS, "a{foo}b{bar}").unwrap();
+    let cloned = ct.clone();
+    let inp = String::from("x");
+    assert_eq!(cloned.render(&inp), ct.render(&inp));
+    assert_eq!(cloned.keys().collect::<Vec<_>>(), vec!["foo", "bar"]);
+}
+
+#[test]
+fn capacity_counts_verbatim_text_exactly() {
+    use crate::render::PieceSource;
+
+    let ct = CompiledTemplate::compile(&*FORMATTERS, "一{foo}二{bar}{foo}").unwrap();
+    assert_eq!(ct.capacity_hint(), Ok("一二".len() + 3 * 16));
+}
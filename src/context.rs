@@ -0,0 +1,427 @@
+#[cfg(feature = "std")]
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use core::marker::PhantomData;
+#[cfg(feature = "std")]
+use std::sync::{Mutex, PoisonError};
+
+use crate::{keys_eq_ignore_case, BytesCallback, FormatterCallback, KeyLookup};
+#[cfg(feature = "std")]
+use crate::{FnvHashMap, KeyString};
+
+/// Callbacks created on demand for each key, remembered so that looking the same key up again
+/// returns the same `Arc`. Without this, `Refresh` and `SameBindings` would see every such key as
+/// changed. Nothing is remembered without the `std` feature.
+///
+/// Keys may be open-ended, such as those from user-supplied templates, so only the most recently
+/// used `Memo::CAPACITY` are kept. A key which was evicted gets a new callback when looked up
+/// again, which `Refresh` then reports as changed.
+pub(crate) struct Memo<V> {
+    #[cfg(feature = "std")]
+    entries: Mutex<MemoEntries<V>>,
+    #[cfg(not(feature = "std"))]
+    entries: PhantomData<V>,
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone)]
+struct MemoEntries<V> {
+    /// Each value, along with the tick when it was last used, which is its key in `order`.
+    values: FnvHashMap<KeyString, (V, u64)>,
+    order: BTreeMap<u64, KeyString>,
+    tick: u64,
+}
+
+impl<V: Clone> Memo<V> {
+    #[cfg(feature = "std")]
+    pub(crate) const CAPACITY: usize = 1024;
+
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "std")]
+            entries: Mutex::new(MemoEntries {
+                values: FnvHashMap::default(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+            #[cfg(not(feature = "std"))]
+            entries: PhantomData,
+        }
+    }
+
+    /// The value remembered for `key` if `fresh` accepts it, and otherwise a new one from `make`.
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    pub(crate) fn get_or_insert<P, M>(&self, key: &str, fresh: P, make: M) -> V
+    where
+        P: FnOnce(&V) -> bool,
+        M: FnOnce() -> V,
+    {
+        #[cfg(feature = "std")]
+        {
+            let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+            let entries = &mut *entries;
+            entries.tick += 1;
+            let tick = entries.tick;
+            if let Some((val, used)) = entries.values.get_mut(key) {
+                if fresh(val) {
+                    if let Some(key) = entries.order.remove(used) {
+                        entries.order.insert(tick, key);
+                    }
+                    *used = tick;
+                    return val.clone();
+                }
+            }
+
+            let val = make();
+            if let Some((_, used)) = entries.values.insert(key.into(), (val.clone(), tick)) {
+                entries.order.remove(&used);
+            }
+            entries.order.insert(tick, key.into());
+            if entries.values.len() > Self::CAPACITY {
+                let oldest = entries.order.keys().next().copied();
+                if let Some(oldest) = oldest.and_then(|tick| entries.order.remove(&tick)) {
+                    entries.values.remove(&oldest);
+                }
+            }
+            val
+        }
+        #[cfg(not(feature = "std"))]
+        make()
+    }
+
+    /// Forget every remembered value.
+    pub(crate) fn clear(&self) {
+        #[cfg(feature = "std")]
+        {
+            let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+            entries.values.clear();
+            entries.order.clear();
+        }
+    }
+
+    /// The number of values remembered.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        #[cfg(feature = "std")]
+        return self
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values
+            .len();
+        #[cfg(not(feature = "std"))]
+        0
+    }
+}
+
+impl<V: Clone> Clone for Memo<V> {
+    fn clone(&self) -> Self {
+        Self {
+            #[cfg(feature = "std")]
+            entries: Mutex::new(
+                self.entries
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone(),
+            ),
+            #[cfg(not(feature = "std"))]
+            entries: PhantomData,
+        }
+    }
+}
+
+/// A `KeyLookup<T>` which exposes the callbacks of a `KeyLookup<U>` under a namespace, by
+/// projecting each `&T` to the `&U` they expect. See `namespace`.
+pub struct Namespace<T: ?Sized, U: ?Sized, L, F> {
+    prefix: String,
+    lookup: L,
+    project: Arc<F>,
+    /// Wrapped callbacks by key, along with the inner callback each one calls.
+    pub(crate) wrapped: Memo<(FormatterCallback<U>, FormatterCallback<T>)>,
+    pub(crate) wrapped_bytes: Memo<(BytesCallback<U>, BytesCallback<T>)>,
+}
+
+/// Expose the keys of `lookup` as `{prefix.key}`, with each callback called on the part of the
+/// data selected by `project`. With an empty prefix, keys are exposed as-is.
+///
+/// This allows a single template to pull from more than one data source, without having to write
+/// a callback for the combined type: pass the sources together as a tuple or struct, and combine
+/// one namespace per source into a tuple, which looks keys up in each element in turn. The sources
+/// can also be borrowed, as a tuple of references.
+///
+/// As for `prefix_handler`, the wrapped callback for each key is remembered so that `Refresh`
+/// doesn't see it as changed, but only for the most recently used keys, so `lookup` may have an
+/// unbounded number of them. `Namespace::clear` forgets them all.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, namespace, FormatMap, Render, ToFormatPieces};
+///
+/// struct Exif { iso: u32 }
+/// struct FileMeta { name: String }
+///
+/// let exif: FormatMap<Exif> = fm!{"iso" => |e: &Exif| Some(e.iso.to_string())};
+/// let fs: FormatMap<FileMeta> = fm!{"name" => |f: &FileMeta| Some(f.name.clone())};
+/// let lookup = (
+///     namespace("exif", exif, |d: &(Exif, FileMeta)| &d.0),
+///     namespace("fs", fs, |d: &(Exif, FileMeta)| &d.1),
+/// );
+///
+/// let fp = lookup.to_format_pieces("{fs.name}: ISO {exif.iso}").unwrap();
+/// let data = (Exif { iso: 400 }, FileMeta { name: "a.jpg".to_string() });
+/// assert_eq!(fp.render(&data), Ok("a.jpg: ISO 400".to_string()));
+/// ```
+///
+/// With borrowed sources, the template can be used for as long as the data is borrowed:
+///
+/// ```
+/// use funcfmt::{fm, namespace, FormatMap, Render, ToFormatPieces};
+///
+/// struct Exif { iso: u32 }
+/// struct FileMeta { name: String }
+///
+/// let exif_data = vec![Exif { iso: 100 }, Exif { iso: 400 }];
+/// let file_metadata = vec![FileMeta { name: "a.jpg".to_string() }, FileMeta { name: "b.jpg".to_string() }];
+///
+/// let exif: FormatMap<Exif> = fm!{"iso" => |e: &Exif| Some(e.iso.to_string())};
+/// let fs: FormatMap<FileMeta> = fm!{"name" => |f: &FileMeta| Some(f.name.clone())};
+/// let lookup = (
+///     namespace("exif", exif, |d: &(&Exif, &FileMeta)| d.0),
+///     namespace("fs", fs, |d: &(&Exif, &FileMeta)| d.1),
+/// );
+/// let fp = lookup.to_format_pieces("{fs.name}: ISO {exif.iso}").unwrap();
+///
+/// let out: Vec<_> = exif_data
+///     .iter()
+///     .zip(&file_metadata)
+///     .map(|data| fp.render(&data).unwrap())
+///     .collect();
+/// assert_eq!(out, ["a.jpg: ISO 100", "b.jpg: ISO 400"]);
+/// ```
+pub fn namespace<T: ?Sized, U: ?Sized, L, F>(
+    prefix: &str,
+    lookup: L,
+    project: F,
+) -> Namespace<T, U, L, F>
+where
+    L: KeyLookup<U>,
+    F: Fn(&T) -> &U + Send + Sync + 'static,
+{
+    Namespace {
+        prefix: prefix.into(),
+        lookup,
+        project: Arc::new(project),
+        wrapped: Memo::new(),
+        wrapped_bytes: Memo::new(),
+    }
+}
+
+impl<T, U, L, F> KeyLookup<T> for Namespace<T, U, L, F>
+where
+    T: ?Sized,
+    U: ?Sized + 'static,
+    L: KeyLookup<U>,
+    F: Fn(&T) -> &U + Send + Sync + 'static,
+{
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        let cb = self.lookup.lookup(self.strip_prefix(key)?)?;
+        Some(self.wrap(key, cb))
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        let cb = self.lookup.lookup_ignore_case(self.strip_prefix(key)?)?;
+        Some(self.wrap(key, cb))
+    }
+
+    fn lookup_bytes(&self, key: &str) -> Option<BytesCallback<T>> {
+        let cb = self.lookup.lookup_bytes(self.strip_prefix(key)?)?;
+        Some(self.wrap_bytes(key, cb))
+    }
+
+    fn lookup_bytes_ignore_case(&self, key: &str) -> Option<BytesCallback<T>> {
+        let cb = self
+            .lookup
+            .lookup_bytes_ignore_case(self.strip_prefix(key)?)?;
+        Some(self.wrap_bytes(key, cb))
+    }
+}
+
+impl<T: ?Sized, U: ?Sized, L, F> Namespace<T, U, L, F> {
+    /// Forget the wrapped callbacks remembered for each key looked up so far.
+    pub fn clear(&self) {
+        self.wrapped.clear();
+        self.wrapped_bytes.clear();
+    }
+
+    /// The key within this namespace, if `key` is in it.
+    fn strip_prefix<'a>(&self, key: &'a str) -> Option<&'a str> {
+        if self.prefix.is_empty() {
+            return Some(key);
+        }
+        key.strip_prefix(self.prefix.as_str())?.strip_prefix('.')
+    }
+
+    /// Wrap a callback for the inner data so that it can be called with the outer data, reusing
+    /// the last wrapper for `key` if it wraps the same callback.
+    fn wrap(&self, key: &str, cb: FormatterCallback<U>) -> FormatterCallback<T>
+    where
+        U: 'static,
+        F: Fn(&T) -> &U + Send + Sync + 'static,
+    {
+        let (_, wrapped) = self.wrapped.get_or_insert(
+            key,
+            |(inner, _)| Arc::ptr_eq(inner, &cb),
+            || {
+                let project = Arc::clone(&self.project);
+                let inner = Arc::clone(&cb);
+                (Arc::clone(&cb), Arc::new(move |data| inner(project(data))))
+            },
+        );
+        wrapped
+    }
+
+    /// Like `wrap`, but for a bytes callback.
+    fn wrap_bytes(&self, key: &str, cb: BytesCallback<U>) -> BytesCallback<T>
+    where
+        U: 'static,
+        F: Fn(&T) -> &U + Send + Sync + 'static,
+    {
+        let (_, wrapped) = self.wrapped_bytes.get_or_insert(
+            key,
+            |(inner, _)| Arc::ptr_eq(inner, &cb),
+            || {
+                let project = Arc::clone(&self.project);
+                let inner = Arc::clone(&cb);
+                (Arc::clone(&cb), Arc::new(move |data| inner(project(data))))
+            },
+        );
+        wrapped
+    }
+}
+
+impl<T: ?Sized, U: ?Sized, L, F> fmt::Debug for Namespace<T, U, L, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Namespace")
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+/// A `KeyLookup<T>` which routes every key starting with a prefix to a single callback. See
+/// `prefix_handler`.
+pub struct PrefixHandler<T: ?Sized, F> {
+    prefix: String,
+    handler: Arc<F>,
+    pub(crate) routes: Memo<FormatterCallback<T>>,
+}
+
+/// Route every key starting with `prefix`, like `{exif.Model}` for a prefix of `exif.`, to
+/// `handler`, which is called with the data and the full key as written in the template.
+///
+/// This is useful when the set of keys is large or open-ended, such as tags from a metadata
+/// library, so registering each one in a map isn't practical. Since any key with the prefix is
+/// accepted, unknown names can only be detected when rendering, by returning `None`. The prefix
+/// on its own is not a valid key. Combine this with other sources of callbacks using a tuple.
+///
+/// The callback for each key is remembered, so that looking it up again gives the same one, and
+/// `Refresh` doesn't see it as changed. Only the most recently used keys are remembered, so this
+/// is safe to use with an unbounded number of distinct keys, and `PrefixHandler::clear` forgets
+/// them all.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{prefix_handler, Render, ToFormatPieces};
+/// use std::collections::HashMap;
+///
+/// let lookup = prefix_handler("exif.", |tags: &HashMap<&str, &str>, key: &str| {
+///     tags.get(&key["exif.".len()..]).map(|v| v.to_string())
+/// });
+///
+/// let fp = lookup.to_format_pieces("{exif.Make} {exif.Model}").unwrap();
+/// let tags = HashMap::from([("Make", "Canon"), ("Model", "EOS R5")]);
+/// assert_eq!(fp.render(&tags), Ok("Canon EOS R5".to_string()));
+/// ```
+pub fn prefix_handler<T: ?Sized, F>(prefix: &str, handler: F) -> PrefixHandler<T, F>
+where
+    F: Fn(&T, &str) -> Option<String> + Send + Sync + 'static,
+{
+    PrefixHandler {
+        prefix: prefix.into(),
+        handler: Arc::new(handler),
+        routes: Memo::new(),
+    }
+}
+
+impl<T: ?Sized, F> PrefixHandler<T, F>
+where
+    F: Fn(&T, &str) -> Option<String> + Send + Sync + 'static,
+{
+    /// The callback passing `key` to the handler, the same one each time for the same key.
+    fn route(&self, key: &str) -> FormatterCallback<T> {
+        self.routes.get_or_insert(
+            key,
+            |_| true,
+            || {
+                let handler = Arc::clone(&self.handler);
+                let key = String::from(key);
+                Arc::new(move |data| handler(data, &key))
+            },
+        )
+    }
+}
+
+impl<T: ?Sized, F> PrefixHandler<T, F> {
+    /// Forget the callbacks remembered for each key looked up so far.
+    pub fn clear(&self) {
+        self.routes.clear();
+    }
+}
+
+impl<T, F> KeyLookup<T> for PrefixHandler<T, F>
+where
+    T: ?Sized,
+    F: Fn(&T, &str) -> Option<String> + Send + Sync + 'static,
+{
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        if key.len() > self.prefix.len() && key.starts_with(self.prefix.as_str()) {
+            Some(self.route(key))
+        } else {
+            None
+        }
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        let matches = key.len() > self.prefix.len()
+            && key
+                .get(..self.prefix.len())
+                .map_or(false, |p| keys_eq_ignore_case(p, &self.prefix));
+        if matches {
+            Some(self.route(key))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: ?Sized, F> Clone for PrefixHandler<T, F> {
+    fn clone(&self) -> Self {
+        Self {
+            prefix: self.prefix.clone(),
+            handler: Arc::clone(&self.handler),
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized, F> fmt::Debug for PrefixHandler<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrefixHandler")
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
@@ -0,0 +1,144 @@
+use super::*;
+
+struct Item {
+    name: &'static str,
+}
+
+struct Meta {
+    size: u64,
+}
+
+fn lookup() -> impl KeyLookup<(Item, Meta)> {
+    let items: FormatMap<Item> = fm! {"name" => |i: &Item| Some(i.name.to_string())};
+    let meta: FormatMap<Meta> = fm! {"size" => |m: &Meta| Some(m.size.to_string())};
+    (
+        namespace("", items, |d: &(Item, Meta)| &d.0),
+        namespace("meta", meta, |d: &(Item, Meta)| &d.1),
+    )
+}
+
+#[test]
+fn namespaced_keys() {
+    let fp = lookup().to_format_pieces("{name}={meta.size}").unwrap();
+    let data = (Item { name: "a" }, Meta { size: 3 });
+    assert_eq!(fp.render(&data), Ok("a=3".to_string()));
+}
+
+#[test]
+fn namespace_requires_separator() {
+    for key in ["size", "metasize", "meta.", "meta.name", "name.x"] {
+        assert_eq!(
+            lookup().to_format_pieces(format!("{{{key}}}")).map(|_| ()),
+            Err(Error::UnknownKey(key.into())),
+            "{key}"
+        );
+    }
+}
+
+#[test]
+fn prefix_handler_gets_full_key() {
+    let lookup = (
+        fm! {"exif.iso" => |_: &u32| Some("fixed".to_string())},
+        prefix_handler("exif.", |n: &u32, key: &str| {
+            if key == "exif.missing" {
+                None
+            } else {
+                Some(format!("{key}={n}"))
+            }
+        }),
+    );
+    let fp = lookup
+        .to_format_pieces("{exif.iso} {exif.Model} {exif.a.b}")
+        .unwrap();
+    assert_eq!(
+        fp.render(&7),
+        Ok("fixed exif.Model=7 exif.a.b=7".to_string())
+    );
+
+    let fp = lookup.to_format_pieces("{exif.missing}").unwrap();
+    assert_eq!(fp.render(&7), Err(Error::NoData("exif.missing".into())));
+
+    for key in ["exif.", "exif", "other.Model"] {
+        assert_eq!(
+            lookup.to_format_pieces(format!("{{{key}}}")).map(|_| ()),
+            Err(Error::UnknownKey(key.into())),
+        );
+    }
+
+    let opts = CompileOptions::new().case_insensitive(true);
+    let fp = lookup.to_format_pieces_with("{EXIF.Model}", &opts).unwrap();
+    assert_eq!(fp.render(&1), Ok("EXIF.Model=1".to_string()));
+}
+
+#[test]
+fn namespace_over_borrowed_sources() {
+    let items = [Item { name: "a" }, Item { name: "b" }];
+    let metas = [Meta { size: 1 }, Meta { size: 2 }];
+    let names: FormatMap<Item> = fm! {"name" => |i: &Item| Some(i.name.to_string())};
+    let sizes: FormatMap<Meta> = fm! {"size" => |m: &Meta| Some(m.size.to_string())};
+    let lookup = (
+        namespace("", names, |d: &(&Item, &Meta)| d.0),
+        namespace("meta", sizes, |d: &(&Item, &Meta)| d.1),
+    );
+    let fp = lookup.to_format_pieces("{name}={meta.size}").unwrap();
+
+    let out: Vec<_> = items
+        .iter()
+        .zip(&metas)
+        .map(|data| fp.render(&data).unwrap())
+        .collect();
+    assert_eq!(out, ["a=1", "b=2"]);
+}
+
+#[test]
+fn wrapped_callbacks_are_reused() {
+    let lookup = (
+        lookup(),
+        prefix_handler("x.", |_: &(Item, Meta), key: &str| Some(key.to_string())),
+    );
+    let tmpl = "{name} {meta.size} {x.y}";
+    let mut fp = lookup.to_format_pieces(tmpl).unwrap();
+    assert!(fp.same_bindings(&lookup.to_format_pieces(tmpl).unwrap()));
+    assert!(fp.refresh(&lookup).unwrap().is_empty());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn prefix_handler_memory_is_bounded() {
+    use crate::context::Memo;
+
+    let lookup = prefix_handler("x.", |_: &(), key: &str| Some(key.to_string()));
+    let first = lookup.lookup("x.0").unwrap();
+    for i in 0..2 * Memo::<()>::CAPACITY {
+        lookup.lookup(&format!("x.{i}")).unwrap();
+    }
+    assert_eq!(lookup.routes.len(), Memo::<()>::CAPACITY);
+
+    // Recently used keys are still remembered, while the oldest were forgotten
+    let last = format!("x.{}", 2 * Memo::<()>::CAPACITY - 1);
+    assert!(Arc::ptr_eq(
+        &lookup.lookup(&last).unwrap(),
+        &lookup.lookup(&last).unwrap()
+    ));
+    assert!(!Arc::ptr_eq(&first, &lookup.lookup("x.0").unwrap()));
+
+    lookup.clear();
+    assert_eq!(lookup.routes.len(), 0);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn namespace_memory_is_bounded() {
+    use crate::context::Memo;
+
+    let inner = prefix_handler("x.", |_: &(), key: &str| Some(key.to_string()));
+    let lookup = namespace("ns", inner, |d: &((), ())| &d.0);
+    for i in 0..2 * Memo::<()>::CAPACITY {
+        let fp = lookup.to_format_pieces(format!("{{ns.x.{i}}}")).unwrap();
+        assert_eq!(fp.render(&((), ())), Ok(format!("x.{i}")));
+    }
+    assert_eq!(lookup.wrapped.len(), Memo::<()>::CAPACITY);
+
+    lookup.clear();
+    assert_eq!(lookup.wrapped.len(), 0);
+}
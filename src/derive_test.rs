@@ -0,0 +1,46 @@
+use super::*;
+
+fn shout(s: &str) -> Option<String> {
+    Some(s.to_uppercase())
+}
+
+#[derive(FormatKeys)]
+struct Named<'a> {
+    r#type: &'a str,
+    #[funcfmt(rename = "n")]
+    count: u32,
+    #[funcfmt(with = shout)]
+    name: &'a str,
+    #[funcfmt(skip)]
+    #[allow(dead_code)]
+    hidden: (),
+}
+
+#[derive(FormatKeys)]
+struct Tuple<T: fmt::Display>(T, #[funcfmt(skip)] ());
+
+#[test]
+fn derive_named_fields() {
+    let map = Named::format_map();
+    let mut keys: Vec<_> = map.keys().map(|k| k.as_str()).collect();
+    keys.sort_unstable();
+    assert_eq!(keys, vec!["n", "name", "type"]);
+
+    let fp = map.to_format_pieces("{type}:{n}:{name}").unwrap();
+    let data = Named {
+        r#type: "a",
+        count: 2,
+        name: "foo",
+        hidden: (),
+    };
+    assert_eq!(fp.render(&data), Ok("a:2:FOO".to_string()));
+}
+
+#[test]
+fn derive_tuple_generic() {
+    let fp = Tuple::<f64>::format_map()
+        .to_format_pieces("<{0}>")
+        .unwrap();
+    assert_eq!(fp.render(&Tuple(1.5, ())), Ok("<1.5>".to_string()));
+    assert!(Tuple::<f64>::format_map().to_format_pieces("{1}").is_err());
+}
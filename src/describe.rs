@@ -0,0 +1,190 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "indexmap")]
+use crate::OrderedFormatMap;
+use crate::{FormatMap, FormatterCallback, KeyLookup, KeyString};
+
+/// Documentation for a single key, as returned by `DescribeKeys::describe_keys`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyInfo {
+    /// The name of the key, as used in templates.
+    pub key: KeyString,
+    /// What the key expands to.
+    pub description: Option<String>,
+    /// An example of the key's output.
+    pub example: Option<String>,
+}
+
+impl KeyInfo {
+    /// Create undocumented metadata for `key`.
+    pub fn new(key: impl Into<KeyString>) -> Self {
+        Self {
+            key: key.into(),
+            description: None,
+            example: None,
+        }
+    }
+
+    /// Set the description of the key.
+    pub fn description(&mut self, description: impl Into<String>) -> &mut Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Set an example of the key's output.
+    pub fn example(&mut self, example: impl Into<String>) -> &mut Self {
+        self.example = Some(example.into());
+        self
+    }
+}
+
+/// A trait for listing the keys a map provides, for example to generate help output describing
+/// the placeholders available in templates.
+///
+/// Plain maps like `FormatMap<T>` have nowhere to store descriptions, so only list their keys,
+/// sorted by name. `DescribedMap<T>` also includes descriptions and examples, in insertion order.
+pub trait DescribeKeys {
+    /// List the keys in this map along with any documentation for them.
+    fn describe_keys(&self) -> Vec<KeyInfo>;
+}
+
+impl<T: ?Sized> DescribeKeys for FormatMap<T> {
+    fn describe_keys(&self) -> Vec<KeyInfo> {
+        let mut keys: Vec<KeyInfo> = self.keys().map(|k| KeyInfo::new(k.clone())).collect();
+        keys.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        keys
+    }
+}
+
+#[cfg(feature = "indexmap")]
+impl<T: ?Sized> DescribeKeys for OrderedFormatMap<T> {
+    fn describe_keys(&self) -> Vec<KeyInfo> {
+        self.keys().map(|k| KeyInfo::new(k.clone())).collect()
+    }
+}
+
+/// A map of keys to callbacks, which also stores a description and example for each key.
+///
+/// It can be built with `fm!` by following callbacks with `=>` and a description, or with
+/// `insert`, which returns the key's `KeyInfo` so that its documentation can be filled in.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, DescribeKeys, DescribedMap, Render, ToFormatPieces};
+///
+/// let mut fmap: DescribedMap<String> = fm!{
+///     "name" => |data: &String| Some(data.clone()) => "The file name",
+///     "len" => |data: &String| Some(data.len().to_string()),
+/// };
+/// fmap.insert("upper", |data: &String| Some(data.to_uppercase()))
+///     .description("The file name in upper case")
+///     .example("README.MD");
+///
+/// let fp = fmap.to_format_pieces("{upper} ({len})").unwrap();
+/// assert_eq!(fp.render(&String::from("a.txt")), Ok("A.TXT (5)".to_string()));
+///
+/// for info in fmap.describe_keys() {
+///     println!("{{{}}}: {}", info.key, info.description.as_deref().unwrap_or("-"));
+/// }
+/// ```
+pub struct DescribedMap<T: ?Sized> {
+    map: FormatMap<T>,
+    info: Vec<KeyInfo>,
+}
+
+impl<T: ?Sized> DescribedMap<T> {
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Self {
+            map: FormatMap::default(),
+            info: Vec::new(),
+        }
+    }
+
+    /// Register `cb` for `key`, replacing any existing callback and its documentation. Returns
+    /// the key's metadata, so that a description and example can be added.
+    pub fn insert<K, F>(&mut self, key: K, cb: F) -> &mut KeyInfo
+    where
+        K: Into<KeyString>,
+        F: Fn(&T) -> Option<String> + Send + Sync + 'static,
+    {
+        self.insert_callback(key, Arc::new(cb))
+    }
+
+    /// Like `insert`, but taking an existing `FormatterCallback<T>`.
+    pub fn insert_callback<K: Into<KeyString>>(
+        &mut self,
+        key: K,
+        cb: FormatterCallback<T>,
+    ) -> &mut KeyInfo {
+        let key = key.into();
+        let idx = match self.info.iter().position(|info| info.key == key) {
+            Some(idx) => {
+                self.info[idx] = KeyInfo::new(key.clone());
+                idx
+            }
+            None => {
+                self.info.push(KeyInfo::new(key.clone()));
+                self.info.len() - 1
+            }
+        };
+        self.map.insert(key, cb);
+        &mut self.info[idx]
+    }
+
+    /// The callback registered for `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&FormatterCallback<T>> {
+        self.map.get(key)
+    }
+
+    /// The documentation for `key`, if it is registered.
+    pub fn info(&self, key: &str) -> Option<&KeyInfo> {
+        self.info.iter().find(|info| info.key == key)
+    }
+
+    /// The underlying map of callbacks, without documentation.
+    pub fn format_map(&self) -> &FormatMap<T> {
+        &self.map
+    }
+}
+
+impl<T: ?Sized> Default for DescribedMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> Clone for DescribedMap<T> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            info: self.info.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for DescribedMap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.info).finish()
+    }
+}
+
+impl<T: ?Sized> DescribeKeys for DescribedMap<T> {
+    fn describe_keys(&self) -> Vec<KeyInfo> {
+        self.info.clone()
+    }
+}
+
+impl<T: ?Sized> KeyLookup<T> for DescribedMap<T> {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.map.lookup(key)
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.map.lookup_ignore_case(key)
+    }
+}
@@ -0,0 +1,51 @@
+use super::*;
+
+#[test]
+fn describe_format_map_sorted() {
+    let fmap: FormatMap<String> = fm! {
+        "b" => |_| None,
+        "a" => |_| None,
+    };
+    assert_eq!(
+        fmap.describe_keys(),
+        vec![KeyInfo::new("a"), KeyInfo::new("b")]
+    );
+}
+
+#[test]
+fn described_map() {
+    let mut fmap: DescribedMap<String> = fm! {
+        "name" => |data: &String| Some(data.clone()) => "The name",
+        "len" => |data: &String| Some(data.len().to_string()),
+    };
+    fmap.insert("upper", |data: &String| Some(data.to_uppercase()))
+        .description("Shouting")
+        .example("ABC");
+    assert_eq!(
+        fmap.describe_keys(),
+        vec![
+            KeyInfo {
+                key: "name".into(),
+                description: Some("The name".into()),
+                example: None,
+            },
+            KeyInfo::new("len"),
+            KeyInfo {
+                key: "upper".into(),
+                description: Some("Shouting".into()),
+                example: Some("ABC".into()),
+            },
+        ]
+    );
+
+    // Reinserting keeps the key's position, but drops its old documentation
+    fmap.insert("name", |_| Some("new".to_string()));
+    assert_eq!(fmap.describe_keys()[0], KeyInfo::new("name"));
+
+    let fp = fmap.to_format_pieces("{name} {len} {upper}").unwrap();
+    assert_eq!(fp.render(&String::from("ab")), Ok("new 2 AB".to_string()));
+    assert_eq!(
+        fmap.to_format_pieces("{missing}").map(|_| ()),
+        Err(Error::UnknownKey("missing".into()))
+    );
+}
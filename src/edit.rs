@@ -0,0 +1,145 @@
+use alloc::sync::Arc;
+
+use crate::{
+    Error, FormatPiece, FormatPieces, Formatter, FormatterCallback, KeyLookup, ToFormatPieces,
+};
+
+/// A trait for editing already processed format pieces in place, without reparsing the whole
+/// template.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, EditPieces, FormatMap, Render, ToFormatPieces};
+/// use std::sync::Arc;
+///
+/// let fmap: FormatMap<String> = fm!{
+///     "name" => |data: &String| Some(data.clone()),
+///     "size" => |data: &String| Some(data.len().to_string()),
+/// };
+/// let mut fp = fmap.to_format_pieces("{name}").unwrap();
+///
+/// // The user turned on sizes
+/// fp.push_template(&fmap, " ({size} bytes)").unwrap();
+/// assert_eq!(fp.render(&String::from("abc")), Ok("abc (3 bytes)".to_string()));
+///
+/// // ... and then asked for names to be shouted
+/// fp.replace_key("name", Arc::new(|data: &String| Some(data.to_uppercase())));
+/// assert_eq!(fp.render(&String::from("abc")), Ok("ABC (3 bytes)".to_string()));
+///
+/// fp.remove_key("name", None);
+/// assert_eq!(fp.render(&String::from("abc")), Ok(" (3 bytes)".to_string()));
+/// ```
+pub trait EditPieces<T: ?Sized> {
+    /// Replace the callback of every formatter for `key` with `cb`. Returns the number of
+    /// formatters which were changed.
+    fn replace_key(&mut self, key: &str, cb: FormatterCallback<T>) -> usize;
+
+    /// Remove every formatter for `key`. If `replacement` is given, each formatter is replaced
+    /// with that verbatim text instead of being dropped. Returns the number of formatters which
+    /// were removed.
+    fn remove_key(&mut self, key: &str, replacement: Option<&str>) -> usize;
+
+    /// Append `pieces`, merging verbatim text at the join (and between adjacent verbatim pieces in
+    /// `pieces`) into a single piece.
+    ///
+    /// `FormatPieces<T>` also implements `Extend<FormatPiece<T>>`, which appends pieces as-is
+    /// without merging anything.
+    fn extend_merged<I: IntoIterator<Item = FormatPiece<T>>>(&mut self, pieces: I);
+
+    /// Process `tmpl` using `map`, and append the result to these pieces, merging verbatim text at
+    /// the join as with `extend_merged`.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `ToFormatPieces::to_format_pieces`. In this case nothing is appended.
+    fn push_template<L, S>(&mut self, map: &L, tmpl: S) -> Result<(), Error>
+    where
+        L: KeyLookup<T> + ?Sized,
+        S: AsRef<str>;
+}
+
+impl<T: ?Sized> EditPieces<T> for FormatPieces<T> {
+    fn replace_key(&mut self, key: &str, cb: FormatterCallback<T>) -> usize {
+        let mut replaced = 0;
+        for piece in self.iter_mut() {
+            match piece {
+                FormatPiece::Formatter(f) if f.key == key => f.cb = Arc::clone(&cb),
+                // The raw bytes would no longer match the replaced callback
+                FormatPiece::Bytes(b) if b.formatter().key == key => {
+                    *piece = FormatPiece::Formatter(Formatter::new(key, Arc::clone(&cb)));
+                }
+                _ => continue,
+            }
+            replaced += 1;
+        }
+        replaced
+    }
+
+    fn remove_key(&mut self, key: &str, replacement: Option<&str>) -> usize {
+        let before = self.len();
+        match replacement {
+            Some(text) => {
+                let mut replaced = 0;
+                for piece in self.iter_mut() {
+                    if piece.formatter().map_or(false, |f| f.key == key) {
+                        *piece = FormatPiece::Verbatim(text.into());
+                        replaced += 1;
+                    }
+                }
+                replaced
+            }
+            None => {
+                self.retain(|piece| piece.formatter().map_or(true, |f| f.key != key));
+                before - self.len()
+            }
+        }
+    }
+
+    fn push_template<L, S>(&mut self, map: &L, tmpl: S) -> Result<(), Error>
+    where
+        L: KeyLookup<T> + ?Sized,
+        S: AsRef<str>,
+    {
+        let pieces = map.to_format_pieces(tmpl)?;
+        self.extend_merged(pieces);
+        Ok(())
+    }
+
+    fn extend_merged<I: IntoIterator<Item = FormatPiece<T>>>(&mut self, pieces: I) {
+        for piece in pieces {
+            match (self.last_mut(), piece) {
+                (Some(FormatPiece::Verbatim(last)), FormatPiece::Verbatim(text)) => {
+                    last.push_str(&text);
+                }
+                (_, piece) => self.push(piece),
+            }
+        }
+    }
+}
+
+/// Join several independently processed fragments into a single `FormatPieces<T>`, merging
+/// verbatim text at each join.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{concat, fm, FormatMap, FormatPiece, Render, ToFormatPieces};
+///
+/// let fmap: FormatMap<String> = fm!{"body" => |data: &String| Some(data.clone())};
+/// let prefix = fmap.to_format_pieces("[").unwrap();
+/// let body = fmap.to_format_pieces("{body}!").unwrap();
+/// let suffix = fmap.to_format_pieces("]").unwrap();
+///
+/// let fp = concat([prefix, body, suffix]);
+/// assert_eq!(fp.len(), 3);
+/// assert_eq!(fp[2], FormatPiece::Verbatim("!]".into()));
+/// assert_eq!(fp.render(&String::from("x")), Ok("[x!]".to_string()));
+/// ```
+pub fn concat<T: ?Sized, I: IntoIterator<Item = FormatPieces<T>>>(parts: I) -> FormatPieces<T> {
+    let mut out = FormatPieces::new();
+    for part in parts {
+        out.extend_merged(part);
+    }
+    out
+}
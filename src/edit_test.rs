@@ -0,0 +1,68 @@
+use super::*;
+
+fn fmap() -> FormatMap<String> {
+    fm! {
+        "foo" => |e: &String| Some(format!("f{e}")),
+        "bar" => |e: &String| Some(format!("b{e}")),
+    }
+}
+
+#[test]
+fn replace_and_remove() {
+    let inp = String::from("x");
+    let mut fp = fmap().to_format_pieces("{foo}-{bar}-{foo}").unwrap();
+
+    assert_eq!(
+        fp.replace_key("foo", Arc::new(|_| Some("F".to_string()))),
+        2
+    );
+    assert_eq!(fp.replace_key("missing", Arc::new(|_| None)), 0);
+    assert_eq!(fp.render(&inp).unwrap(), "F-bx-F");
+
+    assert_eq!(fp.remove_key("foo", Some("?")), 2);
+    assert_eq!(fp.render(&inp).unwrap(), "?-bx-?");
+
+    assert_eq!(fp.remove_key("bar", None), 1);
+    assert_eq!(fp.render(&inp).unwrap(), "?--?");
+    assert_eq!(fp.len(), 4);
+}
+
+#[test]
+fn push_template_is_atomic() {
+    let inp = String::from("x");
+    let mut fp = fmap().to_format_pieces("{foo}").unwrap();
+
+    fp.push_template(&fmap(), " {bar}").unwrap();
+    assert_eq!(fp.render(&inp).unwrap(), "fx bx");
+
+    assert_eq!(
+        fp.push_template(&fmap(), " {bar} {baz}"),
+        Err(Error::UnknownKey("baz".into()))
+    );
+    assert_eq!(
+        fp.push_template(&fmap(), "{"),
+        Err(Error::ImbalancedBrackets)
+    );
+    assert_eq!(fp.render(&inp).unwrap(), "fx bx");
+}
+
+#[test]
+fn concat_merges_verbatim() {
+    let inp = String::from("x");
+    let parts =
+        ["a{foo}b", "c", "", "d{bar}{foo}", "e"].map(|tmpl| fmap().to_format_pieces(tmpl).unwrap());
+    let fp = concat(parts);
+    assert_eq!(fp.render(&inp).unwrap(), "afxbcdbxfxe");
+    assert_eq!(fp.len(), 6);
+    assert_eq!(fp[2], FormatPiece::Verbatim("bcd".into()));
+
+    assert!(concat::<String, _>([]).is_empty());
+
+    // Plain Extend keeps pieces as they are
+    let mut fp = fmap().to_format_pieces("a").unwrap();
+    fp.extend(fmap().to_format_pieces("b").unwrap());
+    assert_eq!(fp.len(), 2);
+    fp.extend_merged(fmap().to_format_pieces("c{foo}").unwrap());
+    assert_eq!(fp.len(), 3);
+    assert_eq!(fp.render(&inp).unwrap(), "abcfx");
+}
@@ -0,0 +1,122 @@
+use alloc::borrow::Cow;
+use alloc::string::String;
+
+/// An escaper applied to callback output during rendering, so that substituted values can be
+/// safely embedded in the surrounding template. Verbatim template text is never escaped.
+///
+/// Attach an escaper to rendering with `RenderOptions::escaper`.
+pub trait Escaper: Send + Sync {
+    /// Escape `s`, returning it unmodified where possible.
+    fn escape<'a>(&self, s: &'a str) -> Cow<'a, str>;
+}
+
+/// Escapes the characters which are significant in HTML text and attribute values: `&`, `<`, `>`,
+/// `"`, and `'`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{Escaper, HtmlEscaper};
+///
+/// assert_eq!(HtmlEscaper.escape("<a href='x'>"), "&lt;a href=&#39;x&#39;&gt;");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HtmlEscaper;
+
+impl Escaper for HtmlEscaper {
+    fn escape<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        if !s.contains(['&', '<', '>', '"', '\'']) {
+            return Cow::Borrowed(s);
+        }
+
+        let mut out = String::with_capacity(s.len().saturating_add(16));
+        for c in s.chars() {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                '\'' => out.push_str("&#39;"),
+                c => out.push(c),
+            }
+        }
+        Cow::Owned(out)
+    }
+}
+
+/// Quotes values so that they are treated as a single word by a POSIX shell.
+///
+/// Values consisting only of characters which are never special to the shell are left as-is.
+/// Everything else is wrapped in single quotes, with any embedded single quotes written as `'\''`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{Escaper, ShellEscaper};
+///
+/// assert_eq!(ShellEscaper.escape("file.txt"), "file.txt");
+/// assert_eq!(ShellEscaper.escape("it's; rm -rf /"), r"'it'\''s; rm -rf /'");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShellEscaper;
+
+fn is_shell_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c)
+}
+
+impl Escaper for ShellEscaper {
+    fn escape<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        if !s.is_empty() && s.chars().all(is_shell_safe) {
+            return Cow::Borrowed(s);
+        }
+
+        let mut out = String::with_capacity(s.len().saturating_add(2));
+        out.push('\'');
+        for c in s.chars() {
+            match c {
+                '\'' => out.push_str(r"'\''"),
+                c => out.push(c),
+            }
+        }
+        out.push('\'');
+        Cow::Owned(out)
+    }
+}
+
+/// Sanitizes values so that they are usable as a single path component.
+///
+/// Path separators (`/` and `\`) and control characters (including NUL) are replaced with `_`.
+/// Values which are exactly `.` or `..` are also replaced with `_`, since they would otherwise
+/// refer to an existing directory.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{Escaper, FilenameEscaper};
+///
+/// assert_eq!(FilenameEscaper.escape("IMG_0001.JPG"), "IMG_0001.JPG");
+/// assert_eq!(FilenameEscaper.escape("../etc/passwd"), ".._etc_passwd");
+/// assert_eq!(FilenameEscaper.escape(".."), "_");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilenameEscaper;
+
+fn is_filename_unsafe(c: char) -> bool {
+    c == '/' || c == '\\' || c.is_control()
+}
+
+impl Escaper for FilenameEscaper {
+    fn escape<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        if s == "." || s == ".." {
+            return Cow::Borrowed("_");
+        }
+        if !s.contains(is_filename_unsafe) {
+            return Cow::Borrowed(s);
+        }
+        Cow::Owned(
+            s.chars()
+                .map(|c| if is_filename_unsafe(c) { '_' } else { c })
+                .collect(),
+        )
+    }
+}
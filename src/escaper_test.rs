@@ -0,0 +1,54 @@
+use super::*;
+use std::borrow::Cow;
+
+#[test]
+fn html_escapes_specials() {
+    assert_eq!(
+        HtmlEscaper.escape(r#"<b>"a" & 'b'</b>"#),
+        "&lt;b&gt;&quot;a&quot; &amp; &#39;b&#39;&lt;/b&gt;"
+    );
+    assert!(matches!(HtmlEscaper.escape("plain"), Cow::Borrowed(_)));
+}
+
+#[test]
+fn shell_quotes_unsafe_values() {
+    assert!(matches!(ShellEscaper.escape("a-b_c.d/e"), Cow::Borrowed(_)));
+    assert_eq!(ShellEscaper.escape(""), "''");
+    assert_eq!(ShellEscaper.escape("$(id)"), "'$(id)'");
+    assert_eq!(ShellEscaper.escape("'"), r"''\'''");
+}
+
+#[test]
+fn filename_sanitizes_separators_and_controls() {
+    assert_eq!(FilenameEscaper.escape("a/b\\c\0d\ne"), "a_b_c_d_e");
+    assert_eq!(FilenameEscaper.escape("."), "_");
+    assert_eq!(FilenameEscaper.escape(""), "");
+    assert!(matches!(FilenameEscaper.escape("ok.txt"), Cow::Borrowed(_)));
+}
+
+#[test]
+fn escaper_applies_to_callback_output_only() {
+    let fmap: FormatMap<String> = fm! {
+        "val" => |e: &String| Some(e.to_string()),
+    };
+    let fp = fmap.to_format_pieces("echo '<' {val}").unwrap();
+    let opts = RenderOptions::new().escaper(ShellEscaper);
+    let inp = String::from("a b");
+    assert_eq!(fp.render_with(&inp, &opts), Ok("echo '<' 'a b'".to_owned()));
+}
+
+#[test]
+fn escaper_applies_after_transformer() {
+    let fmap: FormatMap<String> = fm! {
+        "val" => |e: &String| Some(e.to_string()),
+    };
+    let fp = fmap.to_format_pieces("<p>{val}</p>").unwrap();
+    let opts = RenderOptions::new()
+        .transformer(|_, val| Cow::Owned(format!("<{val}>")))
+        .escaper(HtmlEscaper);
+    let inp = String::from("x");
+    assert_eq!(
+        fp.render_with(&inp, &opts),
+        Ok("<p>&lt;x&gt;</p>".to_owned())
+    );
+}
@@ -0,0 +1,307 @@
+//! A C interface for processing and rendering templates, where the data is a list of named
+//! strings.
+//!
+//! This lets C, or anything which can call C, such as Python's `ctypes`, use the same parsing and
+//! escaping rules as Rust code, rather than reimplementing them. The declarations are in
+//! `include/funcfmt.h`, which is generated by `cbindgen` from this module using `cbindgen.toml`.
+//! To build a library to link against, run:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! A template is processed once with `funcfmt_compile`, given the names of the keys it may use,
+//! and then rendered any number of times with `funcfmt_render`, given a value for each key in the
+//! same order. The numeric values of `FuncfmtStatus` are stable, and new ones are only ever
+//! added, so they can be hardcoded by callers which can't use the header.
+//!
+//! Strings passed in must be NUL-terminated and valid UTF-8. Strings passed out are owned by the
+//! caller, and must be freed with `funcfmt_string_free`.
+//!
+//! # Example
+//!
+//! ```c
+//! const char *keys[] = {"name", "count"};
+//! const char *values[] = {"world", "3"};
+//! FuncfmtTemplate *tmpl;
+//! char *out, *err;
+//!
+//! if (funcfmt_compile("Hello, {name}! ({count})", keys, 2, &tmpl, &err) != FUNCFMT_STATUS_OK) {
+//!     fprintf(stderr, "%s\n", err);
+//!     funcfmt_string_free(err);
+//!     return 1;
+//! }
+//! if (funcfmt_render(tmpl, values, 2, &out, NULL) == FUNCFMT_STATUS_OK) {
+//!     puts(out);
+//!     funcfmt_string_free(out);
+//! }
+//! funcfmt_template_free(tmpl);
+//! ```
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::{fmt, ptr, slice};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+
+use crate::{Error, FormatMap, FormatPieces, FormatterCallback, Render, ToFormatPieces};
+
+/// The result of a call through the C interface.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FuncfmtStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// A required pointer argument was null.
+    NullPointer = 1,
+    /// A string argument was not valid UTF-8.
+    InvalidUtf8 = 2,
+    /// The template couldn't be parsed, for example because its brackets were imbalanced.
+    InvalidTemplate = 3,
+    /// The template used a key which wasn't given to `funcfmt_compile`.
+    UnknownKey = 4,
+    /// A key used by the template had a null value when rendering.
+    NoData = 5,
+    /// The number of values given to `funcfmt_render` didn't match the number of keys the
+    /// template was compiled with.
+    ValueCount = 6,
+    /// Any other error.
+    Other = 7,
+}
+
+impl FuncfmtStatus {
+    const ALL: [Self; 8] = [
+        Self::Ok,
+        Self::NullPointer,
+        Self::InvalidUtf8,
+        Self::InvalidTemplate,
+        Self::UnknownKey,
+        Self::NoData,
+        Self::ValueCount,
+        Self::Other,
+    ];
+
+    /// The status with the numeric value `raw`, if there is one.
+    fn from_raw(raw: c_int) -> Option<Self> {
+        Self::ALL.into_iter().find(|&status| status as c_int == raw)
+    }
+}
+
+impl From<&Error> for FuncfmtStatus {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::UnknownKey(_) => Self::UnknownKey,
+            Error::NoData(_) => Self::NoData,
+            Error::ImbalancedBrackets
+            | Error::EmptyKey
+            | Error::KeyWhitespace(_)
+            | Error::StrayEscape(_) => Self::InvalidTemplate,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// A processed template, created by `funcfmt_compile` and freed by `funcfmt_template_free`.
+///
+/// The data it is rendered with is the value for each key, by position.
+pub struct FuncfmtTemplate {
+    pieces: FormatPieces<[Option<String>]>,
+    nkeys: usize,
+}
+
+/// Record `status` along with `msg` in `error`, if it isn't null.
+unsafe fn fail(
+    error: *mut *mut c_char,
+    status: FuncfmtStatus,
+    msg: impl fmt::Display,
+) -> FuncfmtStatus {
+    if !error.is_null() {
+        // Messages come from our own errors and from valid UTF-8 input, so never contain NUL
+        *error = CString::new(msg.to_string()).map_or(ptr::null_mut(), CString::into_raw);
+    }
+    status
+}
+
+/// Borrow a C string as a `&str`. `what` describes it for error messages.
+unsafe fn borrow_str<'a>(s: *const c_char, what: &str) -> Result<&'a str, (FuncfmtStatus, String)> {
+    if s.is_null() {
+        return Err((FuncfmtStatus::NullPointer, format!("{what} is null")));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| {
+        (
+            FuncfmtStatus::InvalidUtf8,
+            format!("{what} is not valid UTF-8"),
+        )
+    })
+}
+
+/// Borrow a C array of `len` elements, which may only be null if it is empty.
+unsafe fn borrow_array<'a, T>(
+    arr: *const T,
+    len: usize,
+    what: &str,
+) -> Result<&'a [T], (FuncfmtStatus, String)> {
+    match (arr.is_null(), len) {
+        (true, 0) => Ok(&[]),
+        (true, _) => Err((FuncfmtStatus::NullPointer, format!("{what} is null"))),
+        (false, _) => Ok(slice::from_raw_parts(arr, len)),
+    }
+}
+
+/// Process `tmpl`, which may use the `nkeys` key names in `keys`, and store the result in `out`.
+///
+/// On failure, `out` is left unchanged, and if `error` isn't null, a message describing the
+/// failure is stored in it, which must be freed with `funcfmt_string_free`.
+///
+/// # Safety
+///
+/// `tmpl` and each of the strings in `keys` must be NUL-terminated, and `keys` must point to
+/// `nkeys` strings. `out` must be valid for writes, and so must `error` unless it's null.
+#[no_mangle]
+pub unsafe extern "C" fn funcfmt_compile(
+    tmpl: *const c_char,
+    keys: *const *const c_char,
+    nkeys: usize,
+    out: *mut *mut FuncfmtTemplate,
+    error: *mut *mut c_char,
+) -> FuncfmtStatus {
+    if out.is_null() {
+        return fail(error, FuncfmtStatus::NullPointer, "out is null");
+    }
+    let tmpl = match borrow_str(tmpl, "template") {
+        Ok(tmpl) => tmpl,
+        Err((status, msg)) => return fail(error, status, msg),
+    };
+    let keys = match borrow_array(keys, nkeys, "keys") {
+        Ok(keys) => keys,
+        Err((status, msg)) => return fail(error, status, msg),
+    };
+
+    let mut fmap: FormatMap<[Option<String>]> = FormatMap::default();
+    for (idx, &key) in keys.iter().enumerate() {
+        let key = match borrow_str(key, "key") {
+            Ok(key) => key,
+            Err((status, msg)) => return fail(error, status, msg),
+        };
+        let cb: FormatterCallback<[Option<String>]> =
+            Arc::new(move |values: &[Option<String>]| values.get(idx)?.clone());
+        fmap.insert(key.into(), cb);
+    }
+
+    match fmap.to_format_pieces(tmpl) {
+        Ok(pieces) => {
+            *out = Box::into_raw(Box::new(FuncfmtTemplate { pieces, nkeys }));
+            FuncfmtStatus::Ok
+        }
+        Err(err) => fail(error, (&err).into(), err),
+    }
+}
+
+/// Render `tmpl` with `values`, which holds the value for each key passed to `funcfmt_compile`, in
+/// the same order. A null value means that key has no data. The output is stored in `out`, and
+/// must be freed with `funcfmt_string_free`.
+///
+/// On failure, `out` is left unchanged, and if `error` isn't null, a message describing the
+/// failure is stored in it, which must be freed with `funcfmt_string_free`.
+///
+/// # Safety
+///
+/// `tmpl` must have been returned by `funcfmt_compile` and not yet freed. `values` must point to
+/// `nvalues` pointers, each of which is null or NUL-terminated. `out` must be valid for writes,
+/// and so must `error` unless it's null.
+#[no_mangle]
+pub unsafe extern "C" fn funcfmt_render(
+    tmpl: *const FuncfmtTemplate,
+    values: *const *const c_char,
+    nvalues: usize,
+    out: *mut *mut c_char,
+    error: *mut *mut c_char,
+) -> FuncfmtStatus {
+    if tmpl.is_null() {
+        return fail(error, FuncfmtStatus::NullPointer, "template is null");
+    }
+    if out.is_null() {
+        return fail(error, FuncfmtStatus::NullPointer, "out is null");
+    }
+    let tmpl = &*tmpl;
+    if nvalues != tmpl.nkeys {
+        let msg = format!("expected {} values, got {nvalues}", tmpl.nkeys);
+        return fail(error, FuncfmtStatus::ValueCount, msg);
+    }
+    let values = match borrow_array(values, nvalues, "values") {
+        Ok(values) => values,
+        Err((status, msg)) => return fail(error, status, msg),
+    };
+
+    let mut data = Vec::with_capacity(values.len());
+    for &value in values {
+        if value.is_null() {
+            data.push(None);
+            continue;
+        }
+        match borrow_str(value, "value") {
+            Ok(value) => data.push(Some(value.to_string())),
+            Err((status, msg)) => return fail(error, status, msg),
+        }
+    }
+
+    let rendered = match tmpl.pieces.render(&data[..]) {
+        Ok(rendered) => rendered,
+        Err(err) => return fail(error, (&err).into(), err),
+    };
+    match CString::new(rendered) {
+        Ok(rendered) => {
+            *out = rendered.into_raw();
+            FuncfmtStatus::Ok
+        }
+        Err(_) => fail(error, FuncfmtStatus::Other, "output contains a NUL byte"),
+    }
+}
+
+/// Free a template returned by `funcfmt_compile`. Does nothing if `tmpl` is null.
+///
+/// # Safety
+///
+/// `tmpl` must be null, or have been returned by `funcfmt_compile` and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn funcfmt_template_free(tmpl: *mut FuncfmtTemplate) {
+    if !tmpl.is_null() {
+        drop(Box::from_raw(tmpl));
+    }
+}
+
+/// Free a string returned through the C interface. Does nothing if `s` is null.
+///
+/// # Safety
+///
+/// `s` must be null, or have been returned by a function in this module and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn funcfmt_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// A static, NUL-terminated description of `status`, which must not be freed.
+///
+/// This takes the numeric value of a `FuncfmtStatus`, so that values this library doesn't know
+/// about, for example from a newer header, are described as an unknown status rather than being
+/// undefined behaviour.
+#[no_mangle]
+pub extern "C" fn funcfmt_status_str(status: c_int) -> *const c_char {
+    let s: &'static [u8] = match FuncfmtStatus::from_raw(status) {
+        Some(FuncfmtStatus::Ok) => b"ok\0",
+        Some(FuncfmtStatus::NullPointer) => b"null pointer\0",
+        Some(FuncfmtStatus::InvalidUtf8) => b"invalid UTF-8\0",
+        Some(FuncfmtStatus::InvalidTemplate) => b"invalid template\0",
+        Some(FuncfmtStatus::UnknownKey) => b"unknown key\0",
+        Some(FuncfmtStatus::NoData) => b"no data\0",
+        Some(FuncfmtStatus::ValueCount) => b"wrong number of values\0",
+        Some(FuncfmtStatus::Other) => b"error\0",
+        None => b"unknown status\0",
+    };
+    s.as_ptr().cast()
+}
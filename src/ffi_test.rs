@@ -0,0 +1,132 @@
+use super::*;
+use crate::ffi::*;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+fn c_strings(strs: &[&str]) -> Vec<CString> {
+    strs.iter().map(|s| CString::new(*s).unwrap()).collect()
+}
+
+/// Take ownership of a string returned through the C interface.
+unsafe fn take(s: *mut c_char) -> String {
+    let out = CStr::from_ptr(s).to_str().unwrap().to_string();
+    funcfmt_string_free(s);
+    out
+}
+
+unsafe fn compile(
+    tmpl: &str,
+    keys: &[&str],
+) -> Result<*mut FuncfmtTemplate, (FuncfmtStatus, String)> {
+    let tmpl = CString::new(tmpl).unwrap();
+    let keys = c_strings(keys);
+    let key_ptrs: Vec<_> = keys.iter().map(|k| k.as_ptr()).collect();
+    let mut out = ptr::null_mut();
+    let mut err = ptr::null_mut();
+    match funcfmt_compile(
+        tmpl.as_ptr(),
+        key_ptrs.as_ptr(),
+        key_ptrs.len(),
+        &mut out,
+        &mut err,
+    ) {
+        FuncfmtStatus::Ok => Ok(out),
+        status => Err((status, take(err))),
+    }
+}
+
+unsafe fn render(
+    tmpl: *const FuncfmtTemplate,
+    values: &[Option<&str>],
+) -> Result<String, (FuncfmtStatus, String)> {
+    let owned: Vec<_> = values
+        .iter()
+        .map(|v| v.map(|v| CString::new(v).unwrap()))
+        .collect();
+    let ptrs: Vec<_> = owned
+        .iter()
+        .map(|v| v.as_ref().map_or(ptr::null(), |v| v.as_ptr()))
+        .collect();
+    let mut out = ptr::null_mut();
+    let mut err = ptr::null_mut();
+    match funcfmt_render(tmpl, ptrs.as_ptr(), ptrs.len(), &mut out, &mut err) {
+        FuncfmtStatus::Ok => Ok(take(out)),
+        status => Err((status, take(err))),
+    }
+}
+
+#[test]
+fn compile_and_render() {
+    unsafe {
+        let tmpl = compile(
+            "Hello, {name}! {{{count}}} {name?yes:no}",
+            &["name", "count"],
+        )
+        .unwrap();
+        assert_eq!(
+            render(tmpl, &[Some("world"), Some("3")]),
+            Ok("Hello, world! {3} yes".to_string())
+        );
+        assert_eq!(
+            render(tmpl, &[None, Some("3")]),
+            Err((
+                FuncfmtStatus::NoData,
+                Error::NoData("name".into()).to_string()
+            ))
+        );
+        assert_eq!(
+            render(tmpl, &[Some("world")]),
+            Err((
+                FuncfmtStatus::ValueCount,
+                "expected 2 values, got 1".to_string()
+            ))
+        );
+        funcfmt_template_free(tmpl);
+    }
+}
+
+#[test]
+fn compile_errors() {
+    unsafe {
+        assert_eq!(
+            compile("{nope}", &["name"]),
+            Err((
+                FuncfmtStatus::UnknownKey,
+                Error::UnknownKey("nope".into()).to_string()
+            ))
+        );
+        assert_eq!(
+            compile("{name", &["name"]).map_err(|(status, _)| status),
+            Err(FuncfmtStatus::InvalidTemplate)
+        );
+
+        let mut out = ptr::null_mut();
+        let status = funcfmt_compile(ptr::null(), ptr::null(), 0, &mut out, ptr::null_mut());
+        assert_eq!(status, FuncfmtStatus::NullPointer);
+        assert!(out.is_null());
+
+        let bad = [b'{', 0xff, b'}', 0];
+        let status = funcfmt_compile(
+            bad.as_ptr().cast(),
+            ptr::null(),
+            0,
+            &mut out,
+            ptr::null_mut(),
+        );
+        assert_eq!(status, FuncfmtStatus::InvalidUtf8);
+        assert_eq!(
+            CStr::from_ptr(funcfmt_status_str(status as c_int)).to_str(),
+            Ok("invalid UTF-8")
+        );
+    }
+}
+
+#[test]
+fn status_str_unknown() {
+    let describe = |status| unsafe { CStr::from_ptr(funcfmt_status_str(status)).to_str() };
+    assert_eq!(describe(FuncfmtStatus::Other as c_int), Ok("error"));
+    for status in [-1, 8, c_int::MAX] {
+        assert_eq!(describe(status), Ok("unknown status"));
+    }
+}
@@ -0,0 +1,172 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use num_format::{Buffer, Locale};
+
+use crate::{FormatterCallback, KeyLookup};
+
+/// A single filter applied to the output of a callback.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Filter {
+    /// `plural:one:other`
+    Plural { one: String, other: String },
+    /// `thousands`
+    Thousands,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Option<Self> {
+        let mut parts = spec.split(':');
+        match (parts.next()?, parts.next(), parts.next(), parts.next()) {
+            ("plural", Some(one), Some(other), None) => Some(Self::Plural {
+                one: one.into(),
+                other: other.into(),
+            }),
+            ("thousands", None, None, None) => Some(Self::Thousands),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, val: &str, locale: &Locale) -> Option<String> {
+        let num = Number::parse(val)?;
+        match self {
+            Self::Plural { one, other } => Some(if num.is_one() { one } else { other }.clone()),
+            Self::Thousands => Some(num.format(locale)),
+        }
+    }
+}
+
+/// A decimal number as output by a callback.
+struct Number<'a> {
+    negative: bool,
+    int: u64,
+    /// The digits after the decimal point, if any.
+    frac: Option<&'a str>,
+}
+
+impl<'a> Number<'a> {
+    fn parse(s: &'a str) -> Option<Self> {
+        let s = s.trim();
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (int, frac) = match s.split_once('.') {
+            Some((int, frac)) => (int, Some(frac)),
+            None => (s, None),
+        };
+        let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if !all_digits(int) || !frac.map_or(true, all_digits) {
+            return None;
+        }
+        Some(Self {
+            negative,
+            int: int.parse().ok()?,
+            frac,
+        })
+    }
+
+    fn is_one(&self) -> bool {
+        !self.negative && self.int == 1 && self.frac.map_or(true, |f| f.bytes().all(|b| b == b'0'))
+    }
+
+    fn format(&self, locale: &Locale) -> String {
+        let mut buf = Buffer::default();
+        buf.write_formatted(&self.int, locale);
+        let mut out = String::new();
+        if self.negative {
+            out.push_str(locale.minus_sign());
+        }
+        out.push_str(buf.as_str());
+        if let Some(frac) = self.frac {
+            out.push_str(locale.decimal());
+            out.push_str(frac);
+        }
+        out
+    }
+}
+
+/// A `KeyLookup<T>` which adds filters for numeric output to the keys of another, as returned by
+/// `filters`.
+#[derive(Clone, Debug)]
+pub struct Filters<L> {
+    lookup: L,
+    locale: Locale,
+}
+
+/// Allow filters to be applied to the output of the callbacks in `lookup` with
+/// `{key|filter}`. Several filters can be chained, like `{key|a|b}`, and are applied from left to
+/// right.
+///
+/// Filters expect the output of the callback to be a decimal number, like `-1234.5`, and produce
+/// no data otherwise. The available filters are:
+///
+/// - `thousands`: Add thousands separators, like `1,234,567`, using the separators of the locale.
+/// - `plural:one:other`: Output `one` if the number is exactly 1, and `other` otherwise, like
+///   `{count|plural:file:files}`. This uses English plural rules regardless of the locale.
+///
+/// Keys with unknown or malformed filters are unknown. If the entire key, including the `|`, is
+/// registered in `lookup`, it is used as-is instead.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{filters, fm, FormatMap, Render, ToFormatPieces};
+/// use num_format::Locale;
+///
+/// let fmap: FormatMap<u64> = fm!{"count" => |n: &u64| Some(n.to_string())};
+/// let fp = filters(fmap.clone())
+///     .to_format_pieces("{count|thousands} {count|plural:file:files}")
+///     .unwrap();
+/// assert_eq!(fp.render(&1), Ok("1 file".to_string()));
+/// assert_eq!(fp.render(&12345), Ok("12,345 files".to_string()));
+///
+/// let fp = filters(fmap).locale(Locale::de).to_format_pieces("{count|thousands}").unwrap();
+/// assert_eq!(fp.render(&12345), Ok("12.345".to_string()));
+/// ```
+pub fn filters<L>(lookup: L) -> Filters<L> {
+    Filters {
+        lookup,
+        locale: Locale::en,
+    }
+}
+
+impl<L> Filters<L> {
+    /// Set the locale whose separators are used by `thousands`. The default is `Locale::en`.
+    pub fn locale(mut self, locale: Locale) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    fn with_filters<T: ?Sized + 'static>(
+        &self,
+        key: &str,
+        lookup: impl Fn(&str) -> Option<FormatterCallback<T>>,
+    ) -> Option<FormatterCallback<T>> {
+        if let Some(cb) = lookup(key) {
+            return Some(cb);
+        }
+        let (name, rest) = key.split_once('|')?;
+        let cb = lookup(name)?;
+        let filters = rest
+            .split('|')
+            .map(Filter::parse)
+            .collect::<Option<Vec<_>>>()?;
+        let locale = self.locale;
+        Some(Arc::new(move |data| {
+            filters
+                .iter()
+                .try_fold(cb(data)?, |val, filter| filter.apply(&val, &locale))
+        }))
+    }
+}
+
+impl<T: ?Sized + 'static, L: KeyLookup<T>> KeyLookup<T> for Filters<L> {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.with_filters(key, |k| self.lookup.lookup(k))
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.with_filters(key, |k| self.lookup.lookup_ignore_case(k))
+    }
+}
@@ -0,0 +1,60 @@
+use super::*;
+use num_format::Locale;
+
+fn fmap() -> FormatMap<&'static str> {
+    fm! {
+        "n" => |s: &&str| Some(s.to_string()),
+        "a|b" => |_| Some("literal".to_string()),
+    }
+}
+
+#[test]
+fn thousands_and_plural() {
+    let fp = filters(fmap())
+        .to_format_pieces("{n|thousands} {n|plural:item:items}")
+        .unwrap();
+    for (n, expected) in [
+        ("0", "0 items"),
+        ("1", "1 item"),
+        ("1.0", "1.0 item"),
+        ("1.5", "1.5 items"),
+        ("-1", "-1 items"),
+        ("1234567", "1,234,567 items"),
+        ("-1234.50", "-1,234.50 items"),
+    ] {
+        assert_eq!(fp.render(&n).unwrap(), expected, "{n}");
+    }
+    assert_eq!(fp.render(&"abc"), Err(Error::NoData("n|thousands".into())));
+
+    let fp = filters(fmap())
+        .locale(Locale::fr)
+        .to_format_pieces("{n|thousands|plural:un:plusieurs}")
+        .unwrap();
+    // The output of `thousands` is no longer a plain number
+    assert!(fp.render(&"1234").is_err());
+    let fp = filters(fmap())
+        .locale(Locale::de)
+        .to_format_pieces("{n|thousands}")
+        .unwrap();
+    assert_eq!(fp.render(&"1234.5").unwrap(), "1.234,5");
+}
+
+#[test]
+fn unknown_filters() {
+    for key in ["n|nope", "n|plural:a", "n|thousands:x", "n|", "m|thousands"] {
+        let key_name = if key.starts_with('m') {
+            "m|thousands"
+        } else {
+            key
+        };
+        assert_eq!(
+            filters(fmap())
+                .to_format_pieces(format!("{{{key}}}"))
+                .map(|_| ()),
+            Err(Error::UnknownKey(key_name.into())),
+            "{key}"
+        );
+    }
+    let fp = filters(fmap()).to_format_pieces("{a|b}").unwrap();
+    assert_eq!(fp.render(&"").unwrap(), "literal");
+}
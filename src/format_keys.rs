@@ -0,0 +1,44 @@
+use crate::FormatMap;
+
+/// A type which knows how to build its own `FormatMap<Self>`.
+///
+/// With the `derive` feature, this can be derived for structs with `#[derive(FormatKeys)]`, which
+/// adds a key for each field that formats it using `Display`. Fields can be customized with
+/// `#[funcfmt(rename = "name")]`, `#[funcfmt(skip)]`, or `#[funcfmt(with = path)]`, where `path`
+/// is a function taking a reference to the field and returning an `Option<String>`.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "derive")] {
+/// use funcfmt::{FormatKeys, Render, ToFormatPieces};
+///
+/// fn initial(name: &String) -> Option<String> {
+///     name.chars().next().map(String::from)
+/// }
+///
+/// #[derive(FormatKeys)]
+/// struct Track {
+///     title: String,
+///     #[funcfmt(rename = "no")]
+///     number: u32,
+///     #[funcfmt(with = initial)]
+///     artist: String,
+///     #[funcfmt(skip)]
+///     _private: (),
+/// }
+///
+/// let fp = Track::format_map().to_format_pieces("{no}. {title} ({artist})").unwrap();
+/// let track = Track {
+///     title: "Blue in Green".to_string(),
+///     number: 3,
+///     artist: "Miles Davis".to_string(),
+///     _private: (),
+/// };
+/// assert_eq!(fp.render(&track), Ok("3. Blue in Green (M)".to_string()));
+/// # }
+/// ```
+pub trait FormatKeys: Sized {
+    /// Build a `FormatMap<Self>` with a callback for each key this type provides.
+    fn format_map() -> FormatMap<Self>;
+}
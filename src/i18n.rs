@@ -0,0 +1,215 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{
+    Error, FnvHashMap, FormatMap, FormatPieces, FormatterCallback, KeyLookup, KeyString, Render,
+    ToFormatPieces,
+};
+
+/// A message in several locales, each processed once, with the one to render selected by locale
+/// at render time.
+///
+/// Locales are tags like `de-AT`. When rendering for a locale with no template of its own, its
+/// fallback chain is tried in order: each parent formed by removing the last subtag, and then the
+/// default locale. So `de-AT` falls back to `de`, and then to the default, like `en`. Locales are
+/// compared case-insensitively, and `_` is treated as `-`, so `de_at` is the same as `de-AT`.
+///
+/// Each locale can also have its own `FormatMap<T>`, for callbacks whose output depends on the
+/// locale, such as for dates or numbers. Keys are looked up along the same fallback chain, so a
+/// locale's map only needs the keys which differ from its parents.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, FormatMap, LocalizedTemplates};
+///
+/// let fmap: FormatMap<u32> = fm!{"n" => |n: &u32| Some(n.to_string())};
+/// let mut msgs = LocalizedTemplates::new("en", fmap);
+/// msgs.add("en", "{n} files").unwrap();
+/// msgs.add("de", "{n} Dateien").unwrap();
+/// msgs.add_map("de-CH", fm!{"n" => |n: &u32| Some(format!("{n}.00"))}).unwrap();
+///
+/// assert_eq!(msgs.render("de-AT", &3), Ok("3 Dateien".to_string()));
+/// assert_eq!(msgs.render("de-CH", &3), Ok("3.00 Dateien".to_string()));
+/// assert_eq!(msgs.render("fr", &3), Ok("3 files".to_string()));
+/// ```
+pub struct LocalizedTemplates<T: ?Sized> {
+    default_locale: KeyString,
+    maps: FnvHashMap<KeyString, FormatMap<T>>,
+    sources: FnvHashMap<KeyString, String>,
+    /// The processed template for each locale with a template or a map, along with the locale
+    /// whose template it was processed from.
+    templates: FnvHashMap<KeyString, (KeyString, FormatPieces<T>)>,
+}
+
+/// Looks keys up in the maps for each locale in a fallback chain, in order.
+struct ChainLookup<'a, T: ?Sized> {
+    maps: Vec<&'a FormatMap<T>>,
+}
+
+impl<T: ?Sized> KeyLookup<T> for ChainLookup<'_, T> {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.maps.iter().find_map(|map| map.lookup(key))
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.maps.iter().find_map(|map| map.lookup_ignore_case(key))
+    }
+}
+
+/// The canonical form of a locale tag, used as a key internally.
+fn normalize(locale: &str) -> KeyString {
+    locale
+        .chars()
+        .map(|c| {
+            if c == '_' {
+                '-'
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+impl<T: ?Sized> LocalizedTemplates<T> {
+    /// Create an empty set of templates, where `default_locale` is the last resort for every
+    /// fallback chain, and `map` is the `FormatMap<T>` for that locale.
+    pub fn new(default_locale: &str, map: FormatMap<T>) -> Self {
+        let default_locale = normalize(default_locale);
+        let mut maps = FnvHashMap::default();
+        maps.insert(default_locale.clone(), map);
+        Self {
+            default_locale,
+            maps,
+            sources: FnvHashMap::default(),
+            templates: FnvHashMap::default(),
+        }
+    }
+
+    /// The default locale, in normalized form.
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    /// The locales tried in order when rendering for `locale`, in normalized form.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{FormatMap, LocalizedTemplates};
+    ///
+    /// let msgs = LocalizedTemplates::<()>::new("en", FormatMap::default());
+    /// assert_eq!(msgs.fallback_chain("zh_Hant_TW"), ["zh-hant-tw", "zh-hant", "zh", "en"]);
+    /// ```
+    pub fn fallback_chain(&self, locale: &str) -> Vec<KeyString> {
+        let mut chain = Vec::new();
+        for start in [normalize(locale), self.default_locale.clone()] {
+            let mut cur = start.as_str();
+            loop {
+                if !chain.iter().any(|l: &KeyString| l == cur) {
+                    chain.push(cur.into());
+                }
+                match cur.rfind('-') {
+                    Some(idx) => cur = &cur[..idx],
+                    None => break,
+                }
+            }
+        }
+        chain
+    }
+
+    /// Add the template for `locale`, replacing any existing one, and process it with the maps
+    /// along the locale's fallback chain.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `ToFormatPieces::to_format_pieces`. Nothing is changed on error.
+    pub fn add<S: Into<String>>(&mut self, locale: &str, tmpl: S) -> Result<(), Error> {
+        let locale = normalize(locale);
+        let old = self.sources.insert(locale.clone(), tmpl.into());
+        self.rebuild().map_err(|err| {
+            match old {
+                Some(old) => self.sources.insert(locale, old),
+                None => self.sources.remove(&locale),
+            };
+            err
+        })
+    }
+
+    /// Set the `FormatMap<T>` for `locale`, replacing any existing one. Templates are processed
+    /// again to pick up its callbacks, including for `locale` itself if it only has a template
+    /// through its fallback chain.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `ToFormatPieces::to_format_pieces`, if processing a template again fails.
+    /// Nothing is changed on error.
+    pub fn add_map(&mut self, locale: &str, map: FormatMap<T>) -> Result<(), Error> {
+        let locale = normalize(locale);
+        let old = self.maps.insert(locale.clone(), map);
+        self.rebuild().map_err(|err| {
+            match old {
+                Some(old) => self.maps.insert(locale, old),
+                None => self.maps.remove(&locale),
+            };
+            err
+        })
+    }
+
+    /// Process the template for every locale with a template or a map of its own, using the
+    /// nearest template and all maps along the locale's fallback chain. `templates` is only
+    /// replaced if all of them succeed.
+    fn rebuild(&mut self) -> Result<(), Error> {
+        let mut templates = FnvHashMap::default();
+        for locale in self.maps.keys().chain(self.sources.keys()) {
+            if templates.contains_key(locale) {
+                continue;
+            }
+            let chain = self.fallback_chain(locale);
+            let source = chain.iter().find_map(|l| Some((l, self.sources.get(l)?)));
+            let (source_locale, tmpl) = match source {
+                Some(source) => source,
+                None => continue,
+            };
+            let lookup = ChainLookup {
+                maps: chain.iter().filter_map(|l| self.maps.get(l)).collect(),
+            };
+            let pieces = lookup.to_format_pieces(tmpl)?;
+            templates.insert(locale.clone(), (source_locale.clone(), pieces));
+        }
+        self.templates = templates;
+        Ok(())
+    }
+
+    /// The template used when rendering for `locale`, along with the locale whose template it was
+    /// processed from.
+    pub fn get(&self, locale: &str) -> Option<(&str, &FormatPieces<T>)> {
+        self.fallback_chain(locale).iter().find_map(|l| {
+            let (source, pieces) = self.templates.get(l)?;
+            Some((source.as_str(), pieces))
+        })
+    }
+
+    /// Render the template for `locale` with `data`.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::UnknownLocale` if no locale in the fallback chain has a template
+    /// - Any error from `Render::render`
+    pub fn render(&self, locale: &str, data: &T) -> Result<String, Error> {
+        let (_, pieces) = self
+            .get(locale)
+            .ok_or_else(|| Error::UnknownLocale(normalize(locale)))?;
+        pieces.render(data)
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for LocalizedTemplates<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalizedTemplates")
+            .field("default_locale", &self.default_locale)
+            .field("templates", &self.sources)
+            .finish()
+    }
+}
@@ -0,0 +1,55 @@
+use super::*;
+
+fn messages() -> LocalizedTemplates<u32> {
+    let fmap: FormatMap<u32> = fm! {
+        "n" => |n: &u32| Some(n.to_string()),
+        "unit" => |n: &u32| Some(if *n == 1 { "file" } else { "files" }.to_string()),
+    };
+    let mut msgs = LocalizedTemplates::new("en-US", fmap);
+    msgs.add("en", "{n} {unit}").unwrap();
+    msgs.add("de", "{n} {unit}").unwrap();
+    msgs.add("pt_BR", "{n} arquivos").unwrap();
+    msgs
+}
+
+#[test]
+fn fallback_chain_selects_template() {
+    let msgs = messages();
+    assert_eq!(msgs.fallback_chain("de-AT"), ["de-at", "de", "en-us", "en"]);
+    assert_eq!(msgs.render("EN-gb", &1), Ok("1 file".to_string()));
+    assert_eq!(msgs.render("pt-br", &2), Ok("2 arquivos".to_string()));
+    assert_eq!(msgs.get("pt").map(|(l, _)| l), Some("en"));
+    assert_eq!(msgs.get("de_AT").map(|(l, _)| l), Some("de"));
+
+    let empty = LocalizedTemplates::new("en", FormatMap::<u32>::default());
+    assert_eq!(
+        empty.render("de_AT", &1),
+        Err(Error::UnknownLocale("de-at".into()))
+    );
+}
+
+#[test]
+fn locale_maps_override_along_chain() {
+    let mut msgs = messages();
+
+    // Templates already added pick up the new map
+    msgs.add_map("de", fm! {"unit" => |_: &u32| Some("Dateien".to_string())})
+        .unwrap();
+    assert_eq!(msgs.render("de-CH", &3), Ok("3 Dateien".to_string()));
+    assert_eq!(msgs.render("en", &3), Ok("3 files".to_string()));
+
+    // Keys only in a locale's map are unknown elsewhere
+    msgs.add_map("pt", fm! {"x" => |_: &u32| None}).unwrap();
+    assert_eq!(msgs.add("en", "{x}"), Err(Error::UnknownKey("x".into())));
+    msgs.add("pt", "{x?:sem dados}").unwrap();
+    assert_eq!(msgs.render("pt-PT", &0), Ok("sem dados".to_string()));
+
+    // A map which would break an existing template is rejected
+    msgs.add("pt", "{n}").unwrap();
+    let before = msgs.render("de", &1);
+    assert_eq!(
+        msgs.add_map("en-US", FormatMap::default()),
+        Err(Error::UnknownKey("n".into()))
+    );
+    assert_eq!(msgs.render("de", &1), before);
+}
@@ -0,0 +1,56 @@
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use serde_json::Value;
+
+use crate::{FormatterCallback, KeyLookup};
+
+/// A `KeyLookup<serde_json::Value>` which resolves every key as a path into the JSON data, so that
+/// arbitrary structured data can be formatted without writing a callback for each field.
+///
+/// Keys are split on `.`, and each segment indexes into an object by name, or into an array by
+/// position. The empty key `{}` refers to the entire value. Strings are output without quotes,
+/// other scalars as they would appear in JSON, and arrays and objects as compact JSON. Paths which
+/// don't exist, or which lead to `null`, produce no data.
+///
+/// Since any key is a valid path, templates never fail with `Error::UnknownKey`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{JsonLookup, Render, ToFormatPieces};
+/// use serde_json::json;
+///
+/// let fp = JsonLookup.to_format_pieces("{user.name} bought {items.0.id}").unwrap();
+/// let data = json!({"user": {"name": "Alice"}, "items": [{"id": 42}]});
+/// assert_eq!(fp.render(&data), Ok("Alice bought 42".to_string()));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JsonLookup;
+
+/// Follow the path `segments` from `value`.
+fn resolve<'a>(mut value: &'a Value, segments: &[String]) -> Option<&'a Value> {
+    for seg in segments {
+        value = match value {
+            Value::Object(map) => map.get(seg)?,
+            Value::Array(items) => items.get(seg.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+impl KeyLookup<Value> for JsonLookup {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<Value>> {
+        let segments: Vec<String> = if key.is_empty() {
+            Vec::new()
+        } else {
+            key.split('.').map(String::from).collect()
+        };
+        Some(Arc::new(move |data| match resolve(data, &segments)? {
+            Value::Null => None,
+            Value::String(s) => Some(s.clone()),
+            other => Some(other.to_string()),
+        }))
+    }
+}
@@ -0,0 +1,33 @@
+use super::*;
+use serde_json::json;
+
+#[test]
+fn json_paths() {
+    let data = json!({
+        "s": "str",
+        "n": 1.5,
+        "b": true,
+        "null": null,
+        "arr": [1, {"x": "y"}],
+        "obj": {"k": [2]},
+    });
+    let fp = JsonLookup
+        .to_format_pieces("{s}|{n}|{b}|{arr.1.x}|{arr}|{obj}|{obj.k.0}")
+        .unwrap();
+    assert_eq!(
+        fp.render(&data),
+        Ok(r#"str|1.5|true|y|[1,{"x":"y"}]|{"k":[2]}|2"#.to_string())
+    );
+
+    let fp = JsonLookup.to_format_pieces("{}").unwrap();
+    assert_eq!(fp.render(&json!("root")), Ok("root".to_string()));
+}
+
+#[test]
+fn json_missing_is_no_data() {
+    let data = json!({"null": null, "arr": [1], "s": "x"});
+    for key in ["null", "missing", "arr.1", "arr.x", "s.0"] {
+        let fp = JsonLookup.to_format_pieces(format!("{{{key}}}")).unwrap();
+        assert_eq!(fp.render(&data), Err(Error::NoData(key.into())), "{key}");
+    }
+}
@@ -1,75 +1,669 @@
-use fnv::FnvHashMap;
-use smallvec::SmallVec;
-use smartstring::{LazyCompact, SmartString};
-use std::fmt;
-use std::sync::Arc;
-use thiserror::Error;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+// Allow code generated by funcfmt-derive, which refers to `::funcfmt`, to be tested in this crate
+#[cfg(all(test, any(feature = "derive", feature = "macros")))]
+extern crate self as funcfmt;
+
+#[cfg(not(any(feature = "std", feature = "hashbrown")))]
+compile_error!("funcfmt requires either the `std` or the `hashbrown` feature for its hash map");
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Range;
+
+mod bytes;
+#[cfg(feature = "std")]
+mod cache;
+mod cached;
+mod callbacks;
+mod compiled;
+mod context;
+mod describe;
+mod edit;
+mod escaper;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "num-format")]
+mod filters;
+mod format_keys;
+mod i18n;
+#[cfg(feature = "json")]
+mod json;
+mod lookup;
+mod options;
+mod parse;
+#[cfg(feature = "providers")]
+pub mod providers;
+mod render;
+mod state;
+mod stats;
+mod template_set;
+mod value;
+
+use parse::tokenize;
+
+pub use bytes::{BytesCallback, BytesFormatter, BytesMap, RenderBytes};
+#[cfg(feature = "std")]
+pub use cache::TemplateCache;
+pub use cached::CachedRenderer;
+pub use callbacks::{ArgCallback, Callback, CallbackMap};
+pub use compiled::CompiledTemplate;
+pub use context::{namespace, prefix_handler, Namespace, PrefixHandler};
+pub use describe::{DescribeKeys, DescribedMap, KeyInfo};
+pub use edit::{concat, EditPieces};
+pub use escaper::{Escaper, FilenameEscaper, HtmlEscaper, ShellEscaper};
+#[cfg(feature = "num-format")]
+pub use filters::{filters, Filters};
+pub use format_keys::FormatKeys;
+/// Parse a template at compile time, and expand to code which resolves its keys against a map.
+///
+/// `template!("...", map)` is equivalent to `map.to_format_pieces("...")`, returning a
+/// `Result<FormatPieces<T>, Error>`, except that imbalanced brackets are a compile error, and no
+/// parsing happens at runtime.
+///
+/// If the set of keys is known at compile time, pass it as a `&[&str]` constant with
+/// `template!("...", map, keys = KEYS)` to also make any unknown keys a compile error. Since the
+/// map itself is only available at runtime, it's still checked when the macro is evaluated. For
+/// conditionals like `{key?then:else}`, the condition key and any keys in either branch are
+/// checked.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "macros")] {
+/// use funcfmt::{fm, template, Render};
+///
+/// const KEYS: &[&str] = &["foo", "bar"];
+///
+/// let fmap = fm!{"foo" => |data: &String| Some(data.clone()), "bar" => |_| None};
+/// let fp = template!("{foo} - {{foo}}", fmap, keys = KEYS).unwrap();
+/// assert_eq!(fp.render(&String::from("x")), Ok("x - {foo}".to_string()));
+/// # }
+/// ```
+#[cfg(feature = "macros")]
+pub use funcfmt_derive::template;
+#[cfg(feature = "derive")]
+pub use funcfmt_derive::FormatKeys;
+pub use i18n::LocalizedTemplates;
+#[cfg(feature = "json")]
+pub use json::JsonLookup;
+pub use lookup::{keys_eq_ignore_case, KeyLookup};
+pub use options::{
+    CompileOptions, LengthUnit, LineEnding, OutputTransformer, RenderOptions, TrailingNewline,
+    Truncation,
+};
+pub use parse::{escape_key, parse_template, parse_template_with, TemplateToken, TEMPLATE_GRAMMAR};
+#[cfg(feature = "unicode-width")]
+pub use render::RenderedWidths;
+pub use render::{PieceOutput, Render, RenderDisplay, RenderError, Walk};
+pub use state::{stateful, SharedState, WithState};
+pub use stats::{KeyStats, RenderStats};
+pub use template_set::TemplateSet;
+pub use value::{Value, ValueCallback, ValueMap};
+
+/// The hash map used internally, and for `FormatMap<T>`: the standard library's when the `std`
+/// feature is enabled, and `hashbrown`'s otherwise.
+#[cfg(feature = "std")]
+pub(crate) type FnvHashMap<K, V> = fnv::FnvHashMap<K, V>;
+#[cfg(not(feature = "std"))]
+pub(crate) type FnvHashMap<K, V> = hashbrown::HashMap<K, V, fnv::FnvBuildHasher>;
+
+#[doc(hidden)]
+pub mod __private {
+    use crate::{to_piece, CompileOptions, Error, FormatPieces, KeyLookup, TemplateToken};
+
+    pub use alloc::borrow::Cow;
+    pub use alloc::string::ToString;
+    pub use alloc::sync::Arc;
+
+    /// Resolve tokens which were parsed at compile time by `template!`.
+    pub fn from_tokens<T: ?Sized, L: KeyLookup<T> + ?Sized>(
+        map: &L,
+        tokens: &[TemplateToken<'_>],
+    ) -> Result<FormatPieces<T>, Error> {
+        let opts = CompileOptions::default();
+        tokens
+            .iter()
+            .map(|t| to_piece(map, t.clone(), &opts))
+            .collect()
+    }
+
+    const fn str_eq(a: &str, b: &str) -> bool {
+        let (a, b) = (a.as_bytes(), b.as_bytes());
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < a.len() {
+            if a[i] != b[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    /// Fail const evaluation if any key in `used` is not in `known`.
+    pub const fn assert_keys_known(known: &[&str], used: &[&str]) {
+        let mut i = 0;
+        while i < used.len() {
+            let mut j = 0;
+            while j < known.len() && !str_eq(used[i], known[j]) {
+                j += 1;
+            }
+            if j == known.len() {
+                panic!("template uses a key which is not in the list of known keys");
+            }
+            i += 1;
+        }
+    }
+}
 
 /// An error produced during formatting.
-#[derive(Error, Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     /// A key was requested, but it has no entry in the provided `FormatMap<T>`. Stores the key
     /// name which was unknown.
-    #[error("unknown key '{0}'")]
-    UnknownKey(SmartString<LazyCompact>),
+    UnknownKey(KeyString),
 
     /// No data available for a callback. Stores the key name which had no data available, i.e.,
     /// the callback returned `None`.
-    #[error("no data for key '{0}'")]
-    NoData(SmartString<LazyCompact>),
+    NoData(KeyString),
 
     /// The template provided had imbalanced brackets. If you want to escape { or }, use {{ or }}
     /// respectively.
-    #[error("imbalanced brackets in template")]
     ImbalancedBrackets,
 
+    /// A template was requested from a `TemplateSet`, but no template with that name has been
+    /// added. Stores the template name which was unknown.
+    UnknownTemplate(KeyString),
+
+    /// Templates in a `TemplateSet` refer to each other in a cycle. Stores the name of the
+    /// template at which the cycle was detected.
+    TemplateCycle(KeyString),
+
+    /// A `{%...%}` tag in a `TemplateSet` template was unknown, malformed, or unbalanced.
+    InvalidTag,
+
+    /// No locale in the fallback chain of a `LocalizedTemplates` has a template. Stores the locale
+    /// which was requested.
+    UnknownLocale(KeyString),
+
+    /// A key in the template was empty, like `{}`. Only reported with `CompileOptions::strict`.
+    EmptyKey,
+
+    /// A key name had leading or trailing whitespace, like `{ foo}`. Stores the key name. Only
+    /// reported with `CompileOptions::strict`.
+    KeyWhitespace(KeyString),
+
+    /// A key name contained a backslash which doesn't escape anything. Stores the key name as
+    /// written. Only reported with `CompileOptions::strict`.
+    StrayEscape(KeyString),
+
+    /// A callback panicked during rendering, and `RenderOptions::catch_panics` was enabled. Stores
+    /// the key name whose callback panicked.
+    CallbackPanicked(KeyString),
+
+    /// Rendering was cancelled before completion, either because the cancellation flag was set or
+    /// because the deadline passed. See `RenderOptions::cancel_flag` and
+    /// `RenderOptions::deadline`.
+    Cancelled,
+
+    /// The rendered output was longer than allowed by `RenderOptions::max_length`, and
+    /// `Truncation::Error` was in use. Stores the length of the output, in the configured unit.
+    TooLong(usize),
+
     /// An integer overflowed or underflowed internally.
-    #[error("integer overflow/underflow")]
     Overflow,
 
     /// An error occurred during writing the result of the closure to the eventual output `String`.
     /// Stores the encapsulated error.
-    #[error("std::fmt::Write error")]
-    Write(#[from] std::fmt::Error),
+    Write(fmt::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownKey(key) => write!(f, "unknown key '{key}'"),
+            Self::NoData(key) => write!(f, "no data for key '{key}'"),
+            Self::ImbalancedBrackets => f.write_str("imbalanced brackets in template"),
+            Self::UnknownTemplate(name) => write!(f, "unknown template '{name}'"),
+            Self::TemplateCycle(name) => write!(f, "template cycle at '{name}'"),
+            Self::InvalidTag => f.write_str("invalid template tag"),
+            Self::UnknownLocale(locale) => write!(f, "no template for locale '{locale}'"),
+            Self::EmptyKey => f.write_str("empty key in template"),
+            Self::KeyWhitespace(key) => write!(f, "whitespace around key '{key}'"),
+            Self::StrayEscape(key) => write!(f, "stray backslash in key '{key}'"),
+            Self::CallbackPanicked(key) => write!(f, "callback for key '{key}' panicked"),
+            Self::Cancelled => f.write_str("rendering was cancelled"),
+            Self::TooLong(len) => write!(f, "rendered output too long ({len})"),
+            Self::Overflow => f.write_str("integer overflow/underflow"),
+            Self::Write(_) => f.write_str("std::fmt::Write error"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Write(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<fmt::Error> for Error {
+    fn from(err: fmt::Error) -> Self {
+        Self::Write(err)
+    }
+}
+
+/// The string type used for key names, and for verbatim text in `FormatPiece<T>`.
+///
+/// This is `smartstring::SmartString<LazyCompact>` with the `smartstring` feature (the default),
+/// which stores short strings inline without allocating, and `String` otherwise.
+#[cfg(feature = "smartstring")]
+pub type KeyString = smartstring::SmartString<smartstring::LazyCompact>;
+/// The string type used for key names, and for verbatim text in `FormatPiece<T>`.
+///
+/// This is `smartstring::SmartString<LazyCompact>` with the `smartstring` feature (the default),
+/// which stores short strings inline without allocating, and `String` otherwise.
+#[cfg(not(feature = "smartstring"))]
+pub type KeyString = String;
+
 /// A callback to be provided with data during rendering.
 pub type FormatterCallback<T> = Arc<dyn Fn(&T) -> Option<String> + Send + Sync>;
 
+/// Adapt a fallible callback returning a `Result` into one usable in a `FormatMap`, such as in an
+/// entry of `fm!`. An error means there's no data, like `None`, so it can be handled with a
+/// conditional like `{key?...:fallback}`. The error itself is dropped.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fallible, fm, FormatMap, Render, ToFormatPieces};
+///
+/// let fmap: FormatMap<String> = fm!{
+///     "raw" => |data: &String| Some(data.clone()),
+///     "num" => fallible(|data: &String| data.parse::<u8>().map(|n| n.to_string())),
+/// };
+///
+/// let fp = fmap.to_format_pieces("{num?{num}:not a number: {raw}}").unwrap();
+/// assert_eq!(fp.render(&String::from("042")), Ok("42".to_string()));
+/// assert_eq!(fp.render(&String::from("x")), Ok("not a number: x".to_string()));
+/// ```
+pub fn fallible<T, V, E, F>(f: F) -> impl Fn(&T) -> Option<String> + Send + Sync + 'static
+where
+    T: ?Sized,
+    V: Into<String>,
+    F: Fn(&T) -> Result<V, E> + Send + Sync + 'static,
+{
+    move |data| f(data).ok().map(Into::into)
+}
+
 /// A mapping of keys to callback functions.
-pub type FormatMap<T> = FnvHashMap<SmartString<LazyCompact>, FormatterCallback<T>>;
+///
+/// This is the default `KeyLookup<T>` implementation. Other map types can be used to process
+/// templates by implementing `KeyLookup<T>` for them.
+///
+/// `T` can be unsized, so one map and template can render several types through a trait object,
+/// like `FormatMap<dyn Media>`. For types with no trait in common, use `FormatMap<dyn Any>` and
+/// downcast in the callbacks.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, FormatMap, Render, ToFormatPieces};
+///
+/// trait Media {
+///     fn name(&self) -> &str;
+///     fn duration(&self) -> Option<u32>;
+/// }
+///
+/// struct Image(&'static str);
+/// struct Video(&'static str, u32);
+///
+/// impl Media for Image {
+///     fn name(&self) -> &str { self.0 }
+///     fn duration(&self) -> Option<u32> { None }
+/// }
+///
+/// impl Media for Video {
+///     fn name(&self) -> &str { self.0 }
+///     fn duration(&self) -> Option<u32> { Some(self.1) }
+/// }
+///
+/// // `FormatMap<dyn Media>` is short for `FormatMap<dyn Media + 'static>`, which callbacks need
+/// // to spell out
+/// let fmap: FormatMap<dyn Media> = fm!{
+///     "name" => |m: &(dyn Media + 'static)| Some(m.name().to_string()),
+///     "duration" => |m: &(dyn Media + 'static)| m.duration().map(|d| d.to_string()),
+/// };
+/// let fp = fmap.to_format_pieces("{name}{duration? ({duration}s)}").unwrap();
+///
+/// let items: Vec<Box<dyn Media>> = vec![Box::new(Image("a.jpg")), Box::new(Video("b.mp4", 30))];
+/// let out: Vec<_> = items.iter().map(|m| fp.render(&**m).unwrap()).collect();
+/// assert_eq!(out, ["a.jpg", "b.mp4 (30s)"]);
+/// ```
+pub type FormatMap<T> = FnvHashMap<KeyString, FormatterCallback<T>>;
 
+/// Like `FormatMap<T>`, but iterating in insertion order, with the `indexmap` feature.
+///
+/// Iterating a `FormatMap<T>` visits keys in an arbitrary order which can change between runs.
+/// Use this instead when that order is visible, such as when listing the available keys in help
+/// output. It can be built with `ordered_fm!`, and otherwise works anywhere a `FormatMap<T>` does.
+#[cfg(feature = "indexmap")]
+pub type OrderedFormatMap<T> =
+    indexmap::IndexMap<KeyString, FormatterCallback<T>, fnv::FnvBuildHasher>;
+
+/// A container of either plain `Char`s or function callbacks to be called later in `render`.
+///
+/// This is a `smallvec::SmallVec` with the `smallvec` feature (the default), and `Vec` otherwise.
+///
+/// It is always `Send`, `Sync`, and `Clone`, whatever `T` is. Cloning shares the callbacks rather
+/// than copying them, so one processed template can cheaply be handed to several threads.
+#[cfg(feature = "smallvec")]
+pub type FormatPieces<T> = smallvec::SmallVec<[FormatPiece<T>; 256]>; // <=64b per FormatPiece<T>, <=16kb total
 /// A container of either plain `Char`s or function callbacks to be called later in `render`.
-pub type FormatPieces<T> = SmallVec<[FormatPiece<T>; 256]>; // ~40b per FormatPiece<T>, ~10kb total
+///
+/// This is a `smallvec::SmallVec` with the `smallvec` feature (the default), and `Vec` otherwise.
+///
+/// It is always `Send`, `Sync`, and `Clone`, whatever `T` is. Cloning shares the callbacks rather
+/// than copying them, so one processed template can cheaply be handed to several threads.
+#[cfg(not(feature = "smallvec"))]
+pub type FormatPieces<T> = Vec<FormatPiece<T>>;
 
 /// A container around the callback that also contains the name of the key.
-pub struct Formatter<T> {
-    pub key: SmartString<LazyCompact>,
+pub struct Formatter<T: ?Sized> {
+    pub key: KeyString,
     pub cb: FormatterCallback<T>,
 }
 
-impl<T> PartialEq for Formatter<T> {
+impl<T: ?Sized> Formatter<T> {
+    /// Create a new formatter calling `cb` for `key`.
+    pub fn new<K: Into<KeyString>>(key: K, cb: FormatterCallback<T>) -> Self {
+        Self {
+            key: key.into(),
+            cb,
+        }
+    }
+}
+
+// Derived Clone would needlessly require `T: Clone`
+impl<T: ?Sized> Clone for Formatter<T> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            cb: Arc::clone(&self.cb),
+        }
+    }
+}
+
+/// Formatters are compared by key only. Use `SameBindings` to also compare their callbacks.
+impl<T: ?Sized> PartialEq for Formatter<T> {
     fn eq(&self, other: &Self) -> bool {
         self.key == other.key
     }
 }
-impl<T> Eq for Formatter<T> {}
+impl<T: ?Sized> Eq for Formatter<T> {}
 
-impl<T> fmt::Debug for Formatter<T> {
+impl<T: ?Sized> Hash for Formatter<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Formatter<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Formatter(key: {})", self.key)
     }
 }
 
+/// A conditional like `{key?then:else}`, which renders one of two branches depending on whether
+/// the callback for its condition key produces data.
+pub struct Conditional<T: ?Sized> {
+    key: KeyString,
+    condition: Formatter<T>,
+    then: Vec<FormatPiece<T>>,
+    otherwise: Vec<FormatPiece<T>>,
+}
+
+impl<T: ?Sized> Conditional<T> {
+    /// The whole key as written in the template, including the branches, like `foo?a:b`.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The formatter for the condition key, whose callback decides which branch is rendered.
+    pub fn condition(&self) -> &Formatter<T> {
+        &self.condition
+    }
+
+    /// The pieces rendered if the condition produces data.
+    pub fn then(&self) -> &[FormatPiece<T>] {
+        &self.then
+    }
+
+    /// The pieces rendered if the condition produces no data.
+    pub fn otherwise(&self) -> &[FormatPiece<T>] {
+        &self.otherwise
+    }
+
+    /// The branch to render, given whether the condition produced data.
+    pub(crate) fn branch(&self, taken: bool) -> &[FormatPiece<T>] {
+        if taken {
+            &self.then
+        } else {
+            &self.otherwise
+        }
+    }
+}
+
+// Derived Clone would needlessly require `T: Clone`
+impl<T: ?Sized> Clone for Conditional<T> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            condition: self.condition.clone(),
+            then: self.then.clone(),
+            otherwise: self.otherwise.clone(),
+        }
+    }
+}
+
+/// Conditionals are compared by key only, which includes their branches as written. Use
+/// `SameBindings` to also compare their callbacks.
+impl<T: ?Sized> PartialEq for Conditional<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+impl<T: ?Sized> Eq for Conditional<T> {}
+
+impl<T: ?Sized> Hash for Conditional<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Conditional<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Conditional(key: {})", self.key)
+    }
+}
+
 /// Either a plain `Char`, or a function call back to be called later in `render`.
-#[derive(PartialEq, Eq, Debug)]
-pub enum FormatPiece<T> {
-    Verbatim(SmartString<LazyCompact>),
+pub enum FormatPiece<T: ?Sized> {
+    Verbatim(KeyString),
     Formatter(Formatter<T>),
+    /// A conditional like `{key?then:else}`.
+    Conditional(Box<Conditional<T>>),
+    /// A formatter which also has a callback returning raw bytes, for `RenderBytes`.
+    Bytes(BytesFormatter<T>),
+}
+
+impl<T: ?Sized> FormatPiece<T> {
+    /// The key of this piece as written in the template, or `None` for verbatim text.
+    pub(crate) fn key(&self) -> Option<&KeyString> {
+        match self {
+            Self::Verbatim(_) => None,
+            Self::Formatter(f) => Some(&f.key),
+            Self::Conditional(c) => Some(&c.key),
+            Self::Bytes(b) => Some(&b.formatter().key),
+        }
+    }
+
+    /// The formatter of this piece, if it renders the output of a single callback.
+    pub(crate) fn formatter(&self) -> Option<&Formatter<T>> {
+        match self {
+            Self::Formatter(f) => Some(f),
+            Self::Bytes(b) => Some(b.formatter()),
+            Self::Verbatim(_) | Self::Conditional(_) => None,
+        }
+    }
+}
+
+// Derived Debug would needlessly require `T: Debug`
+impl<T: ?Sized> fmt::Debug for FormatPiece<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Verbatim(text) => f.debug_tuple("Verbatim").field(text).finish(),
+            Self::Formatter(fmt) => f.debug_tuple("Formatter").field(fmt).finish(),
+            Self::Conditional(c) => f.debug_tuple("Conditional").field(c).finish(),
+            Self::Bytes(b) => f.debug_tuple("Bytes").field(b).finish(),
+        }
+    }
+}
+
+// Derived PartialEq and Hash would needlessly require `T: PartialEq` and `T: Hash`
+impl<T: ?Sized> PartialEq for FormatPiece<T> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Verbatim(a), Self::Verbatim(b)) => a == b,
+            (Self::Formatter(a), Self::Formatter(b)) => a == b,
+            (Self::Conditional(a), Self::Conditional(b)) => a == b,
+            (Self::Bytes(a), Self::Bytes(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+impl<T: ?Sized> Eq for FormatPiece<T> {}
+
+impl<T: ?Sized> Hash for FormatPiece<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Self::Verbatim(text) => {
+                state.write_u8(0);
+                text.hash(state);
+            }
+            Self::Formatter(f) => {
+                state.write_u8(1);
+                f.hash(state);
+            }
+            Self::Conditional(c) => {
+                state.write_u8(2);
+                c.hash(state);
+            }
+            Self::Bytes(b) => {
+                state.write_u8(3);
+                b.hash(state);
+            }
+        }
+    }
+}
+
+/// A trait for comparing processed templates strictly, including the callbacks they are bound to.
+///
+/// Callbacks can't be compared or hashed by value, so `PartialEq` and `Hash` for format pieces and
+/// `CompiledTemplate<T>` only consider their content: key names and verbatim text. Templates
+/// processed from the same text against different maps are therefore equal, which is usually what
+/// a cache keyed by template wants. `same_bindings` additionally checks that every callback is the
+/// same `Arc`, for example to tell whether a template needs `Refresh`ing.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, FormatMap, SameBindings, ToFormatPieces};
+///
+/// let a: FormatMap<String> = fm!{"foo" => |data: &String| Some(data.clone())};
+/// let b: FormatMap<String> = fm!{"foo" => |data: &String| Some(data.to_uppercase())};
+///
+/// let fp_a = a.to_format_pieces("<{foo}>").unwrap();
+/// let fp_b = b.to_format_pieces("<{foo}>").unwrap();
+/// assert_eq!(fp_a, fp_b);
+/// assert!(!fp_a.same_bindings(&fp_b));
+/// assert!(fp_a.same_bindings(&fp_a.clone()));
+/// ```
+pub trait SameBindings {
+    /// Whether `self` and `other` are equal, and also call exactly the same callbacks, as compared
+    /// by `Arc::ptr_eq`.
+    fn same_bindings(&self, other: &Self) -> bool;
+}
+
+impl<T: ?Sized> SameBindings for Formatter<T> {
+    fn same_bindings(&self, other: &Self) -> bool {
+        self.key == other.key && Arc::ptr_eq(&self.cb, &other.cb)
+    }
+}
+
+impl<T: ?Sized> SameBindings for Conditional<T> {
+    fn same_bindings(&self, other: &Self) -> bool {
+        self.key == other.key
+            && self.condition.same_bindings(&other.condition)
+            && self.then.same_bindings(&other.then)
+            && self.otherwise.same_bindings(&other.otherwise)
+    }
+}
+
+impl<T: ?Sized> SameBindings for FormatPiece<T> {
+    fn same_bindings(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Formatter(a), Self::Formatter(b)) => a.same_bindings(b),
+            (Self::Conditional(a), Self::Conditional(b)) => a.same_bindings(b),
+            (Self::Bytes(a), Self::Bytes(b)) => a.same_bindings(b),
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl<T: ?Sized> SameBindings for [FormatPiece<T>] {
+    fn same_bindings(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .zip(other.iter())
+                .all(|(a, b)| a.same_bindings(b))
+    }
+}
+
+impl<T: ?Sized> SameBindings for FormatPieces<T> {
+    fn same_bindings(&self, other: &Self) -> bool {
+        self[..].same_bindings(&other[..])
+    }
+}
+
+// Derived Clone would needlessly require `T: Clone`
+impl<T: ?Sized> Clone for FormatPiece<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Verbatim(text) => Self::Verbatim(text.clone()),
+            Self::Formatter(f) => Self::Formatter(f.clone()),
+            Self::Conditional(c) => Self::Conditional(c.clone()),
+            Self::Bytes(b) => Self::Bytes(b.clone()),
+        }
+    }
 }
 
 /// A trait for processing a sequence of formatters and given template into a `FormatPieces<T>`.
-pub trait ToFormatPieces<T> {
+pub trait ToFormatPieces<T: ?Sized> {
     /// Processes the given value into a `FormatPieces<T>`.
     ///
     /// # Template format
@@ -79,9 +673,19 @@ pub trait ToFormatPieces<T> {
     ///
     /// If you want to return literal "{foo}", pass `{{foo}}`.
     ///
-    /// There are no restrictions on key names, other than that they cannot contain "{" or "}".
-    /// This is not enforced at construction time, but trying to use them will fail with
-    /// `Error::ImbalancedBrackets`.
+    /// Whitespace (including newlines) in the template just before or after a key can be removed
+    /// with trim markers: `{- foo}` trims before the key, `{foo -}` trims after it, and `{- foo -}`
+    /// trims both. The marker must be separated from the key name by whitespace.
+    ///
+    /// Inside a key, `\{`, `\}`, and `\\` represent a literal `{`, `}`, and `\` respectively, so
+    /// any key name can be used. A backslash before any other character is literal. Use
+    /// `escape_key` to escape a key name for use in a template.
+    ///
+    /// A conditional `{foo?then:else}` outputs `then` if the callback for "foo" produces data, and
+    /// `else` otherwise. The `:else` part may be left out to output nothing. Each branch is itself
+    /// a template, so it may contain keys, like `{foo?<{foo}>:N/A}`. In a branch, outside of any
+    /// nested key, use `\{`, `\}`, `\:`, and `\?` for a literal `{`, `}`, `:`, and `?`. If a
+    /// key containing `?` is registered in the map, it's used as-is instead.
     ///
     /// # Example
     ///
@@ -101,137 +705,463 @@ pub trait ToFormatPieces<T> {
     /// # Errors
     ///
     /// - `Error::ImbalancedBrackets` if `tmpl` contains imbalanced brackets (use `{{` and `}}` to
-    ///    escape)
+    ///   escape)
     /// - `Error::Overflow` if internal string capacity calculation overflows
     /// - `Error::UnknownKey` if a requested key has no associated callback
     fn to_format_pieces<S: AsRef<str>>(&self, tmpl: S) -> Result<FormatPieces<T>, Error>;
+
+    /// Like `to_format_pieces`, but also returns the byte range in `tmpl` that each piece came
+    /// from, at the same index as the piece itself. For formatters, the range includes the
+    /// surrounding brackets. For verbatim text containing an escaped bracket, the range starts at
+    /// the second bracket of the escape.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, FormatMap, ToFormatPieces};
+    ///
+    /// let fmap: FormatMap<String> = fm!{"foo" => |data| Some(format!("b{data}d"))};
+    /// let tmpl = "ab{foo}e";
+    /// let (fp, spans) = fmap.to_format_pieces_spanned(tmpl).unwrap();
+    ///
+    /// assert_eq!(fp.len(), spans.len());
+    /// assert_eq!(spans, vec![0..2, 2..7, 7..8]);
+    /// assert_eq!(&tmpl[spans[1].clone()], "{foo}");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// The same as for `to_format_pieces`.
+    fn to_format_pieces_spanned<S: AsRef<str>>(
+        &self,
+        tmpl: S,
+    ) -> Result<(FormatPieces<T>, Vec<Range<usize>>), Error>;
+
+    /// Like `to_format_pieces`, but processing the template according to `opts`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, CompileOptions, FormatMap, FormatPiece, ToFormatPieces};
+    ///
+    /// let fmap: FormatMap<String> = fm!{"foo" => |data: &String| Some(data.clone())};
+    /// let opts = CompileOptions::new().case_insensitive(true);
+    /// let fp = fmap.to_format_pieces_with("{FOO}", &opts).unwrap();
+    ///
+    /// // The spelling from the template is kept
+    /// assert!(matches!(&fp[0], FormatPiece::Formatter(f) if f.key == "FOO"));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// The same as for `to_format_pieces`.
+    fn to_format_pieces_with<S: AsRef<str>>(
+        &self,
+        tmpl: S,
+        opts: &CompileOptions,
+    ) -> Result<FormatPieces<T>, Error>;
+}
+
+/// Resolve a single template token into a `FormatPiece<T>` using `map`.
+fn to_piece<T: ?Sized, L: KeyLookup<T> + ?Sized>(
+    map: &L,
+    token: TemplateToken<'_>,
+    opts: &CompileOptions,
+) -> Result<FormatPiece<T>, Error> {
+    match token {
+        TemplateToken::Verbatim { text, .. } => Ok(FormatPiece::Verbatim(text.into())),
+        TemplateToken::Key { name, .. } => match resolve(map, &name, opts) {
+            Ok(piece) => Ok(piece),
+            Err(Error::UnknownKey(key)) => match opts.unknown_key_text(&name) {
+                Some(text) => Ok(FormatPiece::Verbatim(text)),
+                None => Err(Error::UnknownKey(key)),
+            },
+            Err(err) => Err(err),
+        },
+    }
 }
 
-impl<T> ToFormatPieces<T> for FormatMap<T> {
+/// Create the piece for the key `name` using `map`. The key may be a conditional like
+/// `{key?then:else}`, in which case its branches are processed too.
+pub(crate) fn resolve<T: ?Sized, L: KeyLookup<T> + ?Sized>(
+    map: &L,
+    name: &str,
+    opts: &CompileOptions,
+) -> Result<FormatPiece<T>, Error> {
+    if let Some(cb) = opts.lookup(map, name) {
+        let f = Formatter::new(name, cb);
+        return Ok(match opts.lookup_bytes(map, name) {
+            Some(bytes) => FormatPiece::Bytes(BytesFormatter::new(f, bytes)),
+            None => FormatPiece::Formatter(f),
+        });
+    }
+    let cond = parse::split_conditional(name).ok_or_else(|| Error::UnknownKey(name.into()))?;
+    let cb = opts
+        .lookup(map, &cond.key)
+        .ok_or_else(|| Error::UnknownKey((&*cond.key).into()))?;
+    let mut then = Vec::new();
+    process_into(map, &cond.then, opts, &mut then)?;
+    let mut otherwise = Vec::new();
+    process_into(map, &cond.otherwise, opts, &mut otherwise)?;
+    Ok(FormatPiece::Conditional(Box::new(Conditional {
+        key: name.into(),
+        condition: Formatter::new(&*cond.key, cb),
+        then,
+        otherwise,
+    })))
+}
+
+/// Process `tmpl` using `map`, appending the pieces to `out`.
+fn process_into<T: ?Sized, L: KeyLookup<T> + ?Sized, C: Extend<FormatPiece<T>>>(
+    map: &L,
+    tmpl: &str,
+    opts: &CompileOptions,
+    out: &mut C,
+) -> Result<(), Error> {
+    tokenize(tmpl, opts, |token| {
+        out.extend(core::iter::once(to_piece(map, token, opts)?));
+        Ok(())
+    })
+}
+
+impl<T: ?Sized, L: KeyLookup<T> + ?Sized> ToFormatPieces<T> for L {
     fn to_format_pieces<S: AsRef<str>>(&self, tmpl: S) -> Result<FormatPieces<T>, Error> {
-        // Need to be a bit careful to not index inside a character boundary
+        self.to_format_pieces_with(tmpl, &CompileOptions::default())
+    }
+
+    fn to_format_pieces_spanned<S: AsRef<str>>(
+        &self,
+        tmpl: S,
+    ) -> Result<(FormatPieces<T>, Vec<Range<usize>>), Error> {
+        let tmpl = tmpl.as_ref();
+        let mut out = FormatPieces::with_capacity(tmpl.len());
+        let mut spans = Vec::with_capacity(tmpl.len());
+        tokenize(tmpl, &CompileOptions::default(), |token| {
+            spans.push(token.span());
+            out.push(to_piece(self, token, &CompileOptions::default())?);
+            Ok(())
+        })?;
+        Ok((out, spans))
+    }
+
+    fn to_format_pieces_with<S: AsRef<str>>(
+        &self,
+        tmpl: S,
+        opts: &CompileOptions,
+    ) -> Result<FormatPieces<T>, Error> {
         let tmpl = tmpl.as_ref();
-        let chars = tmpl.char_indices();
 
         // Ballpark guesses large enough to usually avoid extra allocations
         let mut out = FormatPieces::with_capacity(tmpl.len());
-        let mut start_key_idx = 0;
-        let mut pending_escape = false;
-        let mut last_pushed_idx = 0;
-
-        macro_rules! push_verb {
-            ($out:expr, $tmpl:expr, $range:expr) => {
-                // SAFETY: The range is definitely at a character boundary per .char_indices(), and
-                // ends at idx. This is about a 3.5% speedup.
-                let unpushed = unsafe { $tmpl.get_unchecked($range) };
-                $out.push(FormatPiece::Verbatim(unpushed.into()));
-            };
-        }
-
-        for (idx, cur) in chars {
-            match (cur, start_key_idx) {
-                ('{', 0) => {
-                    push_verb!(out, tmpl, last_pushed_idx..idx);
-                    start_key_idx = idx.checked_add(1).ok_or(Error::Overflow)?;
-                }
-                ('{', s) if idx.checked_sub(s).ok_or(Error::Overflow)? == 0 => {
-                    start_key_idx = 0;
-                    last_pushed_idx = idx;
-                }
-                ('{', _) => return Err(Error::ImbalancedBrackets),
-                ('}', 0) if !pending_escape => {
-                    pending_escape = true;
-                    push_verb!(out, tmpl, last_pushed_idx..idx);
-                }
-                ('}', 0) if pending_escape => {
-                    pending_escape = false;
-                    last_pushed_idx = idx;
-                }
-                ('}', s) => {
-                    // SAFETY: We are already at idx and know it is valid, and s is definitely at
-                    // a character boundary per .char_indices(). This is about a 2% speedup.
-                    let key = unsafe { tmpl.get_unchecked(s..idx) };
-                    let key = key.into();
-                    match self.get(&key) {
-                        Some(f) => {
-                            out.push(FormatPiece::Formatter(Formatter { key, cb: f.clone() }));
-                        }
-                        None => return Err(Error::UnknownKey(key)),
-                    };
-                    start_key_idx = 0;
-                    last_pushed_idx = idx.checked_add(1).ok_or(Error::Overflow)?;
-                }
-
-                _ => {
-                    if pending_escape {
-                        return Err(Error::ImbalancedBrackets);
-                    }
-                }
-            }
-        }
+        process_into(self, tmpl, opts, &mut out)?;
+        Ok(out)
+    }
+}
 
-        if last_pushed_idx < tmpl.len() {
-            push_verb!(out, tmpl, last_pushed_idx..);
-        }
+/// A summary of the changes made by `Refresh::refresh`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RefreshReport {
+    /// The keys whose callbacks were replaced, in order of first appearance in the template.
+    pub updated: Vec<KeyString>,
+}
 
-        Ok(out)
+impl RefreshReport {
+    /// Whether the refresh left everything unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.updated.is_empty()
     }
 }
 
-/// A trait for rendering format pieces into a resulting `String`, given some input data to the
-/// callbacks.
-pub trait Render<T> {
-    /// Given some data, render the given format pieces into a `String`.
+/// A trait for updating already processed format pieces when their `FormatMap<T>` changes.
+pub trait Refresh<T: ?Sized> {
+    /// Re-resolve each key against `map`, replacing only the callbacks which differ (by
+    /// `Arc::ptr_eq`) from the ones currently in use, without reparsing the template.
+    ///
+    /// Keys are matched exactly if possible, and otherwise with `KeyLookup::lookup_ignore_case`,
+    /// so pieces processed with `CompileOptions::case_insensitive` are found again. Conditionals
+    /// keep their branches as processed, with the condition and any keys in the branches
+    /// re-resolved the same way.
     ///
     /// # Example
     ///
     /// ```
-    /// use funcfmt::{FormatMap, ToFormatPieces, Render, fm};
+    /// use funcfmt::{fm, FormatterCallback, Refresh, Render, ToFormatPieces};
+    /// use std::sync::Arc;
     ///
-    /// let fmap = fm!{"foo" => |data| Some(format!("b{data}d"))};
-    /// let fp = fmap.to_format_pieces("a{foo}e").unwrap();
-    /// let data = String::from("c");
-    /// assert_eq!(fp.render(&data), Ok("abcde".to_string()));
+    /// let mut fmap = fm!{
+    ///     "foo" => |_: &String| Some("old".to_string()),
+    ///     "bar" => |_: &String| Some("bar".to_string()),
+    /// };
+    /// let mut fp = fmap.to_format_pieces("{foo} {bar}").unwrap();
+    ///
+    /// let cb: FormatterCallback<String> = Arc::new(|_| Some("new".to_string()));
+    /// fmap.insert("foo".into(), cb);
+    /// let report = fp.refresh(&fmap).unwrap();
+    ///
+    /// assert_eq!(report.updated, vec!["foo"]);
+    /// assert_eq!(fp.render(&String::new()), Ok("new bar".to_string()));
     /// ```
     ///
     /// # Errors
     ///
-    /// - `Error::NoData` if the callback returns `None`
-    /// - `Error::Overflow` if internal string capacity calculation overflows
-    /// - `Error::Write` if writing to the output `String` fails
-    fn render(&self, data: &T) -> Result<String, Error>;
-}
-
-impl<T> Render<T> for FormatPieces<T> {
-    fn render(&self, data: &T) -> Result<String, Error> {
-        // Ballpark guess large enough to usually avoid extra allocations
-        let mut out = String::with_capacity(self.len().checked_mul(16).ok_or(Error::Overflow)?);
-        for piece in self {
-            match piece {
-                FormatPiece::Verbatim(s) => out.push_str(s),
-                FormatPiece::Formatter(f) => {
-                    out.push_str(&(f.cb)(data).ok_or_else(|| Error::NoData(f.key.clone()))?);
-                }
+    /// - `Error::UnknownKey` if a key used by the template is no longer in `map`. In this case
+    ///   nothing is modified.
+    fn refresh<L: KeyLookup<T> + ?Sized>(&mut self, map: &L) -> Result<RefreshReport, Error>;
+}
+
+impl<T: ?Sized> Refresh<T> for FormatPieces<T> {
+    fn refresh<L: KeyLookup<T> + ?Sized>(&mut self, map: &L) -> Result<RefreshReport, Error> {
+        // Resolve everything first, so that failure doesn't leave a partial update
+        let mut resolved = Vec::new();
+        for piece in self.iter().filter(|piece| piece.key().is_some()) {
+            resolved.push(re_resolve(map, piece)?);
+        }
+
+        let mut report = RefreshReport::default();
+        let keyed = self.iter_mut().filter(|piece| piece.key().is_some());
+        for (piece, new) in keyed.zip(resolved) {
+            if piece.same_bindings(&new) {
+                continue;
+            }
+            *piece = new;
+            let key = piece.key().cloned().unwrap_or_default();
+            if !report.updated.contains(&key) {
+                report.updated.push(key);
             }
         }
-        Ok(out)
+        Ok(report)
+    }
+}
+
+/// Resolve `piece` against `map` again, as for `Refresh`. Verbatim text is kept as it is, which
+/// includes any unknown keys kept with `CompileOptions::keep_unknown_keys`.
+fn re_resolve<T: ?Sized, L: KeyLookup<T> + ?Sized>(
+    map: &L,
+    piece: &FormatPiece<T>,
+) -> Result<FormatPiece<T>, Error> {
+    // The key in a formatter is as written in the template, which may only have matched
+    // case-insensitively. An exact match is still preferred.
+    let ignore_case = CompileOptions::new().case_insensitive(true);
+    let branch = |pieces: &[FormatPiece<T>]| -> Result<Vec<FormatPiece<T>>, Error> {
+        pieces.iter().map(|piece| re_resolve(map, piece)).collect()
+    };
+    match piece {
+        FormatPiece::Verbatim(_) => Ok(piece.clone()),
+        FormatPiece::Formatter(f) => resolve(map, &f.key, &ignore_case),
+        FormatPiece::Bytes(b) => resolve(map, &b.formatter().key, &ignore_case),
+        FormatPiece::Conditional(c) => {
+            let key = &c.condition.key;
+            let cb = ignore_case
+                .lookup(map, key)
+                .ok_or_else(|| Error::UnknownKey(key.clone()))?;
+            Ok(FormatPiece::Conditional(Box::new(Conditional {
+                key: c.key.clone(),
+                condition: Formatter::new(key.as_str(), cb),
+                then: branch(&c.then)?,
+                otherwise: branch(&c.otherwise)?,
+            })))
+        }
     }
 }
 
+/// A trait for finding registered keys that a template never uses.
+pub trait UnusedKeys<T: ?Sized> {
+    /// List the keys registered in this map which are not referenced by `pieces`, sorted by name,
+    /// or in insertion order for `OrderedFormatMap<T>`.
+    ///
+    /// This is useful to warn about unused formatters, or to skip initializing expensive data
+    /// providers which a template will never call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, FormatMap, ToFormatPieces, UnusedKeys};
+    ///
+    /// let fmap: FormatMap<String> = fm!{
+    ///     "foo" => |_| None,
+    ///     "bar" => |_| None,
+    ///     "baz" => |_| None,
+    /// };
+    /// let fp = fmap.to_format_pieces("{bar}").unwrap();
+    /// assert_eq!(fmap.unused_keys(&fp), vec!["baz", "foo"]);
+    /// ```
+    fn unused_keys(&self, pieces: &FormatPieces<T>) -> Vec<&str>;
+}
+
+/// Whether `key` is referenced by `pieces`, including as the condition of a conditional or inside
+/// its branches.
+fn uses_key<T: ?Sized>(pieces: &[FormatPiece<T>], key: &str) -> bool {
+    pieces.iter().any(|piece| match piece {
+        FormatPiece::Verbatim(_) => false,
+        FormatPiece::Formatter(f) => f.key == key,
+        FormatPiece::Bytes(b) => b.formatter().key == key,
+        FormatPiece::Conditional(c) => {
+            c.condition.key == key || uses_key(&c.then, key) || uses_key(&c.otherwise, key)
+        }
+    })
+}
+
+/// The keys yielded by `keys` which are not referenced by `pieces`, in the same order.
+fn unused_in<'a, T: ?Sized>(
+    keys: impl Iterator<Item = &'a KeyString>,
+    pieces: &FormatPieces<T>,
+) -> Vec<&'a str> {
+    keys.filter(|key| !uses_key(pieces, key))
+        .map(|key| key.as_str())
+        .collect()
+}
+
+impl<T: ?Sized> UnusedKeys<T> for FormatMap<T> {
+    fn unused_keys(&self, pieces: &FormatPieces<T>) -> Vec<&str> {
+        let mut unused = unused_in(self.keys(), pieces);
+        unused.sort_unstable();
+        unused
+    }
+}
+
+#[cfg(feature = "indexmap")]
+impl<T: ?Sized> UnusedKeys<T> for OrderedFormatMap<T> {
+    fn unused_keys(&self, pieces: &FormatPieces<T>) -> Vec<&str> {
+        unused_in(self.keys(), pieces)
+    }
+}
+
+/// Escape `text` so that it is treated entirely as verbatim text when used as part of a template.
+///
+/// This is the inverse of template parsing: `{` and `}` are doubled, so splicing the result into a
+/// template can never introduce a key or unbalance the template's brackets. If `text` contains no
+/// brackets, it is returned unmodified without allocating.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{escape, fm, Render, ToFormatPieces};
+///
+/// let user_input = "{not a key}";
+/// assert_eq!(escape(user_input), "{{not a key}}");
+///
+/// let fmap = fm!{"foo" => |data: &String| Some(data.clone())};
+/// let fp = fmap.to_format_pieces(format!("{} {{foo}}", escape(user_input))).unwrap();
+/// assert_eq!(fp.render(&String::from("bar")), Ok("{not a key} bar".to_string()));
+/// ```
+pub fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['{', '}']) {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len().saturating_add(8));
+    escape_into(text, &mut out);
+    Cow::Owned(out)
+}
+
+/// Like `escape`, but appends the escaped text to `out` instead of returning it.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::escape_into;
+///
+/// let mut tmpl = String::from("{foo} ");
+/// escape_into("a}b", &mut tmpl);
+/// assert_eq!(tmpl, "{foo} a}}b");
+/// ```
+pub fn escape_into(text: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(idx) = rest.find(['{', '}']) {
+        // Brackets are ASCII, so idx + 1 is definitely at a character boundary.
+        let (before, after) = rest.split_at(idx + 1);
+        out.push_str(before);
+        out.push_str(&before[idx..]);
+        rest = after;
+    }
+    out.push_str(rest);
+}
+
 /// Convenience macro to construct a single mapping for a `FormatMap`, since the types are somewhat
 /// complex.
 ///
+/// Callbacks of several flavors can be mixed in one invocation:
+///
+/// - Plain callbacks, taking the data and returning an `Option<String>`.
+/// - Fallible callbacks returning a `Result`, adapted with `fallible(...)`.
+/// - Callbacks taking an argument from the key, marked with `with_arg` before the closure, like
+///   `move`. They are given the data and the text after the `:` in the key, so `{pad:5}` calls the
+///   callback for `pad` with `"5"`.
+///
+/// Each callback is wrapped in a `Callback<T>`. If any takes an argument, a `CallbackMap` is
+/// constructed instead, since a `FormatMap` can only store plain callbacks. Otherwise, if any
+/// callback is followed by `=>` and a description, a `DescribedMap` is constructed, storing the
+/// descriptions for `DescribeKeys::describe_keys`. Descriptions can't be combined with `with_arg`.
+///
 /// # Example
 ///
 /// ```
-/// use funcfmt::{fm, FormatMap};
+/// use funcfmt::{fallible, fm, CallbackMap, DescribedMap, FormatMap};
 ///
-/// let fmap: FormatMap<String> = fm!{"foo" => |data| Some(format!("b{data}d"))};
+/// let fmap: FormatMap<String> = fm!{
+///     "foo" => |data| Some(format!("b{data}d")),
+///     "num" => fallible(|data: &String| data.parse::<u32>().map(|n| (n * 2).to_string())),
+/// };
+/// let dmap: DescribedMap<String> = fm!{
+///     "foo" => |data| Some(format!("b{data}d")) => "The data, wrapped in b and d",
+/// };
+/// let cmap: CallbackMap<String> = fm!{
+///     "foo" => |data| Some(format!("b{data}d")),
+///     "num" => fallible(|data: &String| data.parse::<u32>().map(|n| (n * 2).to_string())),
+///     "repeat" => with_arg |data: &String, n: &str| Some(data.repeat(n.parse().ok()?)),
+/// };
 /// ```
 #[macro_export]
 macro_rules! fm {
     (@single $($x:tt)*) => (());
-    (@count $($rest:expr),*) => (<[()]>::len(&[$(fm!(@single $rest)),*]));
+    (@count $($rest:expr),*) => (<[()]>::len(&[$($crate::fm!(@single $rest)),*]));
+
+    // Sort entries by flavor, noting whether any takes an argument or has a description. This is
+    // only needed for `with_arg` entries, which otherwise fail to match the arms below.
+    (@munch $args:tt $desc:tt [$($done:tt)*]) => { $crate::fm!(@build $args $desc $($done)*) };
+    (@munch $args:tt $desc:tt [$($done:tt)*]
+        $key:expr => with_arg $value:expr => $d:expr $(, $($rest:tt)*)?) => {
+        $crate::fm!(@munch args desc [$($done)* (with_arg $key, $value, $d)] $($($rest)*)?)
+    };
+    (@munch $args:tt $desc:tt [$($done:tt)*]
+        $key:expr => with_arg $value:expr $(, $($rest:tt)*)?) => {
+        $crate::fm!(@munch args $desc [$($done)* (with_arg $key, $value,)] $($($rest)*)?)
+    };
+    (@munch $args:tt $desc:tt [$($done:tt)*]
+        $key:expr => $value:expr => $d:expr $(, $($rest:tt)*)?) => {
+        $crate::fm!(@munch $args desc [$($done)* (plain $key, $value, $d)] $($($rest)*)?)
+    };
+    (@munch $args:tt $desc:tt [$($done:tt)*] $key:expr => $value:expr $(, $($rest:tt)*)?) => {
+        $crate::fm!(@munch $args $desc [$($done)* (plain $key, $value,)] $($($rest)*)?)
+    };
+
+    (@build args desc $($entry:tt)*) => {
+        compile_error!("descriptions can't be combined with `with_arg` callbacks")
+    };
+    (@build args nodesc $(($flavor:ident $key:expr, $value:expr,))*) => {
+        {
+            let mut map = $crate::CallbackMap::new();
+            $(
+                map.insert($key, $crate::fm!(@callback $flavor $value));
+            )*
+            map
+        }
+    };
+    (@build noargs $desc:tt $(($flavor:ident $key:expr, $value:expr, $($d:expr)?))*) => {
+        $crate::fm!($($key => $value $(=> $d)?),*)
+    };
+
+    (@callback plain $value:expr) => {
+        {
+            let cb: $crate::FormatterCallback<_> = $crate::__private::Arc::new($value);
+            $crate::Callback::Plain(cb)
+        }
+    };
+    (@callback with_arg $value:expr) => {
+        {
+            let cb: $crate::ArgCallback<_> = $crate::__private::Arc::new($value);
+            $crate::Callback::WithArg(cb)
+        }
+    };
 
     ($($key:expr => $value:expr,)+) => { fm!($($key => $value),+) };
     ($($key:expr => $value:expr),*) => {
@@ -239,13 +1169,99 @@ macro_rules! fm {
             let nr = fm!(@count $($key),*);
             let mut map = $crate::FormatMap::with_capacity_and_hasher(nr, Default::default());
             $(
-                let cb: $crate::FormatterCallback<_> = std::sync::Arc::new($value);
+                let cb: $crate::FormatterCallback<_> = $crate::__private::Arc::new($value);
                 map.insert($key.into(), cb);
             )*
             map
         }
     };
+
+    ($($key:expr => $value:expr $(=> $desc:expr)?,)+) => {
+        $crate::fm!($($key => $value $(=> $desc)?),+)
+    };
+    ($($key:expr => $value:expr $(=> $desc:expr)?),*) => {
+        {
+            let mut map = $crate::DescribedMap::new();
+            $(
+                let cb: $crate::FormatterCallback<_> = $crate::__private::Arc::new($value);
+                map.insert_callback($key, cb)$(.description($desc))?;
+            )*
+            map
+        }
+    };
+
+    ($($entries:tt)*) => { $crate::fm!(@munch noargs nodesc [] $($entries)*) };
 }
 
+/// Like `fm!`, but constructing an `OrderedFormatMap`, which remembers the order keys were given
+/// in. With the `indexmap` feature.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "indexmap")] {
+/// use funcfmt::{ordered_fm, OrderedFormatMap};
+///
+/// let fmap: OrderedFormatMap<String> = ordered_fm!{
+///     "name" => |data: &String| Some(data.clone()),
+///     "len" => |data: &String| Some(data.len().to_string()),
+///     "first" => |data: &String| data.chars().next().map(String::from),
+/// };
+/// assert!(fmap.keys().eq(["name", "len", "first"]));
+/// # }
+/// ```
+#[cfg(feature = "indexmap")]
+#[macro_export]
+macro_rules! ordered_fm {
+    ($($key:expr => $value:expr,)+) => { $crate::ordered_fm!($($key => $value),+) };
+    ($($key:expr => $value:expr),*) => {
+        {
+            let nr = $crate::fm!(@count $($key),*);
+            let mut map = $crate::OrderedFormatMap::with_capacity_and_hasher(nr, Default::default());
+            $(
+                let cb: $crate::FormatterCallback<_> = $crate::__private::Arc::new($value);
+                map.insert($key.into(), cb);
+            )*
+            map
+        }
+    };
+}
+
+#[cfg(test)]
+mod bytes_test;
+#[cfg(all(test, feature = "std"))]
+mod cache_test;
+#[cfg(test)]
+mod cached_test;
+#[cfg(test)]
+mod callbacks_test;
+#[cfg(test)]
+mod compiled_test;
+#[cfg(test)]
+mod context_test;
+#[cfg(all(test, feature = "derive"))]
+mod derive_test;
+#[cfg(test)]
+mod edit_test;
+#[cfg(test)]
+mod escaper_test;
+#[cfg(all(test, feature = "ffi"))]
+mod ffi_test;
+#[cfg(all(test, feature = "num-format"))]
+mod filters_test;
+#[cfg(test)]
+mod i18n_test;
+#[cfg(all(test, feature = "json"))]
+mod json_test;
 #[cfg(test)]
 mod lib_test;
+#[cfg(all(test, feature = "macros"))]
+mod macros_test;
+#[cfg(all(test, feature = "providers"))]
+mod providers_test;
+#[cfg(test)]
+mod state_test;
+#[cfg(test)]
+mod template_set_test;
+#[cfg(test)]
+mod value_test;
@@ -1,6 +1,8 @@
 use super::*;
 use once_cell::sync::Lazy;
 use proptest::prelude::*;
+use std::borrow::Cow;
+use std::ops::ControlFlow;
 
 static FORMATTERS: Lazy<FormatMap<String>> = Lazy::new(|| {
     fm! {
@@ -120,3 +122,1068 @@ fn formatter_debug() {
     };
     assert_eq!(format!("{:?}", f1), "Formatter(key: foo)");
 }
+
+#[test]
+fn render_with_default_options_matches_render() {
+    let inp = String::from("bar");
+    let fp = FORMATTERS.to_format_pieces("一{foo}\r\n二{bar}\n").unwrap();
+    assert_eq!(
+        fp.render_with(&inp, &RenderOptions::default()),
+        fp.render(&inp)
+    );
+}
+
+#[test]
+fn line_ending_normalized_across_pieces() {
+    let fmap: FormatMap<String> = fm! {
+        "cr" => |_| Some("x\r".to_owned()),
+        "lf" => |_| Some("\ny\n".to_owned()),
+    };
+    let fp = fmap.to_format_pieces("a\r\n{cr}{lf}b\nc").unwrap();
+    let inp = String::new();
+
+    let lf = RenderOptions::new().line_ending(LineEnding::Lf);
+    assert_eq!(fp.render_with(&inp, &lf), Ok("a\nx\ny\nb\nc".to_owned()));
+
+    let crlf = RenderOptions::new().line_ending(LineEnding::CrLf);
+    assert_eq!(
+        fp.render_with(&inp, &crlf),
+        Ok("a\r\nx\r\ny\r\nb\r\nc".to_owned())
+    );
+}
+
+#[test]
+fn trailing_newline_policy() {
+    let inp = String::from("bar");
+    let with_nl = FORMATTERS.to_format_pieces("{foo}\r\n").unwrap();
+    let without_nl = FORMATTERS.to_format_pieces("{foo}").unwrap();
+
+    let ensure = RenderOptions::new()
+        .line_ending(LineEnding::CrLf)
+        .trailing_newline(TrailingNewline::Ensure);
+    assert_eq!(
+        without_nl.render_with(&inp, &ensure),
+        Ok("bar foo bar\r\n".to_owned())
+    );
+    assert_eq!(
+        with_nl.render_with(&inp, &ensure),
+        Ok("bar foo bar\r\n".to_owned())
+    );
+
+    let strip = RenderOptions::new().trailing_newline(TrailingNewline::Strip);
+    assert_eq!(
+        with_nl.render_with(&inp, &strip),
+        Ok("bar foo bar".to_owned())
+    );
+    assert_eq!(
+        without_nl.render_with(&inp, &strip),
+        Ok("bar foo bar".to_owned())
+    );
+}
+
+#[test]
+fn max_length_truncation() {
+    let fmap: FormatMap<String> = fm! {"val" => |e: &String| Some(e.to_string())};
+    let fp = fmap.to_format_pieces("<{val}>").unwrap();
+    let inp = String::from("h\u{e9}llo");
+    let render = |max, unit, truncation| {
+        fp.render_with(
+            &inp,
+            &RenderOptions::new().max_length(max, unit, truncation),
+        )
+    };
+
+    assert_eq!(
+        render(7, LengthUnit::Chars, Truncation::Error),
+        Ok("<h\u{e9}llo>".into())
+    );
+    assert_eq!(
+        render(3, LengthUnit::Chars, Truncation::Cut),
+        Ok("<h\u{e9}".into())
+    );
+    // Never splits a character, so may be shorter than the maximum
+    assert_eq!(
+        render(3, LengthUnit::Bytes, Truncation::Cut),
+        Ok("<h".into())
+    );
+    assert_eq!(
+        render(4, LengthUnit::Chars, Truncation::Ellipsis),
+        Ok("<h\u{e9}\u{2026}".into())
+    );
+    assert_eq!(
+        render(6, LengthUnit::Bytes, Truncation::Ellipsis),
+        Ok("<h\u{2026}".into())
+    );
+    // Too short for the ellipsis
+    assert_eq!(
+        render(2, LengthUnit::Bytes, Truncation::Ellipsis),
+        Ok("<h".into())
+    );
+    assert_eq!(
+        render(0, LengthUnit::Chars, Truncation::Ellipsis),
+        Ok("".into())
+    );
+    assert_eq!(
+        render(6, LengthUnit::Bytes, Truncation::Error),
+        Err(Error::TooLong(8))
+    );
+
+    let opts = RenderOptions::new().max_length(1, LengthUnit::Chars, Truncation::Error);
+    let err = fp.render_partial(&inp, &opts).unwrap_err();
+    assert_eq!((err.error, err.piece), (Error::TooLong(7), None));
+    assert_eq!(err.partial, "<h\u{e9}llo>");
+}
+
+#[cfg(feature = "unicode-segmentation")]
+#[test]
+fn max_length_graphemes() {
+    let fmap: FormatMap<String> = fm! {"val" => |e: &String| Some(e.to_string())};
+    let fp = fmap.to_format_pieces("{val}!").unwrap();
+    let inp = String::from("e\u{301}e\u{301}e\u{301}");
+    let opts = RenderOptions::new().max_length(3, LengthUnit::Graphemes, Truncation::Ellipsis);
+    assert_eq!(
+        fp.render_with(&inp, &opts),
+        Ok("e\u{301}e\u{301}\u{2026}".into())
+    );
+}
+
+#[test]
+fn transformer_applies_to_callback_output_only() {
+    let fmap: FormatMap<String> = fm! {
+        "val" => |e: &String| Some(e.to_string()),
+    };
+    let fp = fmap.to_format_pieces("a/{val}/b").unwrap();
+    let opts = RenderOptions::new().transformer(|key, val: &str| {
+        assert_eq!(key, "val");
+        Cow::Owned(
+            val.chars()
+                .filter(|c| *c != '/' && !c.is_control())
+                .collect(),
+        )
+    });
+    let inp = String::from("x/\0y\n");
+    assert_eq!(fp.render_with(&inp, &opts), Ok("a/xy/b".to_owned()));
+}
+
+#[cfg(feature = "unicode-width")]
+#[test]
+fn widths_per_piece() {
+    let inp = String::from("é");
+    let fp = FORMATTERS.to_format_pieces("一{foo}!").unwrap();
+    let rw = fp.render_with_widths(&inp, &RenderOptions::new()).unwrap();
+    assert_eq!(rw.output, "一é foo é!");
+    assert_eq!(rw.widths, vec![2, 7, 1]);
+    assert_eq!(rw.total_width(), 10);
+}
+
+#[cfg(feature = "unicode-width")]
+#[test]
+fn widths_of_conditionals() {
+    let inp = String::from("é");
+    let fp = FORMATTERS.to_format_pieces("一{foo?<{foo}>}!").unwrap();
+    let rw = fp.render_with_widths(&inp, &RenderOptions::new()).unwrap();
+    assert_eq!(rw.output, "一<é foo é>!");
+    assert_eq!(rw.widths, vec![2, 9, 1]);
+    assert_eq!(rw.total_width(), 12);
+}
+
+#[cfg(feature = "unicode-width")]
+#[test]
+fn widths_describe_finished_output() {
+    let inp = String::from("é");
+    let fp = FORMATTERS.to_format_pieces("一{foo}!").unwrap();
+
+    let opts = RenderOptions::new()
+        .line_ending(LineEnding::CrLf)
+        .trailing_newline(TrailingNewline::Ensure);
+    let rw = fp.render_with_widths(&inp, &opts).unwrap();
+    assert_eq!(rw.output, "一é foo é!\r\n");
+    assert_eq!(rw.widths, vec![2, 7, 2]);
+
+    let opts = RenderOptions::new().max_length(5, LengthUnit::Chars, Truncation::Ellipsis);
+    let rw = fp.render_with_widths(&inp, &opts).unwrap();
+    assert_eq!(rw.output, "一é f…");
+    assert_eq!(rw.widths, vec![2, 4, 0]);
+    assert_eq!(rw.total_width(), 6);
+}
+
+#[test]
+fn display_matches_render() {
+    let inp = String::from("bar");
+    let fp = FORMATTERS.to_format_pieces("一{foo}二{{bar}}").unwrap();
+    let disp = fp.display(&inp);
+    assert_eq!(disp.to_string(), fp.render(&inp).unwrap());
+    assert_eq!(disp.take_error(), None);
+}
+
+#[test]
+fn display_stashes_error() {
+    let inp = String::from("bar");
+    let fp = FORMATTERS.to_format_pieces("一{foo}二{nodata}").unwrap();
+    let disp = fp.display(&inp);
+    let mut out = String::new();
+    assert_eq!(
+        std::fmt::write(&mut out, format_args!("{disp}")),
+        Err(std::fmt::Error)
+    );
+    assert_eq!(disp.take_error(), Some(Error::NoData("nodata".into())));
+    assert_eq!(disp.take_error(), None);
+}
+
+proptest! {
+    #[test]
+    fn escape_roundtrips(text in r#"\PC*"#) {
+        let tmpl = format!("{}{{foo}}{}", escape(&text), escape(&text));
+        let fp = FORMATTERS.to_format_pieces(tmpl).unwrap();
+        let inp = String::from("x");
+        prop_assert_eq!(fp.render(&inp).unwrap(), format!("{text}x foo x{text}"));
+    }
+}
+
+#[test]
+fn escape_borrows_without_brackets() {
+    assert!(matches!(escape("一二三"), Cow::Borrowed("一二三")));
+    assert_eq!(escape("}{"), "}}{{");
+}
+
+#[test]
+fn refresh_replaces_only_changed_callbacks() {
+    let mut fmap = FORMATTERS.clone();
+    let mut fp = fmap.to_format_pieces("{foo}{bar}{foo}").unwrap();
+    assert!(fp.refresh(&fmap).unwrap().is_empty());
+
+    let cb: FormatterCallback<String> = Arc::new(|_| Some("new".to_owned()));
+    fmap.insert("foo".into(), cb);
+    let report = fp.refresh(&fmap).unwrap();
+    assert_eq!(report.updated, vec!["foo"]);
+
+    let inp = String::from("x");
+    assert_eq!(fp.render(&inp), Ok("newx bar xnew".to_owned()));
+}
+
+#[test]
+fn refresh_unknown_key_leaves_pieces_untouched() {
+    let mut fmap = FORMATTERS.clone();
+    let mut fp = fmap.to_format_pieces("{foo}{bar}").unwrap();
+    let cb: FormatterCallback<String> = Arc::new(|_| Some("new".to_owned()));
+    fmap.insert("foo".into(), cb);
+    fmap.remove("bar");
+
+    assert_eq!(fp.refresh(&fmap), Err(Error::UnknownKey("bar".into())));
+    let inp = String::from("x");
+    assert_eq!(fp.render(&inp), Ok("x foo xx bar x".to_owned()));
+}
+
+#[test]
+fn refresh_keeps_case_insensitive_matches() {
+    let inp = String::from("x");
+    let mut fmap = FORMATTERS.clone();
+    let opts = CompileOptions::new().case_insensitive(true).trim_keys(true);
+    let mut fp = fmap
+        .to_format_pieces_with("{FOO} {Bar?<{ bar }>}", &opts)
+        .unwrap();
+    assert!(fp.refresh(&fmap).unwrap().is_empty());
+
+    fmap.insert("bar".into(), Arc::new(|_| Some("new".to_string())));
+    let report = fp.refresh(&fmap).unwrap();
+    assert_eq!(report.updated, vec!["Bar?<{ bar }>"]);
+    assert_eq!(fp.render(&inp), Ok("x foo x <new>".to_owned()));
+}
+
+#[test]
+fn spans_cover_template() {
+    let tmpl = "一{foo}二{{bar}}{bar}";
+    let (fp, spans) = FORMATTERS.to_format_pieces_spanned(tmpl).unwrap();
+    assert_eq!(fp, FORMATTERS.to_format_pieces(tmpl).unwrap());
+    assert_eq!(fp.len(), spans.len());
+
+    let slices: Vec<&str> = spans.iter().map(|s| &tmpl[s.clone()]).collect();
+    assert_eq!(slices, vec!["一", "{foo}", "二", "{bar", "}", "{bar}"]);
+}
+
+#[test]
+fn parse_template_without_map() {
+    let tokens = parse_template("一{unregistered}{{").unwrap();
+    assert_eq!(
+        tokens,
+        vec![
+            TemplateToken::Verbatim {
+                text: "一",
+                span: 0..3
+            },
+            TemplateToken::Key {
+                name: "unregistered".into(),
+                span: 3..17
+            },
+            TemplateToken::Verbatim {
+                text: "{",
+                span: 18..19
+            },
+        ]
+    );
+    assert_eq!(parse_template("{a{b}"), Err(Error::ImbalancedBrackets));
+}
+
+#[test]
+fn unused_keys_sorted() {
+    let fp = FORMATTERS.to_format_pieces("{bar}{bar}").unwrap();
+    assert_eq!(FORMATTERS.unused_keys(&fp), vec!["foo", "nodata"]);
+
+    let fp = FORMATTERS.to_format_pieces("{foo}{bar}{nodata}").unwrap();
+    assert!(FORMATTERS.unused_keys(&fp).is_empty());
+}
+
+#[test]
+fn unused_keys_in_conditionals() {
+    let fp = FORMATTERS.to_format_pieces("{nodata?a:{foo}}").unwrap();
+    assert_eq!(FORMATTERS.unused_keys(&fp), vec!["bar"]);
+
+    let fp = FORMATTERS.to_format_pieces("{foo?{nodata?{bar}}}").unwrap();
+    assert!(FORMATTERS.unused_keys(&fp).is_empty());
+}
+
+#[cfg(feature = "indexmap")]
+#[test]
+fn ordered_format_map() {
+    let fmap: OrderedFormatMap<String> = ordered_fm! {
+        "zeta" => |data: &String| Some(data.clone()),
+        "alpha" => |_| Some("a".to_string()),
+        "mid" => |_| None,
+    };
+    assert!(fmap.keys().eq(["zeta", "alpha", "mid"]));
+
+    let fp = fmap.to_format_pieces("{alpha}{zeta}").unwrap();
+    assert_eq!(fp.render(&String::from("z")), Ok("az".to_string()));
+    assert_eq!(
+        fmap.to_format_pieces("{ALPHA}").map(|_| ()),
+        Err(Error::UnknownKey("ALPHA".into()))
+    );
+    let fp = fmap
+        .to_format_pieces_with("{ALPHA}", &CompileOptions::new().case_insensitive(true))
+        .unwrap();
+    assert_eq!(fp.render(&String::new()), Ok("a".to_string()));
+
+    // Unlike FormatMap, unused keys are in insertion order rather than sorted
+    let fp = fmap.to_format_pieces("{alpha}").unwrap();
+    assert_eq!(fmap.unused_keys(&fp), vec!["zeta", "mid"]);
+}
+
+#[test]
+fn catch_panics_reports_key() {
+    let fmap: FormatMap<String> = fm! {
+        "ok" => |e: &String| Some(e.to_string()),
+        "bad" => |e: &String| if e.is_empty() { panic!("empty") } else { Some(e.to_string()) },
+    };
+    let fp = fmap.to_format_pieces("{ok}{bad}").unwrap();
+    let opts = RenderOptions::new().catch_panics(true);
+
+    assert_eq!(
+        fp.render_with(&String::new(), &opts),
+        Err(Error::CallbackPanicked("bad".into()))
+    );
+    assert_eq!(
+        fp.render_with(&String::from("x"), &opts),
+        Ok("xx".to_owned())
+    );
+}
+
+#[test]
+#[should_panic(expected = "empty")]
+fn panics_propagate_by_default() {
+    let fmap: FormatMap<String> = fm! {
+        "bad" => |_: &String| panic!("empty"),
+    };
+    let fp = fmap.to_format_pieces("{bad}").unwrap();
+    let _ = fp.render(&String::new());
+}
+
+#[test]
+fn stats_count_calls_and_misses() {
+    let fp = FORMATTERS.to_format_pieces("{foo}{bar}{foo}").unwrap();
+    let nodata = FORMATTERS.to_format_pieces("{nodata}").unwrap();
+    let opts = RenderOptions::new();
+    let mut stats = RenderStats::new();
+    let inp = String::from("x");
+
+    fp.render_with_stats(&inp, &opts, &mut stats).unwrap();
+    fp.render_with_stats(&inp, &opts, &mut stats).unwrap();
+    assert!(nodata.render_with_stats(&inp, &opts, &mut stats).is_err());
+
+    assert_eq!(stats.get("foo").map(|s| (s.calls, s.misses)), Some((4, 0)));
+    assert_eq!(stats.get("bar").map(|s| (s.calls, s.misses)), Some((2, 0)));
+    assert_eq!(
+        stats.get("nodata").map(|s| (s.calls, s.misses)),
+        Some((1, 1))
+    );
+
+    let mut keys: Vec<_> = stats.by_total_time().into_iter().map(|(k, _)| k).collect();
+    keys.sort_unstable();
+    assert_eq!(keys, vec!["bar", "foo", "nodata"]);
+
+    stats.clear();
+    assert_eq!(stats.get("foo"), None);
+}
+
+#[test]
+fn cancel_flag_stops_between_pieces() {
+    let cancel = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let flag = Arc::clone(&cancel);
+    let fmap: FormatMap<String> = fm! {
+        "cancel" => move |_: &String| {
+            flag.store(true, std::sync::atomic::Ordering::Relaxed);
+            Some(String::new())
+        },
+        "never" => |_: &String| panic!("called after cancellation"),
+    };
+    let fp = fmap.to_format_pieces("{cancel}{never}").unwrap();
+    let opts = RenderOptions::new().cancel_flag(cancel);
+    assert_eq!(fp.render_with(&String::new(), &opts), Err(Error::Cancelled));
+}
+
+#[test]
+fn deadline_in_past_cancels() {
+    let fp = FORMATTERS.to_format_pieces("{foo}").unwrap();
+    let inp = String::from("x");
+
+    let past = RenderOptions::new().deadline(std::time::Instant::now());
+    assert_eq!(fp.render_with(&inp, &past), Err(Error::Cancelled));
+
+    let future = std::time::Instant::now() + std::time::Duration::from_secs(3600);
+    let opts = RenderOptions::new().deadline(future);
+    assert_eq!(fp.render_with(&inp, &opts), Ok("x foo x".to_owned()));
+}
+
+#[test]
+fn output_capacity_counts_verbatim_text_exactly() {
+    let inp = String::from("x");
+
+    let fp = FORMATTERS.to_format_pieces("一{{二}}三").unwrap();
+    let out = fp.render(&inp).unwrap();
+    assert_eq!(out.capacity(), out.len());
+
+    // Each callback's output is shorter than the guess for it, so nothing is reallocated
+    let fp = FORMATTERS.to_format_pieces("一{foo}二{bar}").unwrap();
+    let out = fp.render(&inp).unwrap();
+    assert_eq!(out, "一x foo x二x bar x");
+    assert_eq!(out.capacity(), "一二".len() + 2 * 16);
+    let opts = RenderOptions::default();
+    let sliced = render::render_to_string(&fp[..], &inp, &opts, None).unwrap();
+    assert_eq!(sliced.capacity(), out.capacity());
+}
+
+#[test]
+fn unterminated_key() {
+    assert_eq!(
+        FORMATTERS.to_format_pieces("一{foo"),
+        Err(Error::ImbalancedBrackets)
+    );
+    assert_eq!(
+        FORMATTERS.to_format_pieces("一}{foo}"),
+        Err(Error::ImbalancedBrackets)
+    );
+}
+
+#[test]
+fn trailing_close_is_literal() {
+    let inp = String::from("bar");
+    let fp = FORMATTERS.to_format_pieces("一{foo}}").unwrap();
+    assert_eq!(fp.render(&inp), Ok("一bar foo bar}".to_owned()));
+}
+
+#[test]
+fn no_empty_verbatim_pieces() {
+    let fp = FORMATTERS.to_format_pieces("{foo}{bar}").unwrap();
+    assert_eq!(fp.len(), 2);
+    assert!(fp.iter().all(|p| matches!(p, FormatPiece::Formatter(_))));
+}
+
+#[test]
+fn key_lookup_other_maps() {
+    use std::collections::{BTreeMap, HashMap};
+
+    let cb: FormatterCallback<String> = Arc::new(|data| Some(data.clone()));
+    let mut std_map: HashMap<String, FormatterCallback<String>> = HashMap::new();
+    std_map.insert("foo".to_string(), cb.clone());
+    let mut btree: BTreeMap<&str, FormatterCallback<String>> = BTreeMap::new();
+    btree.insert("foo", cb);
+
+    let inp = String::from("x");
+    for fp in [
+        std_map.to_format_pieces("a{foo}b"),
+        btree.to_format_pieces("a{foo}b"),
+    ] {
+        assert_eq!(fp.unwrap().render(&inp), Ok("axb".to_string()));
+    }
+    assert_eq!(
+        btree.to_format_pieces("{bar}"),
+        Err(Error::UnknownKey("bar".into()))
+    );
+}
+
+#[test]
+fn refresh_from_other_map() {
+    use std::collections::BTreeMap;
+
+    let mut fp = FORMATTERS.to_format_pieces("{foo}{bar}").unwrap();
+    let mut btree: BTreeMap<&str, FormatterCallback<String>> = BTreeMap::new();
+    btree.insert("foo", Arc::new(|_| Some("f".to_string())));
+    assert_eq!(fp.refresh(&btree), Err(Error::UnknownKey("bar".into())));
+    assert_eq!(fp.render(&String::from("x")).unwrap(), "x foo xx bar x");
+
+    btree.insert("bar", FORMATTERS["bar"].clone());
+    let report = fp.refresh(&btree).unwrap();
+    assert_eq!(report.updated, vec!["foo"]);
+    assert_eq!(fp.render(&String::from("x")).unwrap(), "fx bar x");
+}
+
+#[cfg(feature = "hashbrown")]
+#[test]
+fn key_lookup_hashbrown() {
+    let mut map: hashbrown::HashMap<&str, FormatterCallback<String>, fnv::FnvBuildHasher> =
+        hashbrown::HashMap::default();
+    map.insert("foo", Arc::new(|data: &String| Some(data.clone())));
+    let fp = map.to_format_pieces("<{foo}>").unwrap();
+    assert_eq!(fp.render(&String::from("x")), Ok("<x>".to_string()));
+}
+
+#[test]
+fn trim_markers() {
+    let inp = String::from("x");
+    for (tmpl, expected) in [
+        ("a \n {- foo -} \n b", "ax foo xb"),
+        ("a {- foo} b", "ax foo x b"),
+        ("a {foo -}\n\n b", "a x foo xb"),
+        ("a {{ {- foo -} }} b", "a {x foo x} b"),
+        ("{foo -}   ", "x foo x"),
+        ("   {- foo}{bar -}  {- foo}", "x foo xx bar xx foo x"),
+    ] {
+        let fp = FORMATTERS.to_format_pieces(tmpl).unwrap();
+        assert_eq!(fp.render(&inp).unwrap(), expected, "{tmpl:?}");
+    }
+
+    // Without whitespace, a dash is part of the key name
+    let fmap: FormatMap<String> = fm! {"-foo-" => |_| Some("dash".to_string())};
+    let fp = fmap.to_format_pieces(" {-foo-} ").unwrap();
+    assert_eq!(fp.render(&inp).unwrap(), " dash ");
+}
+
+#[test]
+fn trim_marker_spans() {
+    let tokens = parse_template("a  {- foo -}  b").unwrap();
+    assert_eq!(
+        tokens,
+        vec![
+            TemplateToken::Verbatim {
+                text: "a",
+                span: 0..1
+            },
+            TemplateToken::Key {
+                name: "foo".into(),
+                span: 3..12
+            },
+            TemplateToken::Verbatim {
+                text: "b",
+                span: 14..15
+            },
+        ]
+    );
+}
+
+#[test]
+fn escaped_key_names() {
+    let fmap: FormatMap<String> = fm! {
+        "a{b}" => |_| Some("brackets".to_string()),
+        "c\\d" => |_| Some("backslash".to_string()),
+        "e\\f" => |_| Some("literal".to_string()),
+    };
+    let fp = fmap.to_format_pieces(r"{a\{b\}} {c\\d} {e\f}").unwrap();
+    assert_eq!(
+        fp.render(&String::new()).unwrap(),
+        "brackets backslash literal"
+    );
+    assert_eq!(
+        fmap.to_format_pieces(r"{a\{b}").map(|_| ()),
+        Err(Error::UnknownKey("a{b".into()))
+    );
+    assert_eq!(
+        fmap.to_format_pieces(r"{a\}").map(|_| ()),
+        Err(Error::ImbalancedBrackets)
+    );
+
+    for key in ["a{b}", "c\\d", "plain", "\\{}"] {
+        let tmpl = format!("{{{}}}", escape_key(key));
+        let tokens = parse_template(&tmpl).unwrap();
+        assert!(
+            matches!(&tokens[..], [TemplateToken::Key { name, .. }] if name == key),
+            "{tmpl}"
+        );
+    }
+    assert!(matches!(escape_key("plain"), Cow::Borrowed("plain")));
+}
+
+#[test]
+fn case_insensitive_keys() {
+    let fmap: FormatMap<String> = fm! {
+        "foo" => |_| Some("lower".to_string()),
+        "Bar" => |_| Some("mixed".to_string()),
+        "BAR" => |_| Some("upper".to_string()),
+    };
+    let inp = String::new();
+
+    // Matching is case-sensitive by default
+    assert_eq!(
+        fmap.to_format_pieces("{FOO}").map(|_| ()),
+        Err(Error::UnknownKey("FOO".into()))
+    );
+
+    let opts = CompileOptions::new().case_insensitive(true);
+    let fp = fmap
+        .to_format_pieces_with("{foo} {Foo} {FOO} {Bar} {BAR} {bar}", &opts)
+        .unwrap();
+    // Exact matches win, otherwise the first matching key in sort order is used
+    assert_eq!(
+        fp.render(&inp).unwrap(),
+        "lower lower lower mixed upper upper"
+    );
+    assert!(matches!(&fp[4], FormatPiece::Formatter(f) if f.key == "FOO"));
+
+    assert_eq!(
+        fmap.to_format_pieces_with("{baz}", &opts).map(|_| ()),
+        Err(Error::UnknownKey("baz".into()))
+    );
+
+    let ct = CompiledTemplate::compile_with(&fmap, "{FOO}{foo}", &opts).unwrap();
+    assert_eq!(ct.keys().collect::<Vec<_>>(), vec!["FOO", "foo"]);
+    assert_eq!(ct.render(&inp).unwrap(), "lowerlower");
+}
+
+#[test]
+fn case_insensitive_through_tuple_and_namespace() {
+    let fmap: FormatMap<String> = fm! {"foo" => |data: &String| Some(data.clone())};
+    let other: FormatMap<String> = fm! {"bar" => |_| Some("b".to_string())};
+    let opts = CompileOptions::new().case_insensitive(true);
+
+    let fp = (fmap.clone(), other)
+        .to_format_pieces_with("{FOO}{BAR}", &opts)
+        .unwrap();
+    assert_eq!(fp.render(&String::from("f")).unwrap(), "fb");
+
+    let ns = namespace("user", fmap, |data: &String| data);
+    let fp = ns.to_format_pieces_with("{user.FOO}", &opts).unwrap();
+    assert_eq!(fp.render(&String::from("f")).unwrap(), "f");
+
+    // An exact match in a later element wins over a case-insensitive one in an earlier one
+    let upper: FormatMap<String> = fm! {"FOO" => |_| Some("upper".to_string())};
+    let lower: FormatMap<String> = fm! {"foo" => |_| Some("lower".to_string())};
+    let fp = (upper.clone(), lower.clone())
+        .to_format_pieces_with("{foo}", &opts)
+        .unwrap();
+    assert_eq!(fp.render(&String::new()).unwrap(), "lower");
+    let fp = (upper, FormatMap::default(), lower)
+        .to_format_pieces_with("{foo}", &opts)
+        .unwrap();
+    assert_eq!(fp.render(&String::new()).unwrap(), "lower");
+}
+
+#[cfg(feature = "unicase")]
+#[test]
+fn case_insensitive_unicode() {
+    let fmap: FormatMap<String> = fm! {"straße" => |_| Some("x".to_string())};
+    let opts = CompileOptions::new().case_insensitive(true);
+    let fp = fmap.to_format_pieces_with("{STRASSE}", &opts).unwrap();
+    assert_eq!(fp.render(&String::new()).unwrap(), "x");
+}
+
+#[test]
+fn send_sync_clone() {
+    fn assert_traits<X: Send + Sync + Clone + 'static>() {}
+
+    // Holds for any T, even ones which are neither Send nor Sync
+    assert_traits::<FormatPieces<String>>();
+    assert_traits::<FormatPieces<std::rc::Rc<String>>>();
+    assert_traits::<FormatPiece<std::rc::Rc<String>>>();
+    assert_traits::<Formatter<std::rc::Rc<String>>>();
+    assert_traits::<CompiledTemplate<std::rc::Rc<String>>>();
+}
+
+#[test]
+fn clones_share_callbacks() {
+    let fp = FORMATTERS.to_format_pieces("<{foo}>").unwrap();
+    let cloned = fp.clone();
+    assert_eq!(fp, cloned);
+    match (&fp[1], &cloned[1]) {
+        (FormatPiece::Formatter(a), FormatPiece::Formatter(b)) => {
+            assert!(Arc::ptr_eq(&a.cb, &b.cb))
+        }
+        _ => panic!("expected formatters"),
+    }
+
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let fp = fp.clone();
+            std::thread::spawn(move || fp.render(&i.to_string()).unwrap())
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), format!("<{i} foo {i}>"));
+    }
+}
+
+#[test]
+fn render_partial_keeps_output() {
+    let inp = String::from("x");
+    let opts = RenderOptions::default();
+
+    let fp = FORMATTERS.to_format_pieces("a{foo}b{nodata}c").unwrap();
+    let err = fp.render_partial(&inp, &opts).unwrap_err();
+    assert_eq!(
+        err,
+        RenderError {
+            error: Error::NoData("nodata".into()),
+            partial: "ax foo xb".to_string(),
+            piece: Some(3),
+            key: Some("nodata".into()),
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "no data for key 'nodata' at piece 3, after 9 bytes of output"
+    );
+    assert_eq!(Error::from(err), Error::NoData("nodata".into()));
+
+    let ct = CompiledTemplate::compile(&*FORMATTERS, "a{foo}b{nodata}c").unwrap();
+    assert_eq!(
+        ct.render_partial(&inp, &opts).unwrap_err().partial,
+        "ax foo xb"
+    );
+
+    let fp = FORMATTERS.to_format_pieces("a{foo}").unwrap();
+    assert_eq!(fp.render_partial(&inp, &opts), Ok("ax foo x".to_string()));
+}
+
+#[test]
+fn keep_unknown_keys() {
+    let inp = String::from("x");
+    let opts = CompileOptions::new().keep_unknown_keys(true);
+    let tmpl = r"{foo} {other} {- a\{b\} -} !";
+
+    let fp = FORMATTERS.to_format_pieces_with(tmpl, &opts).unwrap();
+    assert_eq!(fp[2], FormatPiece::Verbatim("{other}".into()));
+    let out = fp.render(&inp).unwrap();
+    assert_eq!(out, r"x foo x {other}{a\{b\}}!");
+
+    let ct = CompiledTemplate::compile_with(&*FORMATTERS, tmpl, &opts).unwrap();
+    assert_eq!(ct.render(&inp).unwrap(), out);
+    assert_eq!(ct.keys().collect::<Vec<_>>(), vec!["foo"]);
+
+    // The output is itself a template for the keys which were left alone
+    let keys: Vec<_> = parse_template(&out)
+        .unwrap()
+        .into_iter()
+        .filter_map(|t| match t {
+            TemplateToken::Key { name, .. } => Some(name.into_owned()),
+            TemplateToken::Verbatim { .. } => None,
+        })
+        .collect();
+    assert_eq!(keys, vec!["other", "a{b}"]);
+
+    assert_eq!(
+        FORMATTERS
+            .to_format_pieces_with("{other}", &CompileOptions::new())
+            .map(|_| ()),
+        Err(Error::UnknownKey("other".into()))
+    );
+}
+
+#[test]
+fn strict_parse() {
+    let strict = CompileOptions::new().strict(true);
+    let check = |tmpl: &str| parse_template_with(tmpl, &strict).map(|_| ());
+
+    assert_eq!(
+        check(r"{{a}} {- foo -} {foo?x\:y:{foo}} {a\{b\}} z"),
+        Ok(())
+    );
+    assert_eq!(check("a {} b"), Err(Error::EmptyKey));
+    assert_eq!(check("{?x:y}"), Err(Error::EmptyKey));
+    assert_eq!(check("{foo?{}:y}"), Err(Error::EmptyKey));
+    assert_eq!(check("{ foo}"), Err(Error::KeyWhitespace(" foo".into())));
+    assert_eq!(check("{foo ?x}"), Err(Error::KeyWhitespace("foo ".into())));
+    assert_eq!(check(r"{fo\o}"), Err(Error::StrayEscape(r"fo\o".into())));
+    assert_eq!(check("foo}"), Err(Error::ImbalancedBrackets));
+
+    // All of these are tolerated by default
+    for tmpl in ["a {} b", "{ foo}", r"{fo\o}", "foo}"] {
+        assert!(parse_template(tmpl).is_ok(), "{tmpl}");
+    }
+
+    assert_eq!(
+        FORMATTERS
+            .to_format_pieces_with("{foo }", &strict)
+            .map(|_| ()),
+        Err(Error::KeyWhitespace("foo ".into()))
+    );
+    assert_eq!(
+        CompiledTemplate::compile_with(&*FORMATTERS, "x}", &strict).map(|_| ()),
+        Err(Error::ImbalancedBrackets)
+    );
+    assert!(TEMPLATE_GRAMMAR.starts_with("template"));
+}
+
+#[test]
+fn trim_keys() {
+    let inp = String::from("x");
+    let opts = CompileOptions::new().trim_keys(true);
+
+    assert_eq!(
+        FORMATTERS.to_format_pieces("{ foo }").map(|_| ()),
+        Err(Error::UnknownKey(" foo ".into()))
+    );
+    let fp = FORMATTERS
+        .to_format_pieces_with("<{ foo }> {-\tfoo  -} !", &opts)
+        .unwrap();
+    assert!(matches!(&fp[1], FormatPiece::Formatter(f) if f.key == "foo"));
+    assert_eq!(fp.render(&inp), Ok("<x foo x>x foo x!".to_string()));
+
+    let names: Vec<_> = parse_template_with("{ foo ? a : b }", &opts)
+        .unwrap()
+        .into_iter()
+        .map(|t| match t {
+            TemplateToken::Key { name, .. } => name.into_owned(),
+            TemplateToken::Verbatim { text, .. } => text.to_string(),
+        })
+        .collect();
+    assert_eq!(names, vec!["foo? a : b "]);
+
+    let ct = CompiledTemplate::compile_with(&*FORMATTERS, "{ foo ?[{ foo }]}", &opts).unwrap();
+    assert_eq!(ct.render(&inp), Ok("[x foo x]".to_string()));
+
+    // Trimmed keys don't count as whitespace in strict mode
+    let strict = opts.strict(true);
+    assert!(parse_template_with("{ foo }", &strict).is_ok());
+}
+
+#[test]
+fn conditionals() {
+    let inp = String::from("x");
+    for (tmpl, expected) in [
+        ("{foo?yes:no}", "yes"),
+        ("{nodata?yes:no}", "no"),
+        ("{nodata?yes}", ""),
+        ("<{foo?[{foo}]:N/A}>", "<[x foo x]>"),
+        ("<{nodata?[{foo}]:N/A}>", "<N/A>"),
+        ("{nodata?a:b:c}", "b:c"),
+        (r"{foo?\{a\:b\?\}:no}", "{a:b?}"),
+        ("{nodata?1:{foo?2:3}}", "2"),
+        ("{foo?{nodata?1:2}{bar}}", "2x bar x"),
+        ("a {- foo?b -} c", "abc"),
+    ] {
+        let fp = FORMATTERS.to_format_pieces(tmpl).unwrap();
+        assert_eq!(fp.render(&inp).unwrap(), expected, "{tmpl}");
+        let ct = CompiledTemplate::compile(&*FORMATTERS, tmpl).unwrap();
+        assert_eq!(ct.render(&inp).unwrap(), expected, "{tmpl}");
+    }
+
+    let fp = FORMATTERS.to_format_pieces("{foo?{nodata}}").unwrap();
+    assert_eq!(fp.render(&inp), Err(Error::NoData("nodata".into())));
+
+    for (tmpl, err) in [
+        ("{missing?a:b}", Error::UnknownKey("missing".into())),
+        ("{foo?{missing}}", Error::UnknownKey("missing".into())),
+        ("{foo?{bar}", Error::ImbalancedBrackets),
+        ("{foo{bar}?a}", Error::ImbalancedBrackets),
+    ] {
+        assert_eq!(
+            FORMATTERS.to_format_pieces(tmpl).map(|_| ()),
+            Err(err),
+            "{tmpl}"
+        );
+    }
+}
+
+#[test]
+fn conditional_keys_registered_verbatim_win() {
+    let fmap: FormatMap<String> = fm! {
+        "a?" => |_| Some("exact".to_string()),
+        "a" => |_| Some("a".to_string()),
+    };
+    let fp = fmap.to_format_pieces("{a?}").unwrap();
+    assert_eq!(fp.render(&String::new()).unwrap(), "exact");
+
+    let keys: Vec<_> = parse_template("{a?{b}:c}")
+        .unwrap()
+        .into_iter()
+        .map(|t| t.span())
+        .collect();
+    assert_eq!(keys, vec![0..9]);
+}
+
+#[test]
+fn conditional_refresh_and_keep_unknown() {
+    let inp = String::from("x");
+    let mut fmap = FORMATTERS.clone();
+    let mut fp = fmap.to_format_pieces("{foo?<{bar}>}").unwrap();
+    assert!(fp.refresh(&fmap).unwrap().is_empty());
+
+    fmap.insert("bar".into(), Arc::new(|_| Some("new".to_string())));
+    assert_eq!(fp.refresh(&fmap).unwrap().updated, vec!["foo?<{bar}>"]);
+    assert_eq!(fp.render(&inp).unwrap(), "<new>");
+
+    let opts = CompileOptions::new().keep_unknown_keys(true);
+    let mut fp = FORMATTERS
+        .to_format_pieces_with(r"{other?\:{foo}} {foo?{other}}", &opts)
+        .unwrap();
+    assert_eq!(fp.render(&inp).unwrap(), r"{other?\:{foo}} {other}");
+    assert!(fp.refresh(&*FORMATTERS).unwrap().is_empty());
+    assert_eq!(fp.render(&inp).unwrap(), r"{other?\:{foo}} {other}");
+
+    let opts = CompileOptions::new().case_insensitive(true);
+    let mut fp = fmap.to_format_pieces_with("{FOO?<{Bar}>}", &opts).unwrap();
+    assert!(fp.refresh(&fmap).unwrap().is_empty());
+    assert_eq!(fp.render(&inp).unwrap(), "<new>");
+}
+
+#[test]
+fn walk_chunks_and_break() {
+    let fmap: FormatMap<String> = fm! {
+        "foo" => |e: &String| Some(e.to_string()),
+        "none" => |_| None,
+    };
+    let fp = fmap.to_format_pieces("a{foo}b{foo?<{foo}>}").unwrap();
+    let mut chunks = Vec::new();
+    let res = fp.walk(&String::from("x"), |chunk| {
+        chunks.push(match chunk {
+            PieceOutput::Verbatim(text) => format!("v:{text}"),
+            PieceOutput::Formatter { key, output } => format!("{key}:{output}"),
+        });
+        ControlFlow::<()>::Continue(())
+    });
+    assert_eq!(res, Ok(ControlFlow::Continue(())));
+    assert_eq!(chunks, ["v:a", "foo:x", "v:b", "v:<", "foo:x", "v:>"]);
+
+    let ct = CompiledTemplate::compile(&fmap, "a{foo}b{none}").unwrap();
+    let mut seen = 0;
+    let res = ct.walk(&String::from("x"), |chunk| {
+        seen += 1;
+        match chunk {
+            PieceOutput::Formatter { output, .. } => ControlFlow::Break(output.to_string()),
+            PieceOutput::Verbatim(_) => ControlFlow::Continue(()),
+        }
+    });
+    assert_eq!((res, seen), (Ok(ControlFlow::Break("x".to_string())), 2));
+
+    let res = ct.walk(&String::from("x"), |_| ControlFlow::<()>::Continue(()));
+    assert_eq!(res, Err(Error::NoData("none".into())));
+}
+
+#[test]
+fn equality_hash_and_bindings() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn hash<H: Hash>(h: &H) -> u64 {
+        let mut s = DefaultHasher::new();
+        h.hash(&mut s);
+        s.finish()
+    }
+
+    // No PartialEq or Hash bound on the data type
+    struct Opaque;
+    let a: FormatMap<Opaque> = fm! {"foo" => |_| Some("a".to_string())};
+    let b: FormatMap<Opaque> = fm! {"foo" => |_| Some("b".to_string())};
+
+    let fp_a = a.to_format_pieces("x{foo}{foo?y}").unwrap();
+    let fp_b = b.to_format_pieces("x{foo}{foo?y}").unwrap();
+    assert!(fp_a == fp_b);
+    assert_eq!(hash(&fp_a), hash(&fp_b));
+    assert!(!fp_a.same_bindings(&fp_b));
+    assert!(fp_a.same_bindings(&fp_a.clone()));
+    assert!(fp_a != a.to_format_pieces("x{foo}").unwrap());
+
+    let ct_a = CompiledTemplate::compile(&a, "x{foo}").unwrap();
+    let ct_b = CompiledTemplate::compile(&b, "x{foo}").unwrap();
+    assert!(ct_a == ct_b);
+    assert_eq!(hash(&ct_a), hash(&ct_b));
+    assert!(!ct_a.same_bindings(&ct_b));
+    assert!(ct_a.same_bindings(&ct_a.clone()));
+    assert!(ct_a != CompiledTemplate::compile(&a, "y{foo}").unwrap());
+}
+
+#[test]
+fn unsized_data() {
+    use std::any::Any;
+
+    let fmap: FormatMap<dyn Any> = fm! {
+        "int" => |d: &(dyn Any + 'static)| d.downcast_ref::<i32>().map(|i| i.to_string()),
+        "str" => |d: &(dyn Any + 'static)| d.downcast_ref::<&str>().map(|s| s.to_string()),
+    };
+    let fp = fmap
+        .to_format_pieces("{int?int {int}:{str?str {str}:other}}")
+        .unwrap();
+    assert_eq!(fp.render(&5), Ok("int 5".to_string()));
+    assert_eq!(fp.render(&"x"), Ok("str x".to_string()));
+    assert_eq!(fp.render(&1.0), Ok("other".to_string()));
+
+    let ct = CompiledTemplate::compile(&fmap, "<{int}>").unwrap();
+    assert_eq!(ct.render(&7), Ok("<7>".to_string()));
+    assert_eq!(ct.render(&"x"), Err(Error::NoData("int".into())));
+
+    let mut renderer = CachedRenderer::new(fp).stable("int", |_: &dyn Any| ());
+    assert_eq!(renderer.render(&5), Ok("int 5".to_string()));
+}
+
+#[test]
+fn mixed_callback_flavors() {
+    let fmap: CallbackMap<String> = fm! {
+        "raw" => |data: &String| Some(data.clone()),
+        "num" => fallible(|data: &String| data.parse::<u32>().map(|n| (n * 2).to_string())),
+        "len" => fallible(|data: &String| Ok::<_, ()>(data.len().to_string())),
+        "pad" => with_arg |data: &String, width: &str| {
+            let width = width.parse().ok()?;
+            Some(format!("{data:>width$}"))
+        },
+    };
+
+    let fp = fmap
+        .to_format_pieces("{num?{num}:NaN} {len} [{pad:4}] {raw}")
+        .unwrap();
+    assert_eq!(
+        fp.render(&"21".to_string()),
+        Ok("42 2 [  21] 21".to_string())
+    );
+    assert_eq!(
+        fp.render(&"x".to_string()),
+        Ok("NaN 1 [   x] x".to_string())
+    );
+    assert_eq!(
+        fmap.to_format_pieces("{raw:4}"),
+        Err(Error::UnknownKey("raw:4".into()))
+    );
+    assert_eq!(
+        fmap.to_format_pieces("{pad}"),
+        Err(Error::UnknownKey("pad".into()))
+    );
+
+    // Maps without argument callbacks are still plain FormatMaps
+    let plain: FormatMap<String> = fm! {
+        "raw" => |data: &String| Some(data.clone()),
+        "num" => fallible(|data: &String| data.parse::<u32>().map(|n| n.to_string())),
+    };
+    assert_eq!(plain.len(), 2);
+
+    let dmap: DescribedMap<String> = fm! {
+        "num" => fallible(|data: &String| data.parse::<u8>().map(|n| n.to_string())) => "A number",
+    };
+    let fp = dmap.to_format_pieces("{num}").unwrap();
+    assert_eq!(
+        fp.render(&"300".to_string()),
+        Err(Error::NoData("num".into()))
+    );
+}
+
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn format_piece_stays_small() {
+    // FormatPieces stores 256 of these inline, so conditionals are boxed to keep them small
+    assert!(core::mem::size_of::<FormatPiece<()>>() <= 64);
+}
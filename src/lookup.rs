@@ -0,0 +1,251 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::borrow::Borrow;
+#[cfg(any(feature = "std", feature = "hashbrown", feature = "indexmap"))]
+use core::hash::{BuildHasher, Hash};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::{BytesCallback, FormatterCallback};
+
+/// A source of callbacks, looked up by key when processing templates.
+///
+/// `FormatMap<T>` is the default implementation, but any `HashMap` (with any `BuildHasher`),
+/// `hashbrown::HashMap` (with the `hashbrown` feature), `indexmap::IndexMap` (with the `indexmap`
+/// feature), or `BTreeMap` from string keys to callbacks works too. For other backends, such as a
+/// sorted `Vec` or a static perfect hash map for key sets known at compile time, implement this
+/// trait directly.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{FormatterCallback, KeyLookup, Render, ToFormatPieces};
+/// use std::sync::Arc;
+///
+/// struct SortedVec(Vec<(&'static str, FormatterCallback<String>)>);
+///
+/// impl KeyLookup<String> for SortedVec {
+///     fn lookup(&self, key: &str) -> Option<FormatterCallback<String>> {
+///         let idx = self.0.binary_search_by_key(&key, |(k, _)| *k).ok()?;
+///         Some(Arc::clone(&self.0[idx].1))
+///     }
+/// }
+///
+/// let lookup = SortedVec(vec![
+///     ("bar", Arc::new(|_: &String| Some("b".to_string()))),
+///     ("foo", Arc::new(|data: &String| Some(data.clone()))),
+/// ]);
+/// let fp = lookup.to_format_pieces("{foo}{bar}").unwrap();
+/// assert_eq!(fp.render(&String::from("f")), Ok("fb".to_string()));
+/// ```
+pub trait KeyLookup<T: ?Sized> {
+    /// The callback registered for `key`, if any.
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>>;
+
+    /// Like `lookup`, but matching `key` case-insensitively, as used by
+    /// `CompileOptions::case_insensitive`. An exact match is always preferred.
+    ///
+    /// The default implementation only tries `key` as-is and then in lowercase. Implementations
+    /// which can enumerate their keys should override this to compare against each of them with
+    /// `keys_eq_ignore_case`.
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.lookup(key)
+            .or_else(|| self.lookup(&key.to_lowercase()))
+    }
+
+    /// A callback for `key` returning raw bytes, if any, used instead of the callback from
+    /// `lookup` by `RenderBytes`. This is only called for keys which `lookup` found, and always
+    /// matches `key` exactly.
+    ///
+    /// The default implementation returns `None`, so that `RenderBytes` outputs the `String` from
+    /// `lookup` as UTF-8.
+    fn lookup_bytes(&self, key: &str) -> Option<BytesCallback<T>> {
+        let _ = key;
+        None
+    }
+
+    /// Like `lookup_bytes`, but for keys which `lookup_ignore_case` found, so matching the same
+    /// key case-insensitively.
+    ///
+    /// The default implementation only tries `key` as-is with `lookup_bytes`. Implementations
+    /// which override both `lookup_bytes` and `lookup_ignore_case` should override this too.
+    fn lookup_bytes_ignore_case(&self, key: &str) -> Option<BytesCallback<T>> {
+        self.lookup_bytes(key)
+    }
+}
+
+/// Compare two keys case-insensitively: with Unicode case folding if the `unicase` feature is
+/// enabled, and only for ASCII otherwise.
+pub fn keys_eq_ignore_case(a: &str, b: &str) -> bool {
+    #[cfg(feature = "unicase")]
+    {
+        unicase::eq(a, b)
+    }
+    #[cfg(not(feature = "unicase"))]
+    {
+        a.eq_ignore_ascii_case(b)
+    }
+}
+
+/// Find the callback for `key` in an iterator over a map's entries, preferring an exact match,
+/// and otherwise taking the case-insensitive match whose key sorts first, so that the result is
+/// deterministic regardless of iteration order.
+fn find_ignore_case<'a, T: ?Sized + 'a, K: Borrow<str> + 'a>(
+    entries: impl Iterator<Item = (&'a K, &'a FormatterCallback<T>)>,
+    key: &str,
+) -> Option<FormatterCallback<T>> {
+    let mut best: Option<(&str, &FormatterCallback<T>)> = None;
+    for (k, cb) in entries {
+        let k = k.borrow();
+        if k == key {
+            return Some(Arc::clone(cb));
+        }
+        if keys_eq_ignore_case(k, key) && best.map_or(true, |(b, _)| k < b) {
+            best = Some((k, cb));
+        }
+    }
+    best.map(|(_, cb)| Arc::clone(cb))
+}
+
+#[cfg(feature = "std")]
+impl<T: ?Sized, K, S> KeyLookup<T> for HashMap<K, FormatterCallback<T>, S>
+where
+    K: Borrow<str> + Hash + Eq,
+    S: BuildHasher,
+{
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.get(key).cloned()
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.lookup(key)
+            .or_else(|| find_ignore_case(self.iter(), key))
+    }
+}
+
+#[cfg(feature = "hashbrown")]
+impl<T: ?Sized, K, S> KeyLookup<T> for hashbrown::HashMap<K, FormatterCallback<T>, S>
+where
+    K: Borrow<str> + Hash + Eq,
+    S: BuildHasher,
+{
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.get(key).cloned()
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.lookup(key)
+            .or_else(|| find_ignore_case(self.iter(), key))
+    }
+}
+
+#[cfg(feature = "indexmap")]
+impl<T: ?Sized, K, S> KeyLookup<T> for indexmap::IndexMap<K, FormatterCallback<T>, S>
+where
+    K: Borrow<str> + Hash + Eq,
+    S: BuildHasher,
+{
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.get(key).cloned()
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.lookup(key)
+            .or_else(|| find_ignore_case(self.iter(), key))
+    }
+}
+
+impl<T: ?Sized, K> KeyLookup<T> for BTreeMap<K, FormatterCallback<T>>
+where
+    K: Borrow<str> + Ord,
+{
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.get(key).cloned()
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.lookup(key)
+            .or_else(|| find_ignore_case(self.iter(), key))
+    }
+}
+
+/// Look keys up in each element in turn, using the first callback found. This allows combining
+/// several sources of callbacks, such as your own `FormatMap<T>` and the ready-made ones in
+/// `providers`.
+impl<T: ?Sized, A, B> KeyLookup<T> for (A, B)
+where
+    A: KeyLookup<T>,
+    B: KeyLookup<T>,
+{
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.0.lookup(key).or_else(|| self.1.lookup(key))
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        // An exact match in any element is preferred over a case-insensitive one in an earlier one
+        self.lookup(key)
+            .or_else(|| self.0.lookup_ignore_case(key))
+            .or_else(|| self.1.lookup_ignore_case(key))
+    }
+
+    fn lookup_bytes(&self, key: &str) -> Option<BytesCallback<T>> {
+        match self.0.lookup(key) {
+            Some(_) => self.0.lookup_bytes(key),
+            None => self.1.lookup_bytes(key),
+        }
+    }
+
+    fn lookup_bytes_ignore_case(&self, key: &str) -> Option<BytesCallback<T>> {
+        // Follow the element that lookup_ignore_case took the callback from
+        if self.lookup(key).is_some() {
+            self.lookup_bytes(key)
+        } else if self.0.lookup_ignore_case(key).is_some() {
+            self.0.lookup_bytes_ignore_case(key)
+        } else {
+            self.1.lookup_bytes_ignore_case(key)
+        }
+    }
+}
+
+impl<T: ?Sized, A, B, C> KeyLookup<T> for (A, B, C)
+where
+    A: KeyLookup<T>,
+    B: KeyLookup<T>,
+    C: KeyLookup<T>,
+{
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.0
+            .lookup(key)
+            .or_else(|| self.1.lookup(key))
+            .or_else(|| self.2.lookup(key))
+    }
+
+    fn lookup_ignore_case(&self, key: &str) -> Option<FormatterCallback<T>> {
+        self.lookup(key)
+            .or_else(|| self.0.lookup_ignore_case(key))
+            .or_else(|| self.1.lookup_ignore_case(key))
+            .or_else(|| self.2.lookup_ignore_case(key))
+    }
+
+    fn lookup_bytes(&self, key: &str) -> Option<BytesCallback<T>> {
+        if self.0.lookup(key).is_some() {
+            self.0.lookup_bytes(key)
+        } else if self.1.lookup(key).is_some() {
+            self.1.lookup_bytes(key)
+        } else {
+            self.2.lookup_bytes(key)
+        }
+    }
+
+    fn lookup_bytes_ignore_case(&self, key: &str) -> Option<BytesCallback<T>> {
+        if self.lookup(key).is_some() {
+            self.lookup_bytes(key)
+        } else if self.0.lookup_ignore_case(key).is_some() {
+            self.0.lookup_bytes_ignore_case(key)
+        } else if self.1.lookup_ignore_case(key).is_some() {
+            self.1.lookup_bytes_ignore_case(key)
+        } else {
+            self.2.lookup_bytes_ignore_case(key)
+        }
+    }
+}
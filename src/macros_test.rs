@@ -0,0 +1,137 @@
+use super::*;
+use once_cell::sync::Lazy;
+use proptest::prelude::*;
+
+// The macros can't depend on this crate, so they have their own parser, which must agree with ours
+#[path = "../funcfmt-derive/src/parse.rs"]
+mod derive_parse;
+
+static FORMATTERS: Lazy<FormatMap<String>> = Lazy::new(|| {
+    fm! {
+        "foo" => |e| Some(format!("{e} foo {e}")),
+        "bar" => |e| Some(format!("{e} bar {e}")),
+    }
+});
+
+const KEYS: &[&str] = &["foo", "bar"];
+
+#[test]
+fn template_matches_runtime_parse() {
+    macro_rules! check {
+        ($tmpl:literal) => {
+            let fp = template!($tmpl, *FORMATTERS, keys = KEYS).unwrap();
+            assert_eq!(fp, FORMATTERS.to_format_pieces($tmpl).unwrap(), "{}", $tmpl);
+        };
+    }
+    check!("");
+    check!("一");
+    check!("{foo}{bar}");
+    check!("一{foo}二{{bar}}{bar}");
+    check!("}}{{");
+    check!("a}");
+}
+
+#[test]
+fn template_unknown_key_at_runtime() {
+    assert_eq!(
+        template!("{baz}", *FORMATTERS),
+        Err(Error::UnknownKey("baz".into()))
+    );
+}
+
+#[test]
+fn assert_keys_known() {
+    __private::assert_keys_known(KEYS, &["bar", "foo", "bar"]);
+    let res = std::panic::catch_unwind(|| __private::assert_keys_known(KEYS, &["fo"]));
+    assert!(res.is_err());
+}
+
+#[test]
+fn template_trim_markers() {
+    let fp = template!("a \n {- foo -} {{ {- bar}", *FORMATTERS, keys = KEYS).unwrap();
+    assert_eq!(
+        fp,
+        FORMATTERS
+            .to_format_pieces("a \n {- foo -} {{ {- bar}")
+            .unwrap()
+    );
+}
+
+#[test]
+fn template_escaped_keys() {
+    let fmap: FormatMap<String> = fm! {"a{b}\\" => |_| Some("x".to_string())};
+    let fp = template!(r"<{a\{b\}\\}> {{}}", fmap).unwrap();
+    assert_eq!(fp, fmap.to_format_pieces(r"<{a\{b\}\\}> {{}}").unwrap());
+}
+
+#[test]
+fn template_conditionals() {
+    let tmpl = r"{foo?<{bar}>:\:} {bar?a:{foo?b}}";
+    let fp = template!(
+        r"{foo?<{bar}>:\:} {bar?a:{foo?b}}",
+        *FORMATTERS,
+        keys = KEYS
+    )
+    .unwrap();
+    assert_eq!(fp, FORMATTERS.to_format_pieces(tmpl).unwrap());
+    assert_eq!(fp.render(&String::from("x")), Ok("<x bar x> a".to_string()));
+}
+
+#[test]
+fn template_checks_keys_in_branches() {
+    let mut names = Vec::new();
+    derive_parse::used_keys(r"foo?<{bar?{baz}}>:{qux\}}", &mut names).unwrap();
+    assert_eq!(names, vec!["foo", "bar", "baz", "qux}"]);
+}
+
+/// Every key used by `tmpl`, including those nested in conditionals, as the runtime parser sees it.
+fn runtime_keys(tmpl: &str, out: &mut Vec<String>) -> Result<(), Error> {
+    for token in parse_template(tmpl)? {
+        let name = match token {
+            TemplateToken::Key { name, .. } => name,
+            TemplateToken::Verbatim { .. } => continue,
+        };
+        match parse::split_conditional(&name) {
+            Some(cond) => {
+                out.push(cond.key.into_owned());
+                runtime_keys(&cond.then, out)?;
+                runtime_keys(&cond.otherwise, out)?;
+            }
+            None => out.push(name.into_owned()),
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn derive_parser_matches_runtime(tmpl in r"[ab {}\\?:\-]{0,24}") {
+        let ours = parse_template(&tmpl).map(|tokens| {
+            tokens
+                .into_iter()
+                .map(|t| match t {
+                    TemplateToken::Verbatim { text, span } => (false, text.to_string(), span),
+                    TemplateToken::Key { name, span } => (true, name.into_owned(), span),
+                })
+                .collect::<Vec<_>>()
+        });
+        let theirs = derive_parse::tokenize(&tmpl).map(|tokens| {
+            tokens
+                .into_iter()
+                .map(|t| (t.is_key, t.text, t.span))
+                .collect::<Vec<_>>()
+        });
+        prop_assert_eq!(ours.as_ref().ok(), theirs.as_ref().ok());
+        prop_assert_eq!(ours.is_err(), theirs.is_err());
+
+        let (mut ours, mut theirs) = (Vec::new(), Vec::new());
+        let ours = runtime_keys(&tmpl, &mut ours).map(|()| ours);
+        let theirs = derive_parse::tokenize(&tmpl).and_then(|tokens| {
+            for token in tokens.iter().filter(|t| t.is_key) {
+                derive_parse::used_keys(&token.text, &mut theirs)?;
+            }
+            Ok(theirs)
+        });
+        prop_assert_eq!(ours.ok(), theirs.ok());
+    }
+}
@@ -0,0 +1,573 @@
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use crate::parse::is_conditional;
+use crate::{escape_key, BytesCallback, Error, Escaper, FormatterCallback, KeyLookup, KeyString};
+
+/// How line endings in rendered output should be normalized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Leave line endings exactly as they were produced by the template and callbacks.
+    Preserve,
+    /// Normalize all line endings to `\n`.
+    Lf,
+    /// Normalize all line endings to `\r\n`.
+    CrLf,
+}
+
+impl Default for LineEnding {
+    fn default() -> Self {
+        Self::Preserve
+    }
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Preserve | Self::Lf => "\n",
+            Self::CrLf => "\r\n",
+        }
+    }
+}
+
+/// What to do with a newline at the very end of rendered output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingNewline {
+    /// Leave the end of the output as-is.
+    Preserve,
+    /// Append a newline if the output does not already end with one.
+    Ensure,
+    /// Remove a single trailing newline (`\n` or `\r\n`) if present.
+    Strip,
+}
+
+impl Default for TrailingNewline {
+    fn default() -> Self {
+        Self::Preserve
+    }
+}
+
+/// The unit in which `RenderOptions::max_length` is measured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthUnit {
+    /// UTF-8 bytes. Output is never cut in the middle of a character.
+    Bytes,
+    /// Unicode scalar values, as yielded by `str::chars`.
+    Chars,
+    /// Extended grapheme clusters, with the `unicode-segmentation` feature. This is closest to
+    /// what a user perceives as a single character.
+    #[cfg(feature = "unicode-segmentation")]
+    Graphemes,
+}
+
+impl LengthUnit {
+    /// The length of `s` in this unit.
+    fn len(self, s: &str) -> usize {
+        match self {
+            Self::Bytes => s.len(),
+            Self::Chars => s.chars().count(),
+            #[cfg(feature = "unicode-segmentation")]
+            Self::Graphemes => {
+                unicode_segmentation::UnicodeSegmentation::graphemes(s, true).count()
+            }
+        }
+    }
+
+    /// The length in bytes of the longest prefix of `s` which is at most `max` units long.
+    fn prefix_len(self, s: &str, max: usize) -> usize {
+        match self {
+            Self::Bytes => {
+                let mut end = max.min(s.len());
+                while !s.is_char_boundary(end) {
+                    end -= 1;
+                }
+                end
+            }
+            Self::Chars => s.char_indices().nth(max).map_or(s.len(), |(idx, _)| idx),
+            #[cfg(feature = "unicode-segmentation")]
+            Self::Graphemes => unicode_segmentation::UnicodeSegmentation::grapheme_indices(s, true)
+                .nth(max)
+                .map_or(s.len(), |(idx, _)| idx),
+        }
+    }
+}
+
+/// What to do with rendered output which is longer than `RenderOptions::max_length`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Truncation {
+    /// Cut the output at the maximum length.
+    Cut,
+    /// Cut the output so that it fits the maximum length with `…` appended. If the maximum is too
+    /// short to fit even the ellipsis, the output is cut without it.
+    Ellipsis,
+    /// Fail with `Error::TooLong`.
+    Error,
+}
+
+/// The ellipsis appended by `Truncation::Ellipsis`.
+const ELLIPSIS: &str = "\u{2026}";
+
+/// A transformer applied to every callback output during rendering. It is given the key name and
+/// the value produced by the callback, and returns the value to actually write to the output.
+pub type OutputTransformer = Arc<dyn for<'a> Fn(&str, &'a str) -> Cow<'a, str> + Send + Sync>;
+
+/// Options controlling how `Render::render_with` produces its output.
+///
+/// The defaults produce exactly the same output as `Render::render`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, LineEnding, Render, RenderOptions, ToFormatPieces, TrailingNewline};
+///
+/// let fmap = fm!{"foo" => |data| Some(format!("{data}\n"))};
+/// let fp = fmap.to_format_pieces("a\r\n{foo}").unwrap();
+/// let opts = RenderOptions::new()
+///     .line_ending(LineEnding::Lf)
+///     .trailing_newline(TrailingNewline::Strip);
+/// assert_eq!(fp.render_with(&String::from("b"), &opts), Ok("a\nb".to_string()));
+/// ```
+#[derive(Clone, Default)]
+pub struct RenderOptions {
+    line_ending: LineEnding,
+    trailing_newline: TrailingNewline,
+    transformer: Option<OutputTransformer>,
+    escaper: Option<Arc<dyn Escaper>>,
+    #[cfg(feature = "std")]
+    catch_panics: bool,
+    cancel_flag: Option<Arc<AtomicBool>>,
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+    max_length: Option<(usize, LengthUnit, Truncation)>,
+}
+
+impl fmt::Debug for RenderOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RenderOptions");
+        d.field("line_ending", &self.line_ending)
+            .field("trailing_newline", &self.trailing_newline)
+            .field("transformer", &self.transformer.is_some())
+            .field("escaper", &self.escaper.is_some());
+        #[cfg(feature = "std")]
+        d.field("catch_panics", &self.catch_panics);
+        d.field("cancel_flag", &self.cancel_flag);
+        #[cfg(feature = "std")]
+        d.field("deadline", &self.deadline);
+        d.field("max_length", &self.max_length);
+        d.finish()
+    }
+}
+
+impl RenderOptions {
+    /// Create a new set of options with everything at its default.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how line endings are normalized. This applies uniformly to both verbatim template text
+    /// and callback output.
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Set whether a trailing newline is appended to or stripped from the output. Any newline
+    /// appended uses the configured `LineEnding`, or `\n` if line endings are preserved.
+    pub fn trailing_newline(mut self, trailing_newline: TrailingNewline) -> Self {
+        self.trailing_newline = trailing_newline;
+        self
+    }
+
+    /// Set a transformer to be applied to the output of every callback, but not to verbatim
+    /// template text. This is useful for things like sanitizing every substituted value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, Render, RenderOptions, ToFormatPieces};
+    /// use std::borrow::Cow;
+    ///
+    /// let fmap = fm!{"name" => |data: &String| Some(data.clone())};
+    /// let fp = fmap.to_format_pieces("out/{name}.txt").unwrap();
+    /// let opts = RenderOptions::new().transformer(|_key, val: &str| {
+    ///     if val.contains('/') {
+    ///         Cow::Owned(val.replace('/', "_"))
+    ///     } else {
+    ///         Cow::Borrowed(val)
+    ///     }
+    /// });
+    /// let data = String::from("a/b");
+    /// assert_eq!(fp.render_with(&data, &opts), Ok("out/a_b.txt".to_string()));
+    /// ```
+    pub fn transformer<F>(mut self, transformer: F) -> Self
+    where
+        F: for<'a> Fn(&str, &'a str) -> Cow<'a, str> + Send + Sync + 'static,
+    {
+        self.transformer = Some(Arc::new(transformer));
+        self
+    }
+
+    /// Set an escaper to be applied to the output of every callback, but not to verbatim template
+    /// text. If a transformer is also set, the escaper is applied to its result.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, HtmlEscaper, Render, RenderOptions, ToFormatPieces};
+    ///
+    /// let fmap = fm!{"title" => |data: &String| Some(data.clone())};
+    /// let fp = fmap.to_format_pieces("<h1>{title}</h1>").unwrap();
+    /// let opts = RenderOptions::new().escaper(HtmlEscaper);
+    /// let data = String::from("Tom & Jerry");
+    /// assert_eq!(fp.render_with(&data, &opts), Ok("<h1>Tom &amp; Jerry</h1>".to_string()));
+    /// ```
+    pub fn escaper<E: Escaper + 'static>(mut self, escaper: E) -> Self {
+        self.escaper = Some(Arc::new(escaper));
+        self
+    }
+
+    /// Set whether panics in callbacks are caught and turned into `Error::CallbackPanicked`,
+    /// rather than unwinding through the caller. This allows one bad input to fail on its own
+    /// without taking down an entire batch.
+    ///
+    /// This has no effect if panics abort, and the panic hook still runs as usual, so the panic
+    /// message will still be printed unless the hook is replaced.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, Error, Render, RenderOptions, ToFormatPieces};
+    ///
+    /// let fmap = fm!{"bad" => |_: &String| panic!("oh no")};
+    /// let fp = fmap.to_format_pieces("{bad}").unwrap();
+    /// let opts = RenderOptions::new().catch_panics(true);
+    /// let res = fp.render_with(&String::new(), &opts);
+    /// assert_eq!(res, Err(Error::CallbackPanicked("bad".into())));
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn catch_panics(mut self, catch_panics: bool) -> Self {
+        self.catch_panics = catch_panics;
+        self
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn catches_panics(&self) -> bool {
+        self.catch_panics
+    }
+
+    /// Set a flag which, once set to `true` (for example, from another thread), causes rendering
+    /// to stop with `Error::Cancelled`.
+    ///
+    /// The flag is checked before each piece is rendered, so a callback which is already running
+    /// will not be interrupted, but no further callbacks will be called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, Error, Render, RenderOptions, ToFormatPieces};
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let cancel = Arc::new(AtomicBool::new(false));
+    /// let fmap = fm!{"foo" => |data: &String| Some(data.clone())};
+    /// let fp = fmap.to_format_pieces("{foo}").unwrap();
+    /// let opts = RenderOptions::new().cancel_flag(Arc::clone(&cancel));
+    ///
+    /// cancel.store(true, Ordering::Relaxed);
+    /// assert_eq!(fp.render_with(&String::new(), &opts), Err(Error::Cancelled));
+    /// ```
+    pub fn cancel_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel_flag = Some(flag);
+        self
+    }
+
+    /// Set a deadline after which rendering stops with `Error::Cancelled`. Like
+    /// `cancel_flag`, this is checked before each piece is rendered.
+    #[cfg(feature = "std")]
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Limit the output to `max` units long, measured in `unit`, handling longer output according
+    /// to `truncation`.
+    ///
+    /// The limit applies to the entire output, after all other whole-output policies like
+    /// `TrailingNewline`, so a trailing newline may be cut off.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, Error, LengthUnit, Render, RenderOptions, ToFormatPieces, Truncation};
+    ///
+    /// let fmap = fm!{"title" => |data: &String| Some(data.clone())};
+    /// let fp = fmap.to_format_pieces("[{title}]").unwrap();
+    /// let data = String::from("Kind of Blue");
+    ///
+    /// let opts = RenderOptions::new().max_length(8, LengthUnit::Chars, Truncation::Ellipsis);
+    /// assert_eq!(fp.render_with(&data, &opts), Ok("[Kind o…".to_string()));
+    ///
+    /// let opts = RenderOptions::new().max_length(8, LengthUnit::Bytes, Truncation::Error);
+    /// assert_eq!(fp.render_with(&data, &opts), Err(Error::TooLong(14)));
+    /// ```
+    pub fn max_length(mut self, max: usize, unit: LengthUnit, truncation: Truncation) -> Self {
+        self.max_length = Some((max, unit, truncation));
+        self
+    }
+
+    /// Return `Error::Cancelled` if rendering should stop now.
+    pub(crate) fn check_cancelled(&self) -> Result<(), Error> {
+        if let Some(flag) = &self.cancel_flag {
+            if flag.load(Ordering::Relaxed) {
+                return Err(Error::Cancelled);
+            }
+        }
+        #[cfg(feature = "std")]
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(Error::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply any per-value transformations to the output of the callback for `key`.
+    pub(crate) fn transform_output<'a>(&self, key: &str, val: &'a str) -> Cow<'a, str> {
+        let val = match &self.transformer {
+            Some(t) => t(key, val),
+            None => Cow::Borrowed(val),
+        };
+        match (&self.escaper, val) {
+            (None, val) => val,
+            (Some(e), Cow::Borrowed(val)) => e.escape(val),
+            (Some(e), Cow::Owned(val)) => Cow::Owned(e.escape(&val).into_owned()),
+        }
+    }
+
+    /// Apply any whole-output policies to the rendered string.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::TooLong` if the output is too long and `Truncation::Error` is in use. In this case
+    ///   `out` is left with the output before truncation.
+    pub(crate) fn finish(&self, out: &mut String) -> Result<(), Error> {
+        if self.line_ending != LineEnding::Preserve {
+            *out = normalize_line_endings(out, self.line_ending.as_str());
+        }
+
+        match self.trailing_newline {
+            TrailingNewline::Preserve => {}
+            TrailingNewline::Ensure => {
+                if !out.ends_with('\n') {
+                    out.push_str(self.line_ending.as_str());
+                }
+            }
+            TrailingNewline::Strip => {
+                if out.ends_with('\n') {
+                    out.pop();
+                    if out.ends_with('\r') {
+                        out.pop();
+                    }
+                }
+            }
+        }
+
+        if let Some((max, unit, truncation)) = self.max_length {
+            let len = unit.len(out);
+            if len > max {
+                let ellipsis = match truncation {
+                    Truncation::Cut => "",
+                    Truncation::Ellipsis if unit.len(ELLIPSIS) <= max => ELLIPSIS,
+                    Truncation::Ellipsis => "",
+                    Truncation::Error => return Err(Error::TooLong(len)),
+                };
+                out.truncate(unit.prefix_len(out, max - unit.len(ellipsis)));
+                out.push_str(ellipsis);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Rewrite every `\n` and `\r\n` in `s` to `eol`. A lone `\r` is not considered a line ending.
+fn normalize_line_endings(s: &str, eol: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(idx) = rest.find('\n') {
+        let line = &rest[..idx];
+        out.push_str(line.strip_suffix('\r').unwrap_or(line));
+        out.push_str(eol);
+        rest = &rest[idx + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Options controlling how templates are processed, as used by
+/// `ToFormatPieces::to_format_pieces_with` and `CompiledTemplate::compile_with`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, CompileOptions, FormatMap, Render, ToFormatPieces};
+///
+/// let fmap: FormatMap<String> = fm!{"name" => |data: &String| Some(data.clone())};
+/// let opts = CompileOptions::new().case_insensitive(true);
+/// let fp = fmap.to_format_pieces_with("{Name} {NAME}", &opts).unwrap();
+/// assert_eq!(fp.render(&String::from("x")), Ok("x x".to_string()));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CompileOptions {
+    case_insensitive: bool,
+    keep_unknown_keys: bool,
+    strict: bool,
+    trim_keys: bool,
+}
+
+impl CompileOptions {
+    /// Create a new set of options with everything at its default.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether keys are matched against the map case-insensitively, using
+    /// `KeyLookup::lookup_ignore_case`. An exact match is always preferred. The spelling used in
+    /// the template is kept in `Formatter::key`, and so in any error messages.
+    ///
+    /// Only ASCII letters are compared case-insensitively, unless the `unicase` feature is enabled,
+    /// in which case full Unicode case folding is used.
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Set whether keys with no callback in the map are kept as verbatim text, rather than failing
+    /// with `Error::UnknownKey`. The text is the key in brackets (escaped with `escape_key` if
+    /// needed), so the output can be processed again as a template to fill in the remaining keys.
+    /// Escaped brackets elsewhere in the template are still output as single brackets, so they need
+    /// escaping twice (`{{{{`) to survive both passes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, CompileOptions, FormatMap, Render, ToFormatPieces};
+    ///
+    /// let mine: FormatMap<()> = fm!{"user" => |_| Some("cdown".to_string())};
+    /// let opts = CompileOptions::new().keep_unknown_keys(true);
+    /// let fp = mine.to_format_pieces_with("{user} ran {cmd}", &opts).unwrap();
+    /// let first = fp.render(&()).unwrap();
+    /// assert_eq!(first, "cdown ran {cmd}");
+    ///
+    /// let theirs: FormatMap<()> = fm!{"cmd" => |_| Some("ls".to_string())};
+    /// let fp = theirs.to_format_pieces(first).unwrap();
+    /// assert_eq!(fp.render(&()), Ok("cdown ran ls".to_string()));
+    /// ```
+    pub fn keep_unknown_keys(mut self, keep_unknown_keys: bool) -> Self {
+        self.keep_unknown_keys = keep_unknown_keys;
+        self
+    }
+
+    /// Set whether templates are parsed strictly, rejecting constructs which are normally
+    /// tolerated but are almost certainly typos. The exact syntax accepted is given by
+    /// `TEMPLATE_GRAMMAR`. In strict mode, these are errors:
+    ///
+    /// - `Error::EmptyKey` for an empty key, like `{}` or `{?a:b}`
+    /// - `Error::KeyWhitespace` for a key name with leading or trailing whitespace, like
+    ///   `{ foo}`, other than around trim markers
+    /// - `Error::StrayEscape` for a backslash in a key name which doesn't escape `{`, `}`, or `\`
+    /// - `Error::ImbalancedBrackets` for an unescaped `}` at the end of the template
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, CompileOptions, Error, FormatMap, ToFormatPieces};
+    ///
+    /// let fmap: FormatMap<()> = fm!{"" => |_| None};
+    /// let opts = CompileOptions::new().strict(true);
+    /// assert!(fmap.to_format_pieces("{}").is_ok());
+    /// assert_eq!(fmap.to_format_pieces_with("{}", &opts).map(|_| ()), Err(Error::EmptyKey));
+    /// ```
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub(crate) fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Set whether whitespace around key names is ignored, so that `{ foo }` refers to the key
+    /// `foo`, as is common in hand-written templates. For conditionals, whitespace is only removed
+    /// around the condition key, so in `{ foo ? a : b }`, the branches are `" a "` and `" b "`.
+    ///
+    /// Trim markers still need whitespace after or before them, so `{- foo -}` is unaffected.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, CompileOptions, FormatMap, Render, ToFormatPieces};
+    ///
+    /// let fmap: FormatMap<String> = fm!{"name" => |data: &String| Some(data.clone())};
+    /// let opts = CompileOptions::new().trim_keys(true);
+    /// let fp = fmap.to_format_pieces_with("Hello, { name }!", &opts).unwrap();
+    /// assert_eq!(fp.render(&String::from("world")), Ok("Hello, world!".to_string()));
+    /// ```
+    pub fn trim_keys(mut self, trim_keys: bool) -> Self {
+        self.trim_keys = trim_keys;
+        self
+    }
+
+    pub(crate) fn trims_keys(&self) -> bool {
+        self.trim_keys
+    }
+
+    /// Find the callback for `key` in `map`, according to these options.
+    pub(crate) fn lookup<T: ?Sized, L: KeyLookup<T> + ?Sized>(
+        &self,
+        map: &L,
+        key: &str,
+    ) -> Option<FormatterCallback<T>> {
+        if self.case_insensitive {
+            map.lookup_ignore_case(key)
+        } else {
+            map.lookup(key)
+        }
+    }
+
+    /// Find the bytes callback for `key` in `map`, if `lookup` found a callback for it.
+    pub(crate) fn lookup_bytes<T: ?Sized, L: KeyLookup<T> + ?Sized>(
+        &self,
+        map: &L,
+        key: &str,
+    ) -> Option<BytesCallback<T>> {
+        if self.case_insensitive {
+            map.lookup_bytes_ignore_case(key)
+        } else {
+            map.lookup_bytes(key)
+        }
+    }
+
+    /// The verbatim text to use for `key`, which has no callback, or `None` if unknown keys
+    /// aren't being kept.
+    pub(crate) fn unknown_key_text(&self, key: &str) -> Option<KeyString> {
+        if !self.keep_unknown_keys {
+            return None;
+        }
+        let mut out = KeyString::new();
+        out.push('{');
+        // Conditionals are kept exactly as written, so they are already escaped
+        if is_conditional(key) {
+            out.push_str(key);
+        } else {
+            out.push_str(&escape_key(key));
+        }
+        out.push('}');
+        Some(out)
+    }
+}
@@ -0,0 +1,446 @@
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+use memchr::{memchr2, memchr3};
+
+use crate::{CompileOptions, Error};
+
+/// A single lexical element of a template, as returned by `parse_template`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplateToken<'a> {
+    /// Plain text to be output as-is. Escaped brackets have already been unescaped.
+    Verbatim {
+        /// The text itself.
+        text: &'a str,
+        /// The byte range in the template that the text came from.
+        span: Range<usize>,
+    },
+    /// A key to be replaced with the output of its callback.
+    Key {
+        /// The name of the key, without the surrounding brackets or any trim markers. Escaped
+        /// characters have already been unescaped, except in conditionals like `{key?then:else}`,
+        /// where the name is the entire conditional exactly as written.
+        name: Cow<'a, str>,
+        /// The byte range in the template that the key came from, including the brackets.
+        span: Range<usize>,
+    },
+}
+
+impl TemplateToken<'_> {
+    /// The byte range in the template that this token came from.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Self::Verbatim { span, .. } | Self::Key { span, .. } => span.clone(),
+        }
+    }
+}
+
+/// Split a template into verbatim text and keys, without resolving keys against any
+/// `FormatMap<T>`.
+///
+/// This accepts exactly the same syntax as `ToFormatPieces::to_format_pieces`, so it can be used
+/// by tooling to validate templates or list the keys they use before any callbacks exist.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{parse_template, TemplateToken};
+///
+/// let tokens = parse_template("ab{foo}{{e}}").unwrap();
+/// let keys: Vec<_> = tokens
+///     .iter()
+///     .filter_map(|t| match t {
+///         TemplateToken::Key { name, .. } => Some(name.as_ref()),
+///         TemplateToken::Verbatim { .. } => None,
+///     })
+///     .collect();
+/// assert_eq!(keys, vec!["foo"]);
+/// assert_eq!(tokens[1], TemplateToken::Key { name: "foo".into(), span: 2..7 });
+/// ```
+///
+/// # Errors
+///
+/// - `Error::ImbalancedBrackets` if `tmpl` contains imbalanced brackets (use `{{` and `}}` to
+///   escape)
+pub fn parse_template(tmpl: &str) -> Result<Vec<TemplateToken<'_>>, Error> {
+    parse_template_with(tmpl, &CompileOptions::default())
+}
+
+/// Like `parse_template`, but with the syntax accepted depending on `opts`, so that templates can
+/// be validated with `CompileOptions::strict`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{parse_template_with, CompileOptions, Error};
+///
+/// let strict = CompileOptions::new().strict(true);
+/// assert!(parse_template_with("{foo}", &strict).is_ok());
+/// assert_eq!(parse_template_with("{foo }", &strict), Err(Error::KeyWhitespace("foo ".into())));
+/// ```
+///
+/// # Errors
+///
+/// The same as for `parse_template`, and in strict mode, those listed for
+/// `CompileOptions::strict`.
+pub fn parse_template_with<'a>(
+    tmpl: &'a str,
+    opts: &CompileOptions,
+) -> Result<Vec<TemplateToken<'a>>, Error> {
+    let mut out = Vec::new();
+    tokenize(tmpl, opts, |token| {
+        out.push(token);
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+/// The template syntax accepted in strict mode, in EBNF. See `CompileOptions::strict`.
+///
+/// Outside of strict mode, the syntax is extended to also accept empty keys, key names with
+/// leading or trailing whitespace, a backslash which doesn't escape anything (which is literal),
+/// and a single unescaped `}` at the very end of the template (which is also literal).
+pub const TEMPLATE_GRAMMAR: &str = r#"template     = { text | "{{" | "}}" | key } ;
+text         = char - ( "{" | "}" ) , { char - ( "{" | "}" ) } ;
+key          = "{" , [ "-" , ws ] , name , [ "?" , branch , [ ":" , branch ] ] , [ ws , "-" ] , "}" ;
+name         = name_char , [ { name_char | ws } , name_char ] ;
+name_char    = ( char - ( ws | "{" | "}" | "\" | "?" ) ) | "\{" | "\}" | "\\" ;
+branch       = { ( char - ( "{" | "}" | "\" | ":" ) ) | "\{" | "\}" | "\:" | "\?" | "\\" | key } ;
+ws           = white_space , { white_space } ;
+(* In the first branch of a conditional, an unescaped ":" starts the second branch. In the
+   second branch, ":" is literal. *)
+"#;
+
+/// Split `tmpl` into verbatim text and keys, calling `sink` with each token in order.
+///
+/// Only `{` and `}` are structurally significant, and both are ASCII, so we jump between them with
+/// `memchr2` and slice the verbatim runs in between wholesale. Since ASCII bytes never appear
+/// inside a multi-byte UTF-8 sequence, every index found this way is at a character boundary.
+///
+/// Empty verbatim runs (for example, between two adjacent keys) are not emitted.
+///
+/// Of `opts`, only `CompileOptions::strict` and `CompileOptions::trim_keys` affect tokenizing.
+pub(crate) fn tokenize<'a>(
+    tmpl: &'a str,
+    opts: &CompileOptions,
+    mut sink: impl FnMut(TemplateToken<'a>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let bytes = tmpl.as_bytes();
+    let mut verb_start = 0;
+    let mut pos = 0;
+    // Whether the previous key asked for whitespace at the start of this verbatim run to be trimmed
+    let mut trim_next = false;
+
+    // All arithmetic below is on indices within tmpl, which can never exceed isize::MAX, so adding
+    // the small constants used here can't overflow.
+    macro_rules! push_verb {
+        ($end:expr, $trim_end:expr) => {
+            // SAFETY: Both ends are either at the start or end of tmpl, or next to an ASCII
+            // bracket found by memchr2, and so are at character boundaries.
+            let mut text = unsafe { tmpl.get_unchecked(verb_start..$end) };
+            let mut start = verb_start;
+            if core::mem::take(&mut trim_next) {
+                let trimmed = text.trim_start();
+                start += text.len() - trimmed.len();
+                text = trimmed;
+            }
+            if $trim_end {
+                text = text.trim_end();
+            }
+            if !text.is_empty() {
+                sink(TemplateToken::Verbatim {
+                    text,
+                    span: start..start + text.len(),
+                })?;
+            }
+        };
+    }
+
+    while let Some(off) = memchr2(b'{', b'}', &bytes[pos..]) {
+        let idx = pos + off;
+        let next = bytes.get(idx + 1).copied();
+
+        match (bytes[idx], next) {
+            // Escaped bracket: emit everything up to and excluding the first bracket, and start the
+            // next verbatim run at the second one.
+            (b'{', Some(b'{')) | (b'}', Some(b'}')) => {
+                push_verb!(idx, false);
+                verb_start = idx + 1;
+                pos = idx + 2;
+            }
+            (b'{', _) => {
+                let key_start = idx + 1;
+                let (key_end, escaped, conditional) = find_key_end(bytes, key_start)?;
+                // SAFETY: key_start is just after an ASCII bracket, and key_end is at one.
+                let (name, trim_before, trim_after) =
+                    split_trim_markers(unsafe { tmpl.get_unchecked(key_start..key_end) });
+                let name = if opts.trims_keys() {
+                    trim_key(name)
+                } else {
+                    Cow::Borrowed(name)
+                };
+                if opts.is_strict() {
+                    check_strict_key(&name, conditional, opts)?;
+                }
+                push_verb!(idx, trim_before);
+                let name = if escaped && !conditional {
+                    Cow::Owned(unescape_key(&name))
+                } else {
+                    name
+                };
+                sink(TemplateToken::Key {
+                    name,
+                    span: idx..key_end + 1,
+                })?;
+                trim_next = trim_after;
+                verb_start = key_end + 1;
+                pos = verb_start;
+            }
+            // A lone closing bracket is tolerated as literal text at the very end of the template,
+            // but is imbalanced anywhere else.
+            (_, None) if !opts.is_strict() => break,
+            _ => return Err(Error::ImbalancedBrackets),
+        }
+    }
+
+    push_verb!(tmpl.len(), false);
+
+    Ok(())
+}
+
+/// Split trim markers from the contents of a key, returning the key name and whether whitespace
+/// before and after the key should be trimmed.
+///
+/// A marker is a `-` separated from the key name by whitespace, like `{- key -}`. Requiring the
+/// whitespace means that key names which merely start or end with `-` are unaffected.
+pub(crate) fn split_trim_markers(raw: &str) -> (&str, bool, bool) {
+    let (name, before) = match raw.strip_prefix('-') {
+        Some(rest) if rest.starts_with(char::is_whitespace) => (rest.trim_start(), true),
+        _ => (raw, false),
+    };
+    match name.strip_suffix('-') {
+        Some(rest) if rest.ends_with(char::is_whitespace) => (rest.trim_end(), before, true),
+        _ => (name, before, false),
+    }
+}
+
+/// Remove whitespace around a key name, as for `CompileOptions::trim_keys`. For conditionals, this
+/// is whitespace around the condition key, since whitespace in the branches is literal text.
+fn trim_key(name: &str) -> Cow<'_, str> {
+    let name = name.trim_start();
+    match find_condition(name.as_bytes()) {
+        Some(q) if name[..q].ends_with(char::is_whitespace) => {
+            Cow::Owned([name[..q].trim_end(), &name[q..]].concat())
+        }
+        Some(_) => Cow::Borrowed(name),
+        None => Cow::Borrowed(name.trim_end()),
+    }
+}
+
+/// Reject key names which are accepted, but are likely mistakes. `raw` is the name as written,
+/// without trim markers. For conditionals, the condition key is checked, as are any keys nested
+/// in the branches.
+fn check_strict_key(raw: &str, conditional: bool, opts: &CompileOptions) -> Result<(), Error> {
+    let key = match find_condition(raw.as_bytes()) {
+        Some(q) if conditional => {
+            if let Some(cond) = split_conditional(raw) {
+                tokenize(&cond.then, opts, |_| Ok(()))?;
+                tokenize(&cond.otherwise, opts, |_| Ok(()))?;
+            }
+            &raw[..q]
+        }
+        _ => raw,
+    };
+    if key.is_empty() {
+        return Err(Error::EmptyKey);
+    }
+    if key.trim() != key {
+        return Err(Error::KeyWhitespace(key.into()));
+    }
+    let bytes = key.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if bytes.get(i + 1).map_or(false, |&b| is_key_escapable(b)) => i += 2,
+            b'\\' => return Err(Error::StrayEscape(key.into())),
+            _ => i += 1,
+        }
+    }
+    Ok(())
+}
+
+/// Characters which must be escaped with a backslash to appear in a key name.
+fn is_key_escapable(b: u8) -> bool {
+    matches!(b, b'{' | b'}' | b'\\')
+}
+
+/// Find the closing bracket of a key starting at `start`, skipping over backslash escapes, and
+/// over nested keys in the branches of a conditional. Returns its index, whether any escapes were
+/// seen, and whether the key is a conditional.
+fn find_key_end(bytes: &[u8], start: usize) -> Result<(usize, bool, bool), Error> {
+    let mut pos = start;
+    let mut escaped = false;
+    let mut depth = 0usize;
+    while let Some(off) = memchr3(b'{', b'}', b'\\', &bytes[pos..]) {
+        let idx = pos + off;
+        match bytes[idx] {
+            b'}' if depth == 0 => {
+                let conditional = find_condition(&bytes[start..idx]).is_some();
+                return Ok((idx, escaped, conditional));
+            }
+            b'}' => {
+                depth -= 1;
+                pos = idx + 1;
+            }
+            // Brackets may only nest in the branches of a conditional
+            b'{' if depth > 0 || find_condition(&bytes[start..idx]).is_some() => {
+                depth += 1;
+                pos = idx + 1;
+            }
+            b'{' => return Err(Error::ImbalancedBrackets),
+            _ => match bytes.get(idx + 1) {
+                Some(&next) if is_key_escapable(next) => {
+                    escaped = true;
+                    pos = idx + 2;
+                }
+                // A backslash before anything else is literal
+                _ => pos = idx + 1,
+            },
+        }
+    }
+    Err(Error::ImbalancedBrackets)
+}
+
+/// The index of the first `?` in a key which isn't escaped with a backslash, if any. Such a key is
+/// a conditional, like `{key?then:else}`.
+pub(crate) fn find_condition(key: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < key.len() {
+        match key[i] {
+            b'\\' => i += 2,
+            b'?' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Whether `name`, as found in `TemplateToken::Key`, is a conditional.
+pub(crate) fn is_conditional(name: &str) -> bool {
+    find_condition(name.as_bytes()).is_some()
+}
+
+/// The parts of a conditional key like `{key?then:else}`.
+pub(crate) struct Conditional<'a> {
+    /// The key whose callback decides which branch is used.
+    pub key: Cow<'a, str>,
+    /// The template to use if the key's callback produces data.
+    pub then: String,
+    /// The template to use otherwise.
+    pub otherwise: String,
+}
+
+/// Split a conditional key into its parts, or return `None` if `name` isn't a conditional.
+///
+/// The branches are converted to regular template syntax: nested keys are kept as they are, and
+/// outside of them, `\{`, `\}`, `\:`, `\?`, and `\\` are unescaped into literal text.
+pub(crate) fn split_conditional(name: &str) -> Option<Conditional<'_>> {
+    let q = find_condition(name.as_bytes())?;
+    let key = &name[..q];
+    let key = if key.contains('\\') {
+        Cow::Owned(unescape_key(key))
+    } else {
+        Cow::Borrowed(key)
+    };
+
+    let mut branches = [String::new(), String::new()];
+    let mut cur = 0;
+    let mut depth = 0usize;
+    let mut chars = name[q + 1..].chars();
+    while let Some(c) = chars.next() {
+        let out = &mut branches[cur];
+        if depth > 0 {
+            // Inside a nested key, copy everything as-is for the nested key to handle
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '\\' => match chars.next() {
+                Some('{') => out.push_str("{{"),
+                Some('}') => out.push_str("}}"),
+                Some(next @ (':' | '?' | '\\')) => out.push(next),
+                Some(next) => {
+                    out.push('\\');
+                    out.push(next);
+                }
+                None => out.push('\\'),
+            },
+            '{' => {
+                depth += 1;
+                out.push(c);
+            }
+            ':' if cur == 0 => cur = 1,
+            _ => out.push(c),
+        }
+    }
+
+    let [then, otherwise] = branches;
+    Some(Conditional {
+        key,
+        then,
+        otherwise,
+    })
+}
+
+/// Remove backslash escapes from a key name.
+fn unescape_key(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if next.is_ascii() && is_key_escapable(next as u8) {
+                    out.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Escape `name` so that it can be used as a key in a template, even if it contains brackets or
+/// backslashes. If no escaping is needed, it is returned unmodified without allocating.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{escape_key, fm, FormatMap, Render, ToFormatPieces};
+///
+/// let key = "a{b}";
+/// let fmap: FormatMap<()> = fm!{key => |_| Some("x".to_string())};
+/// let fp = fmap.to_format_pieces(format!("<{{{}}}>", escape_key(key))).unwrap();
+/// assert_eq!(fp.render(&()), Ok("<x>".to_string()));
+/// ```
+pub fn escape_key(name: &str) -> Cow<'_, str> {
+    if !name.bytes().any(is_key_escapable) {
+        return Cow::Borrowed(name);
+    }
+    let mut out = String::with_capacity(name.len().saturating_add(4));
+    for c in name.chars() {
+        if c.is_ascii() && is_key_escapable(c as u8) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    Cow::Owned(out)
+}
@@ -0,0 +1,91 @@
+//! Ready-made callbacks for data which doesn't come from the value being rendered, such as
+//! environment variables, the current time, or details about the running process.
+//!
+//! These are generic over the data type, so they can be combined with your own `FormatMap<T>`
+//! using a tuple, which looks keys up in each element in turn.
+//!
+//! # Example
+//!
+//! ```
+//! use funcfmt::providers::{env_formatters, process_formatters};
+//! use funcfmt::{fm, FormatMap, Render, ToFormatPieces};
+//!
+//! std::env::set_var("FUNCFMT_EXAMPLE", "hi");
+//! let fmap: FormatMap<String> = fm!{"msg" => |data: &String| Some(data.clone())};
+//! let lookup = (fmap, env_formatters(), process_formatters());
+//! let fp = lookup.to_format_pieces("{env.FUNCFMT_EXAMPLE} {msg} from {pid}").unwrap();
+//! let out = fp.render(&String::from("there")).unwrap();
+//! assert_eq!(out, format!("hi there from {}", std::process::id()));
+//! ```
+
+use alloc::string::ToString;
+use alloc::sync::Arc;
+#[cfg(feature = "time")]
+use time::format_description::{self, well_known::Rfc3339, OwnedFormatItem};
+#[cfg(feature = "time")]
+use time::OffsetDateTime;
+
+use crate::{FormatMap, FormatterCallback, KeyLookup};
+
+/// Callbacks for environment variables, as returned by `env_formatters`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EnvFormatters;
+
+/// Callbacks for environment variables, with keys like `{env.HOME}`.
+///
+/// Variables are read when rendering, not when the template is processed. Variables which are
+/// unset or not valid Unicode produce no data.
+pub fn env_formatters() -> EnvFormatters {
+    EnvFormatters
+}
+
+impl<T> KeyLookup<T> for EnvFormatters {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        let name = key.strip_prefix("env.")?.to_string();
+        Some(Arc::new(move |_| std::env::var(&name).ok()))
+    }
+}
+
+/// Callbacks for the current date and time, as returned by `datetime_formatters`.
+#[cfg(feature = "time")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DateTimeFormatters;
+
+/// Callbacks for the current local date and time, with keys like `{now:%Y-%m-%d}` using
+/// `strftime` format specifiers, or just `{now}` for RFC 3339.
+///
+/// The time is read when rendering. If the local time zone can't be determined, UTC is used.
+/// Keys with invalid format specifiers are unknown.
+#[cfg(feature = "time")]
+pub fn datetime_formatters() -> DateTimeFormatters {
+    DateTimeFormatters
+}
+
+#[cfg(feature = "time")]
+fn now() -> OffsetDateTime {
+    OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc())
+}
+
+#[cfg(feature = "time")]
+impl<T> KeyLookup<T> for DateTimeFormatters {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        if key == "now" {
+            return Some(Arc::new(|_| now().format(&Rfc3339).ok()));
+        }
+        let fmt = key.strip_prefix("now:")?;
+        let items: OwnedFormatItem = format_description::parse_strftime_owned(fmt).ok()?;
+        Some(Arc::new(move |_| now().format(&items).ok()))
+    }
+}
+
+/// Callbacks for details of the running process: `{pid}` for the process ID, and `{hostname}` for
+/// the name of the host it is running on.
+pub fn process_formatters<T>() -> FormatMap<T> {
+    let mut map = FormatMap::default();
+    let pid: FormatterCallback<T> = Arc::new(|_| Some(std::process::id().to_string()));
+    map.insert("pid".into(), pid);
+    let hostname: FormatterCallback<T> =
+        Arc::new(|_| hostname::get().ok().and_then(|h| h.into_string().ok()));
+    map.insert("hostname".into(), hostname);
+    map
+}
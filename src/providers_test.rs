@@ -0,0 +1,52 @@
+use super::*;
+use providers::{env_formatters, process_formatters};
+
+#[test]
+fn env_provider() {
+    std::env::set_var("FUNCFMT_TEST_ENV", "val");
+    let fp = env_formatters()
+        .to_format_pieces("{env.FUNCFMT_TEST_ENV}|")
+        .unwrap();
+    assert_eq!(fp.render(&()), Ok("val|".to_string()));
+
+    // Read at render time, not processing time
+    std::env::set_var("FUNCFMT_TEST_ENV", "new");
+    assert_eq!(fp.render(&()), Ok("new|".to_string()));
+
+    let fp = env_formatters()
+        .to_format_pieces("{env.FUNCFMT_TEST_UNSET}")
+        .unwrap();
+    assert_eq!(
+        fp.render(&()),
+        Err(Error::NoData("env.FUNCFMT_TEST_UNSET".into()))
+    );
+    assert!(KeyLookup::<()>::lookup(&env_formatters(), "HOME").is_none());
+}
+
+#[test]
+fn tuple_lookup_order() {
+    let mine: FormatMap<()> = fm! {"pid" => |_| Some("mine".to_string())};
+    let fp = (mine, process_formatters(), env_formatters())
+        .to_format_pieces("{pid} {hostname}")
+        .unwrap();
+    let out = fp.render(&()).unwrap();
+    assert!(out.starts_with("mine "), "{out}");
+    assert!(out.len() > "mine ".len());
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn datetime_provider() {
+    use providers::datetime_formatters;
+
+    let fp = datetime_formatters()
+        .to_format_pieces("{now:%Y}|{now}")
+        .unwrap();
+    let out = fp.render(&()).unwrap();
+    let (year, rfc3339) = out.split_once('|').unwrap();
+    assert_eq!(year.len(), 4);
+    assert!(rfc3339.starts_with(year), "{out}");
+
+    let invalid = KeyLookup::<()>::lookup(&datetime_formatters(), "now:%Q");
+    assert!(invalid.is_none());
+}
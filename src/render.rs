@@ -0,0 +1,704 @@
+use alloc::string::String;
+#[cfg(feature = "unicode-width")]
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+use core::ops::ControlFlow;
+use core::time::Duration;
+
+use crate::{
+    BytesFormatter, Conditional, Error, FormatPiece, FormatPieces, Formatter, KeyString,
+    RenderOptions, RenderStats,
+};
+
+/// A trait for rendering format pieces into a resulting `String`, given some input data to the
+/// callbacks.
+pub trait Render<T: ?Sized> {
+    /// Given some data, render the given format pieces into a `String`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{FormatMap, ToFormatPieces, Render, fm};
+    ///
+    /// let fmap = fm!{"foo" => |data| Some(format!("b{data}d"))};
+    /// let fp = fmap.to_format_pieces("a{foo}e").unwrap();
+    /// let data = String::from("c");
+    /// assert_eq!(fp.render(&data), Ok("abcde".to_string()));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// - `Error::NoData` if the callback returns `None`
+    /// - `Error::Overflow` if internal string capacity calculation overflows
+    /// - `Error::Write` if writing to the output `String` fails
+    fn render(&self, data: &T) -> Result<String, Error>;
+
+    /// Like `render`, but with the output shaped by the given `RenderOptions`.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `render`.
+    fn render_with(&self, data: &T, opts: &RenderOptions) -> Result<String, Error>;
+
+    /// Like `render_with`, but also return the display width contributed by each piece, as
+    /// measured by `unicode-width`. This allows truncating or padding the output to fit a terminal
+    /// without having to measure and split it again.
+    ///
+    /// The widths always add up to the width of the final output. Normalizing line endings with
+    /// `LineEnding` doesn't change them, since `\\r\\n` has the same width as `\\n`. A newline added
+    /// by `TrailingNewline::Ensure` is counted in the last piece. If the output is shortened by
+    /// `TrailingNewline::Strip` or `RenderOptions::max_length`, widths are cut back from the end to
+    /// describe only what remains, and any ellipsis is counted in the piece that was cut.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, Render, RenderOptions, ToFormatPieces};
+    ///
+    /// let fmap = fm!{"foo" => |data: &String| Some(data.clone())};
+    /// let fp = fmap.to_format_pieces("[{foo}]").unwrap();
+    /// let data = String::from("日本");
+    /// let rw = fp.render_with_widths(&data, &RenderOptions::default()).unwrap();
+    /// assert_eq!(rw.output, "[日本]");
+    /// assert_eq!(&rw.widths[..], &[1, 4, 1]);
+    /// assert_eq!(rw.total_width(), 6);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// The same as for `render`.
+    #[cfg(feature = "unicode-width")]
+    fn render_with_widths(&self, data: &T, opts: &RenderOptions) -> Result<RenderedWidths, Error>;
+
+    /// Return an adapter which renders directly into a `fmt::Formatter` when displayed, so that
+    /// output can be embedded in `write!` or `format_args!` without first building a `String`.
+    ///
+    /// Since `fmt::Display` can only fail with `fmt::Error`, the real `Error` is stashed and can
+    /// be retrieved with `RenderDisplay::take_error`.
+    ///
+    /// `format!` and `to_string` panic if `fmt` fails, so only use them when no callback can
+    /// return `None`. Otherwise, use `write!` and check its result, or use `render` instead.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, Error, Render, ToFormatPieces};
+    /// use std::fmt::Write;
+    ///
+    /// let fmap = fm!{"foo" => |data: &String| Some(data.clone()), "none" => |_| None};
+    /// let data = String::from("c");
+    ///
+    /// let fp = fmap.to_format_pieces("b{foo}d").unwrap();
+    /// assert_eq!(format!("a{}e", fp.display(&data)), "abcde");
+    ///
+    /// let fp = fmap.to_format_pieces("{none}").unwrap();
+    /// let disp = fp.display(&data);
+    /// let mut out = String::new();
+    /// assert!(write!(out, "{disp}").is_err());
+    /// assert_eq!(disp.take_error(), Some(Error::NoData("none".into())));
+    /// ```
+    fn display<'a>(&'a self, data: &'a T) -> RenderDisplay<'a, T>;
+
+    /// Like `render_with`, but also record how often each callback was called, how often it
+    /// produced no data, and how long it took into `stats`. Statistics accumulate across calls,
+    /// so the same `RenderStats` can be passed to many renders to profile an entire batch.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `render`.
+    fn render_with_stats(
+        &self,
+        data: &T,
+        opts: &RenderOptions,
+        stats: &mut RenderStats,
+    ) -> Result<String, Error>;
+
+    /// Like `render_with`, but on failure, return a `RenderError` including the output rendered
+    /// up to the failing piece, that piece's index, and its key, so that callers can show how far
+    /// rendering got.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, Error, Render, RenderOptions, ToFormatPieces};
+    ///
+    /// let fmap = fm!{"foo" => |data: &String| Some(data.clone()), "iso" => |_| None};
+    /// let fp = fmap.to_format_pieces("{foo} at {iso}!").unwrap();
+    /// let err = fp
+    ///     .render_partial(&String::from("x"), &RenderOptions::default())
+    ///     .unwrap_err();
+    ///
+    /// assert_eq!(err.partial, "x at ");
+    /// assert_eq!(err.piece, Some(2));
+    /// assert_eq!(err.key.as_deref(), Some("iso"));
+    /// assert_eq!(err.error, Error::NoData("iso".into()));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// The same as for `render`, wrapped in a `RenderError`.
+    fn render_partial(&self, data: &T, opts: &RenderOptions) -> Result<String, RenderError>;
+}
+
+/// A rendering failure along with the output produced before it, as returned by
+/// `Render::render_partial`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RenderError {
+    /// The underlying error.
+    pub error: Error,
+    /// Everything rendered before the failure. Unless the failure was `Error::TooLong`,
+    /// whole-output options like `TrailingNewline` have not been applied.
+    pub partial: String,
+    /// The index of the piece which failed, or `None` if rendering failed outside of any piece,
+    /// such as when the whole output is too long.
+    pub piece: Option<usize>,
+    /// The key of the piece which failed, if it was a formatter.
+    pub key: Option<KeyString>,
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        if let Some(piece) = self.piece {
+            write!(
+                f,
+                " at piece {piece}, after {} bytes of output",
+                self.partial.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RenderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<RenderError> for Error {
+    fn from(err: RenderError) -> Self {
+        err.error
+    }
+}
+
+/// Rendered output along with the display width contributed by each piece, as returned by
+/// `Render::render_with_widths`.
+#[cfg(feature = "unicode-width")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenderedWidths {
+    /// The rendered output.
+    pub output: String,
+    /// The display width of each piece, in the same order as the `FormatPieces<T>`. The width of a
+    /// conditional is the total width of the branch it rendered.
+    pub widths: Vec<usize>,
+}
+
+#[cfg(feature = "unicode-width")]
+impl RenderedWidths {
+    /// The display width of the entire output.
+    pub fn total_width(&self) -> usize {
+        self.widths.iter().sum()
+    }
+}
+
+/// A chunk of rendered output, as passed to the visitor of `Walk::walk`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PieceOutput<'a> {
+    /// Verbatim text from the template.
+    Verbatim(&'a str),
+    /// The output of the callback for `key`.
+    Formatter { key: &'a str, output: &'a str },
+}
+
+impl<'a> PieceOutput<'a> {
+    /// The text this chunk contributes to the output.
+    pub fn text(&self) -> &'a str {
+        match *self {
+            Self::Verbatim(text) => text,
+            Self::Formatter { output, .. } => output,
+        }
+    }
+}
+
+/// A trait for rendering format pieces chunk by chunk, handing each to a visitor rather than
+/// building a `String`. This allows assembling output in custom ways, such as styling callback
+/// output differently from verbatim text.
+pub trait Walk<T: ?Sized> {
+    /// Render with `data`, calling `visitor` with each chunk of output in order. The branches of
+    /// conditionals are walked like any other pieces. If `visitor` returns `ControlFlow::Break`,
+    /// walking stops and its value is returned.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use funcfmt::{fm, PieceOutput, ToFormatPieces, Walk};
+    /// use std::ops::ControlFlow;
+    ///
+    /// let fmap = fm!{"name" => |data: &String| Some(data.clone())};
+    /// let fp = fmap.to_format_pieces("Hello, {name}!").unwrap();
+    ///
+    /// let mut out = String::new();
+    /// let res = fp.walk(&String::from("world"), |chunk| {
+    ///     match chunk {
+    ///         PieceOutput::Verbatim(text) => out.push_str(text),
+    ///         PieceOutput::Formatter { output, .. } => {
+    ///             out.push_str(&format!("\x1b[1m{output}\x1b[0m"))
+    ///         }
+    ///     }
+    ///     ControlFlow::<()>::Continue(())
+    /// });
+    /// assert_eq!(res, Ok(ControlFlow::Continue(())));
+    /// assert_eq!(out, "Hello, \x1b[1mworld\x1b[0m!");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// The same as for `Render::render`.
+    fn walk<B, F>(&self, data: &T, visitor: F) -> Result<ControlFlow<B>, Error>
+    where
+        F: FnMut(PieceOutput<'_>) -> ControlFlow<B>;
+}
+
+pub(crate) fn walk<T: ?Sized, P, B, F>(
+    pieces: &P,
+    data: &T,
+    mut visitor: F,
+) -> Result<ControlFlow<B>, Error>
+where
+    P: PieceSource<T> + ?Sized,
+    F: FnMut(PieceOutput<'_>) -> ControlFlow<B>,
+{
+    let res = render_pieces(
+        pieces,
+        data,
+        &RenderOptions::default(),
+        None,
+        |chunk| match visitor(chunk) {
+            ControlFlow::Continue(()) => Ok(()),
+            ControlFlow::Break(b) => Err(WalkStop::Break(b)),
+        },
+    );
+    match res {
+        Ok(()) => Ok(ControlFlow::Continue(())),
+        Err(WalkStop::Break(b)) => Ok(ControlFlow::Break(b)),
+        Err(WalkStop::Error(err)) => Err(err),
+    }
+}
+
+/// Why `walk` stopped early, so that the visitor breaking can't be mistaken for a real error.
+enum WalkStop<B> {
+    Break(B),
+    Error(Error),
+}
+
+impl<B> From<Error> for WalkStop<B> {
+    fn from(err: Error) -> Self {
+        Self::Error(err)
+    }
+}
+
+/// A borrowed view of a single piece, independent of how the pieces are stored.
+pub(crate) enum PieceRef<'a, T: ?Sized> {
+    Verbatim(&'a str),
+    Formatter(&'a Formatter<T>),
+    Conditional(&'a Conditional<T>),
+    Bytes(&'a BytesFormatter<T>),
+}
+
+impl<'a, T: ?Sized> From<&'a FormatPiece<T>> for PieceRef<'a, T> {
+    fn from(piece: &'a FormatPiece<T>) -> Self {
+        match piece {
+            FormatPiece::Verbatim(s) => Self::Verbatim(s),
+            FormatPiece::Formatter(f) => Self::Formatter(f),
+            FormatPiece::Conditional(c) => Self::Conditional(c),
+            FormatPiece::Bytes(b) => Self::Bytes(b),
+        }
+    }
+}
+
+/// Anything which can be rendered as an ordered sequence of pieces.
+pub(crate) trait PieceSource<T: ?Sized> {
+    /// The number of pieces.
+    fn piece_count(&self) -> usize;
+
+    /// The piece at `idx`, which must be less than `piece_count()`.
+    fn piece(&self, idx: usize) -> PieceRef<'_, T>;
+
+    /// The capacity to allocate for the rendered output.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::Overflow` if the capacity calculation overflows
+    fn capacity_hint(&self) -> Result<usize, Error> {
+        // Ballpark guess large enough to usually avoid extra allocations
+        self.piece_count().checked_mul(16).ok_or(Error::Overflow)
+    }
+}
+
+/// The capacity to allocate for rendering `pieces`: all of their verbatim text, which is known
+/// exactly, plus a guess for the output of each keyed piece.
+fn pieces_capacity<T: ?Sized>(pieces: &[FormatPiece<T>]) -> Result<usize, Error> {
+    pieces
+        .iter()
+        .try_fold(0usize, |acc, piece| match piece {
+            FormatPiece::Verbatim(s) => acc.checked_add(s.len()),
+            _ => acc.checked_add(16),
+        })
+        .ok_or(Error::Overflow)
+}
+
+impl<T: ?Sized> PieceSource<T> for [FormatPiece<T>] {
+    fn piece_count(&self) -> usize {
+        self.len()
+    }
+
+    fn piece(&self, idx: usize) -> PieceRef<'_, T> {
+        (&self[idx]).into()
+    }
+
+    fn capacity_hint(&self) -> Result<usize, Error> {
+        pieces_capacity(self)
+    }
+}
+
+impl<T: ?Sized> PieceSource<T> for FormatPieces<T> {
+    fn piece_count(&self) -> usize {
+        self.len()
+    }
+
+    fn piece(&self, idx: usize) -> PieceRef<'_, T> {
+        (&self[idx]).into()
+    }
+
+    fn capacity_hint(&self) -> Result<usize, Error> {
+        pieces_capacity(self)
+    }
+}
+
+/// Call the callback for a formatter, catching panics if requested.
+#[cfg_attr(not(feature = "std"), allow(unused_variables))]
+fn invoke<T: ?Sized>(
+    f: &Formatter<T>,
+    data: &T,
+    opts: &RenderOptions,
+) -> Result<Option<String>, Error> {
+    #[cfg(feature = "std")]
+    if opts.catches_panics() {
+        // The callback can't be observed in a broken state afterwards: on panic we return an error
+        // for the entire render, so any partial results are discarded.
+        return std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (f.cb)(data)))
+            .map_err(|_| Error::CallbackPanicked(f.key.clone()));
+    }
+    Ok((f.cb)(data))
+}
+
+/// Call the callback for a formatter, converting a panic (if requested) into an `Error`.
+///
+/// If `stats` is provided or the `tracing` feature is enabled, the call is also timed. Timing
+/// requires the `std` feature.
+fn call_formatter<T: ?Sized>(
+    f: &Formatter<T>,
+    data: &T,
+    opts: &RenderOptions,
+    stats: Option<&mut RenderStats>,
+) -> Result<Option<String>, Error> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("funcfmt_callback", key = %f.key).entered();
+    #[cfg(feature = "std")]
+    let start = (stats.is_some() || cfg!(feature = "tracing")).then(std::time::Instant::now);
+
+    let val = invoke(f, data, opts);
+
+    if stats.is_some() || cfg!(feature = "tracing") {
+        #[cfg(feature = "std")]
+        let elapsed = start.map_or(Duration::ZERO, |start| start.elapsed());
+        #[cfg(not(feature = "std"))]
+        let elapsed = Duration::ZERO;
+        let hit = matches!(val, Ok(Some(_)));
+        #[cfg(feature = "tracing")]
+        tracing::trace!(key = %f.key, elapsed_ns = elapsed.as_nanos() as u64, hit, "callback returned");
+        if let Some(stats) = stats {
+            stats.record(&f.key, elapsed, hit);
+        }
+    }
+
+    val
+}
+
+/// Render the pieces into a new `String`, applying all options.
+pub(crate) fn render_to_string<T: ?Sized, P: PieceSource<T> + ?Sized>(
+    pieces: &P,
+    data: &T,
+    opts: &RenderOptions,
+    stats: Option<&mut RenderStats>,
+) -> Result<String, Error> {
+    let mut out = String::with_capacity(pieces.capacity_hint()?);
+    render_pieces(pieces, data, opts, stats, |chunk| {
+        out.push_str(chunk.text());
+        Ok::<_, Error>(())
+    })?;
+    opts.finish(&mut out)?;
+    Ok(out)
+}
+
+/// Render the pieces into a new `String`, applying all options, and keeping the partial output on
+/// failure.
+pub(crate) fn render_partial<T: ?Sized, P: PieceSource<T> + ?Sized>(
+    pieces: &P,
+    data: &T,
+    opts: &RenderOptions,
+) -> Result<String, RenderError> {
+    let capacity = pieces.capacity_hint().map_err(|error| RenderError {
+        error,
+        partial: String::new(),
+        piece: None,
+        key: None,
+    })?;
+    let mut out = String::with_capacity(capacity);
+    let res = render_pieces_at(pieces, data, opts, None, &mut |chunk| {
+        out.push_str(chunk.text());
+        Ok(())
+    });
+    match res {
+        Ok(()) => match opts.finish(&mut out) {
+            Ok(()) => Ok(out),
+            Err(error) => Err(RenderError {
+                error,
+                partial: out,
+                piece: None,
+                key: None,
+            }),
+        },
+        Err((idx, error)) => Err(RenderError {
+            error,
+            partial: out,
+            piece: Some(idx),
+            key: match pieces.piece(idx) {
+                PieceRef::Formatter(f) => Some(f.key.clone()),
+                PieceRef::Conditional(c) => Some(c.key().into()),
+                PieceRef::Bytes(b) => Some(b.formatter().key.clone()),
+                PieceRef::Verbatim(_) => None,
+            },
+        }),
+    }
+}
+
+/// Render each piece in turn, calling `emit` with the text it contributes to the output.
+///
+/// `emit` may fail with its own error type `E`, which rendering errors are converted into.
+fn render_pieces<T: ?Sized, P: PieceSource<T> + ?Sized, E: From<Error>>(
+    pieces: &P,
+    data: &T,
+    opts: &RenderOptions,
+    stats: Option<&mut RenderStats>,
+    mut emit: impl FnMut(PieceOutput<'_>) -> Result<(), E>,
+) -> Result<(), E> {
+    render_pieces_at(pieces, data, opts, stats, &mut emit).map_err(|(_, err)| err)
+}
+
+/// Like `render_pieces`, but also returning the index of the piece which failed.
+fn render_pieces_at<T: ?Sized, P: PieceSource<T> + ?Sized, E: From<Error>>(
+    pieces: &P,
+    data: &T,
+    opts: &RenderOptions,
+    mut stats: Option<&mut RenderStats>,
+    emit: &mut dyn FnMut(PieceOutput<'_>) -> Result<(), E>,
+) -> Result<(), (usize, E)> {
+    for idx in 0..pieces.piece_count() {
+        render_piece(pieces.piece(idx), data, opts, stats.as_deref_mut(), emit)
+            .map_err(|err| (idx, err))?;
+    }
+    Ok(())
+}
+
+/// Render a single piece, calling `emit` with each chunk of text it contributes to the output.
+///
+/// `emit` is a trait object since this recurses into the branches of conditionals.
+fn render_piece<T: ?Sized, E: From<Error>>(
+    piece: PieceRef<'_, T>,
+    data: &T,
+    opts: &RenderOptions,
+    mut stats: Option<&mut RenderStats>,
+    emit: &mut dyn FnMut(PieceOutput<'_>) -> Result<(), E>,
+) -> Result<(), E> {
+    opts.check_cancelled()?;
+    match piece {
+        PieceRef::Verbatim(s) => emit(PieceOutput::Verbatim(s)),
+        PieceRef::Bytes(b) => {
+            render_piece(PieceRef::Formatter(b.formatter()), data, opts, stats, emit)
+        }
+        PieceRef::Formatter(f) => {
+            let val = call_formatter(f, data, opts, stats)?
+                .ok_or_else(|| Error::NoData(f.key.clone()))?;
+            let val = opts.transform_output(&f.key, &val);
+            emit(PieceOutput::Formatter {
+                key: &f.key,
+                output: &val,
+            })
+        }
+        // The output of a conditional is its branch, which is rendered like any other template,
+        // rather than the output of the callback
+        PieceRef::Conditional(c) => {
+            let val = call_formatter(c.condition(), data, opts, stats.as_deref_mut())?;
+            render_pieces_at(c.branch(val.is_some()), data, opts, stats, emit)
+                .map_err(|(_, err)| err)
+        }
+    }
+}
+
+#[cfg(feature = "unicode-width")]
+pub(crate) fn render_with_widths<T: ?Sized, P: PieceSource<T> + ?Sized>(
+    pieces: &P,
+    data: &T,
+    opts: &RenderOptions,
+) -> Result<RenderedWidths, Error> {
+    use unicode_width::UnicodeWidthStr;
+
+    let mut out = String::with_capacity(pieces.capacity_hint()?);
+    let mut widths = Vec::with_capacity(pieces.piece_count());
+    for idx in 0..pieces.piece_count() {
+        // Conditionals can emit several chunks, which all count towards their single piece
+        let mut width = 0;
+        render_piece(pieces.piece(idx), data, opts, None, &mut |chunk| {
+            out.push_str(chunk.text());
+            width += chunk.text().width();
+            Ok::<_, Error>(())
+        })?;
+        widths.push(width);
+    }
+    opts.finish(&mut out)?;
+    fit_widths(&mut widths, out.width());
+    Ok(RenderedWidths {
+        output: out,
+        widths,
+    })
+}
+
+/// Adjust per-piece widths so that they add up to `total`, the width of the finished output. Any
+/// excess is cut back from the end, and any shortfall (like an added newline) goes to the last
+/// piece.
+#[cfg(feature = "unicode-width")]
+fn fit_widths(widths: &mut [usize], total: usize) {
+    let mut remaining = total;
+    for width in widths.iter_mut() {
+        *width = (*width).min(remaining);
+        remaining -= *width;
+    }
+    if let Some(last) = widths.last_mut() {
+        *last += remaining;
+    }
+}
+
+/// An adapter implementing `fmt::Display` by rendering format pieces on demand, as returned by
+/// `Render::display`.
+///
+/// If rendering fails, `fmt` returns `fmt::Error`, and the underlying `Error` can be retrieved
+/// with `take_error`. Since `format!` and `to_string` panic in that case, use `write!` when a
+/// callback may return no data.
+pub struct RenderDisplay<'a, T: ?Sized> {
+    pieces: &'a dyn PieceSource<T>,
+    data: &'a T,
+    error: RefCell<Option<Error>>,
+}
+
+impl<'a, T: ?Sized> RenderDisplay<'a, T> {
+    pub(crate) fn new(pieces: &'a dyn PieceSource<T>, data: &'a T) -> Self {
+        Self {
+            pieces,
+            data,
+            error: RefCell::new(None),
+        }
+    }
+
+    /// Take the error which caused the most recent call to `fmt` to fail, if any.
+    pub fn take_error(&self) -> Option<Error> {
+        self.error.borrow_mut().take()
+    }
+}
+
+impl<T: ?Sized> fmt::Display for RenderDisplay<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opts = RenderOptions::default();
+        render_pieces(self.pieces, self.data, &opts, None, |chunk| {
+            Ok(f.write_str(chunk.text())?)
+        })
+        .map_err(|err| {
+            *self.error.borrow_mut() = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for RenderDisplay<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RenderDisplay")
+            .field("pieces", &self.pieces.piece_count())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// Implement `Render<T>` for a type implementing `PieceSource<T>`.
+macro_rules! impl_render {
+    ($ty:ty) => {
+        impl<T: ?Sized> $crate::Render<T> for $ty {
+            fn render(&self, data: &T) -> Result<String, $crate::Error> {
+                self.render_with(data, &$crate::RenderOptions::default())
+            }
+
+            fn render_with(
+                &self,
+                data: &T,
+                opts: &$crate::RenderOptions,
+            ) -> Result<String, $crate::Error> {
+                $crate::render::render_to_string(self, data, opts, None)
+            }
+
+            #[cfg(feature = "unicode-width")]
+            fn render_with_widths(
+                &self,
+                data: &T,
+                opts: &$crate::RenderOptions,
+            ) -> Result<$crate::RenderedWidths, $crate::Error> {
+                $crate::render::render_with_widths(self, data, opts)
+            }
+
+            fn render_with_stats(
+                &self,
+                data: &T,
+                opts: &$crate::RenderOptions,
+                stats: &mut $crate::RenderStats,
+            ) -> Result<String, $crate::Error> {
+                $crate::render::render_to_string(self, data, opts, Some(stats))
+            }
+
+            fn render_partial(
+                &self,
+                data: &T,
+                opts: &$crate::RenderOptions,
+            ) -> Result<String, $crate::RenderError> {
+                $crate::render::render_partial(self, data, opts)
+            }
+
+            fn display<'a>(&'a self, data: &'a T) -> $crate::RenderDisplay<'a, T> {
+                $crate::render::RenderDisplay::new(self, data)
+            }
+        }
+    };
+}
+
+pub(crate) use impl_render;
+
+impl_render!(FormatPieces<T>);
+
+impl<T: ?Sized> Walk<T> for FormatPieces<T> {
+    fn walk<B, F>(&self, data: &T, visitor: F) -> Result<ControlFlow<B>, Error>
+    where
+        F: FnMut(PieceOutput<'_>) -> ControlFlow<B>,
+    {
+        walk(self, data, visitor)
+    }
+}
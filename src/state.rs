@@ -0,0 +1,135 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::cell::{RefCell, RefMut};
+use core::fmt;
+
+use crate::{Error, FormatterCallback, Render};
+
+/// The data passed to callbacks rendered through `SharedState<S>`: the caller's data, along with
+/// scratch state which lives for a single render.
+pub struct WithState<T, S> {
+    data: T,
+    state: RefCell<S>,
+}
+
+impl<T, S> WithState<T, S> {
+    /// The data being rendered.
+    pub fn data(&self) -> &T {
+        &self.data
+    }
+
+    /// Mutable access to the state for this render.
+    ///
+    /// # Panics
+    ///
+    /// If the state is already borrowed, which can only happen if a callback calls this again
+    /// while still holding the result.
+    pub fn state(&self) -> RefMut<'_, S> {
+        self.state.borrow_mut()
+    }
+}
+
+impl<T: fmt::Debug, S: fmt::Debug> fmt::Debug for WithState<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WithState")
+            .field("data", &self.data)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+/// Create a callback which is given mutable access to the per-render state of a
+/// `SharedState<S>`, along with the data. See `SharedState`.
+pub fn stateful<T, S, F>(f: F) -> FormatterCallback<WithState<T, S>>
+where
+    F: Fn(&T, &mut S) -> Option<String> + Send + Sync + 'static,
+{
+    Arc::new(move |ws: &WithState<T, S>| f(&ws.data, &mut ws.state()))
+}
+
+/// Renders templates whose callbacks share scratch state, created afresh by a factory for every
+/// render.
+///
+/// This allows callbacks for different keys (or different occurrences of the same key) to
+/// coordinate within a single render, for example to number items or skip duplicates, without
+/// wrapping state in a `Mutex` inside each closure. Since each render gets its own state, the same
+/// `SharedState<S>` and processed template can be used from many threads at once.
+///
+/// Templates are processed against callbacks taking `WithState<T, S>`, which are most easily made
+/// with `stateful`. Existing callbacks for `T` can be reused with `namespace`, projecting with
+/// `WithState::data`. Since callbacks must be `'static`, the data is taken by value: to render
+/// borrowed data, pass a cheap handle to it, like an `Arc`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{stateful, FormatMap, SharedState, ToFormatPieces, WithState};
+///
+/// let mut fmap: FormatMap<WithState<String, u32>> = FormatMap::default();
+/// fmap.insert("index".into(), stateful(|_, n: &mut u32| {
+///     *n += 1;
+///     Some(n.to_string())
+/// }));
+/// fmap.insert("name".into(), stateful(|data: &String, _| Some(data.clone())));
+///
+/// let shared = SharedState::new(|| 0);
+/// let fp = fmap.to_format_pieces("{index}. {name}, {index}. {name}").unwrap();
+/// assert_eq!(shared.render(&fp, String::from("x")), Ok("1. x, 2. x".to_string()));
+/// // Every render starts from fresh state
+/// assert_eq!(shared.render(&fp, String::from("y")), Ok("1. y, 2. y".to_string()));
+/// ```
+pub struct SharedState<S> {
+    factory: Arc<dyn Fn() -> S + Send + Sync>,
+}
+
+impl<S> SharedState<S> {
+    /// Create a `SharedState<S>` which calls `factory` to create the state for each render.
+    pub fn new<F: Fn() -> S + Send + Sync + 'static>(factory: F) -> Self {
+        Self {
+            factory: Arc::new(factory),
+        }
+    }
+
+    /// Render `pieces` with `data` and fresh state.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `Render::render`.
+    pub fn render<T, R>(&self, pieces: &R, data: T) -> Result<String, Error>
+    where
+        R: Render<WithState<T, S>> + ?Sized,
+    {
+        self.render_with_state(pieces, data).map(|(out, _)| out)
+    }
+
+    /// Like `render`, but also return the state as it was left at the end of rendering.
+    ///
+    /// # Errors
+    ///
+    /// The same as for `Render::render`.
+    pub fn render_with_state<T, R>(&self, pieces: &R, data: T) -> Result<(String, S), Error>
+    where
+        R: Render<WithState<T, S>> + ?Sized,
+    {
+        let ws = WithState {
+            data,
+            state: RefCell::new((self.factory)()),
+        };
+        let out = pieces.render(&ws)?;
+        Ok((out, ws.state.into_inner()))
+    }
+}
+
+impl<S> Clone for SharedState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            factory: Arc::clone(&self.factory),
+        }
+    }
+}
+
+impl<S> fmt::Debug for SharedState<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedState").finish_non_exhaustive()
+    }
+}
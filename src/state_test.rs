@@ -0,0 +1,73 @@
+use super::*;
+use std::collections::HashSet;
+
+struct File {
+    name: &'static str,
+    tags: Vec<&'static str>,
+}
+
+fn fmap() -> impl KeyLookup<WithState<File, HashSet<&'static str>>> {
+    let mut stateful_map: FormatMap<WithState<File, HashSet<&str>>> = FormatMap::default();
+    // Only show each tag the first time it appears in the output
+    stateful_map.insert(
+        "newtags".into(),
+        stateful(|file: &File, seen: &mut HashSet<&str>| {
+            let tags: Vec<_> = file
+                .tags
+                .iter()
+                .copied()
+                .filter(|tag| seen.insert(tag))
+                .collect();
+            Some(tags.join(","))
+        }),
+    );
+    let plain: FormatMap<File> = fm! {"name" => |f: &File| Some(f.name.to_string())};
+    (
+        stateful_map,
+        namespace("", plain, WithState::<File, HashSet<&str>>::data),
+    )
+}
+
+#[test]
+fn state_is_shared_within_a_render() {
+    let file = || File {
+        name: "a",
+        tags: vec!["x", "y", "x"],
+    };
+    let fp = fmap()
+        .to_format_pieces("{name}[{newtags}][{newtags}]")
+        .unwrap();
+    let shared = SharedState::new(HashSet::new);
+
+    let (out, seen) = shared.render_with_state(&fp, file()).unwrap();
+    assert_eq!(out, "a[x,y][]");
+    assert_eq!(seen.len(), 2);
+
+    let ct = CompiledTemplate::compile(&fmap(), "{newtags}{newtags}").unwrap();
+    assert_eq!(shared.render(&ct, file()).unwrap(), "x,y");
+}
+
+#[test]
+fn renders_concurrently() {
+    let counter: FormatMap<WithState<u32, u32>> = [(
+        "n".into(),
+        stateful(|_: &u32, n: &mut u32| {
+            *n += 1;
+            Some(n.to_string())
+        }),
+    )]
+    .into_iter()
+    .collect();
+    let fp = Arc::new(counter.to_format_pieces("{n}{n}{n}").unwrap());
+    let shared = SharedState::new(|| 0);
+
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let (fp, shared) = (Arc::clone(&fp), shared.clone());
+            std::thread::spawn(move || shared.render(&*fp, i).unwrap())
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), "123");
+    }
+}
@@ -0,0 +1,75 @@
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::{FnvHashMap, KeyString};
+
+/// Statistics about the calls made to a single key's callback, as collected in `RenderStats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyStats {
+    /// The number of times the callback was called.
+    pub calls: u64,
+    /// The number of calls which produced no data, or which panicked.
+    pub misses: u64,
+    /// The total time spent inside the callback across all calls. This is only measured when the
+    /// `std` feature is enabled, and is always zero otherwise.
+    pub total_time: Duration,
+}
+
+/// Per-key callback statistics accumulated across one or more renders with
+/// `Render::render_with_stats`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, Render, RenderOptions, RenderStats, ToFormatPieces};
+///
+/// let fmap = fm!{"foo" => |data: &String| Some(data.clone()), "none" => |_| None};
+/// let fp = fmap.to_format_pieces("{foo}{foo}").unwrap();
+/// let mut stats = RenderStats::new();
+/// for data in ["a", "b"] {
+///     fp.render_with_stats(&data.to_string(), &RenderOptions::default(), &mut stats).unwrap();
+/// }
+/// assert_eq!(stats.get("foo").unwrap().calls, 4);
+/// assert_eq!(stats.get("none"), None);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RenderStats {
+    keys: FnvHashMap<KeyString, KeyStats>,
+}
+
+impl RenderStats {
+    /// Create an empty set of statistics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The statistics for `key`, if its callback has been called at all.
+    pub fn get(&self, key: &str) -> Option<&KeyStats> {
+        self.keys.get(key)
+    }
+
+    /// All keys which have been called, ordered from the most to the least total time spent in
+    /// their callback.
+    pub fn by_total_time(&self) -> Vec<(&str, &KeyStats)> {
+        let mut out: Vec<_> = self.keys.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        out.sort_unstable_by(|a, b| b.1.total_time.cmp(&a.1.total_time).then(a.0.cmp(b.0)));
+        out
+    }
+
+    /// Discard all statistics collected so far.
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    pub(crate) fn record(&mut self, key: &str, elapsed: Duration, hit: bool) {
+        let stats = match self.keys.get_mut(key) {
+            Some(stats) => stats,
+            None => self.keys.entry(key.into()).or_default(),
+        };
+        stats.calls = stats.calls.saturating_add(1);
+        if !hit {
+            stats.misses = stats.misses.saturating_add(1);
+        }
+        stats.total_time = stats.total_time.saturating_add(elapsed);
+    }
+}
@@ -0,0 +1,368 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::parse::{find_condition, split_conditional, split_trim_markers, tokenize};
+use crate::{
+    escape_into, CompileOptions, Error, FnvHashMap, FormatMap, FormatPieces, KeyString,
+    TemplateToken, ToFormatPieces,
+};
+
+/// A family of named templates sharing one `FormatMap<T>`, which can inherit structure from each
+/// other.
+///
+/// # Inheritance
+///
+/// A base template marks overridable regions with `{%block name%}...{%endblock%}`. The content
+/// between the tags is used as-is unless a child overrides it. Blocks may be nested.
+///
+/// A child template starts with `{%extends base%}`, and then provides replacement content for
+/// any of the base's blocks with its own `{%block name%}...{%endblock%}` tags. Anything in the
+/// child outside of a block is ignored. Children may themselves be extended.
+///
+/// Whitespace just inside the `{%` and `%}` delimiters is ignored. Everything else is regular
+/// template syntax, which is processed as usual once inheritance has been resolved.
+///
+/// # Includes
+///
+/// `{>name}` is replaced with the entire template `name`, after its own inheritance and includes
+/// have been resolved. This allows many templates to share common fragments, like a header.
+/// Includes are also expanded in the branches of conditionals, like `{key?{>name}:none}`.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{fm, Render, TemplateSet};
+///
+/// let mut set = TemplateSet::new(fm!{"name" => |data: &String| Some(data.clone())});
+/// set.add("base", "== {%block title%}Report{%endblock%} ==\n{%block body%}{%endblock%}");
+/// set.add("greeting", "{%extends base%}{%block body%}Hello, {name}!{%endblock%}");
+///
+/// let fp = set.compile("greeting").unwrap();
+/// let data = String::from("world");
+/// assert_eq!(fp.render(&data), Ok("== Report ==\nHello, world!".to_string()));
+///
+/// set.add("sig", "-- {name}");
+/// set.add("letter", "Dear {name},\n{>sig}");
+/// let fp = set.compile("letter").unwrap();
+/// assert_eq!(fp.render(&data), Ok("Dear world,\n-- world".to_string()));
+/// ```
+pub struct TemplateSet<T> {
+    map: FormatMap<T>,
+    templates: FnvHashMap<KeyString, String>,
+}
+
+impl<T> TemplateSet<T> {
+    /// Create an empty set of templates, with keys resolved using `map`.
+    pub fn new(map: FormatMap<T>) -> Self {
+        Self {
+            map,
+            templates: FnvHashMap::default(),
+        }
+    }
+
+    /// The `FormatMap<T>` used to resolve keys.
+    pub fn map(&self) -> &FormatMap<T> {
+        &self.map
+    }
+
+    /// Add a template under the given name, replacing any existing template with that name.
+    ///
+    /// The template is not checked until it, or a template extending it, is compiled.
+    pub fn add<N, S>(&mut self, name: N, tmpl: S)
+    where
+        N: Into<KeyString>,
+        S: Into<String>,
+    {
+        self.templates.insert(name.into(), tmpl.into());
+    }
+
+    /// Resolve inheritance and includes for the template with the given name, and process the
+    /// result into a `FormatPieces<T>`.
+    ///
+    /// # Errors
+    ///
+    /// - `Error::UnknownTemplate` if `name`, or a template it extends or includes, has not been
+    ///   added
+    /// - `Error::TemplateCycle` if templates extend or include each other in a cycle
+    /// - `Error::InvalidTag` if a `{%...%}` tag is unknown, malformed, or unbalanced
+    /// - Any error from `ToFormatPieces::to_format_pieces` on the resolved template
+    pub fn compile(&self, name: &str) -> Result<FormatPieces<T>, Error> {
+        self.map
+            .to_format_pieces(self.expand(name, &mut Vec::new())?)
+    }
+
+    /// Resolve inheritance for the template with the given name, and then recursively replace
+    /// each `{>include}` with the template it names. `stack` holds the templates currently being
+    /// expanded, to detect cycles.
+    fn expand(&self, name: &str, stack: &mut Vec<KeyString>) -> Result<String, Error> {
+        if stack.iter().any(|n| n == name) {
+            return Err(Error::TemplateCycle(name.into()));
+        }
+
+        let resolved = self.resolve(name)?;
+        if !resolved.contains("{>") {
+            return Ok(resolved);
+        }
+
+        stack.push(name.into());
+        let out = self.expand_includes(&resolved, stack)?;
+        stack.pop();
+        Ok(out)
+    }
+
+    /// Replace each `{>include}` in the template source `src`, including those in the branches of
+    /// conditionals.
+    fn expand_includes(&self, src: &str, stack: &mut Vec<KeyString>) -> Result<String, Error> {
+        // Rebuild the template from its tokens, so that escapes are preserved everywhere except
+        // in the included templates, which are already in template syntax.
+        let mut out = String::with_capacity(src.len());
+        tokenize(src, &CompileOptions::default(), |token| {
+            match token {
+                TemplateToken::Verbatim { text, .. } => escape_into(text, &mut out),
+                TemplateToken::Key { name, span } => {
+                    // Use the key exactly as written, so that escapes and any conditional
+                    // branches are preserved. Trim markers have already been applied.
+                    let raw = split_trim_markers(&src[span.start + 1..span.end - 1]).0;
+                    if let Some(include) = name.strip_prefix('>') {
+                        out.push_str(&self.expand(include, stack)?);
+                    } else if let Some(q) =
+                        find_condition(raw.as_bytes()).filter(|_| raw.contains("{>"))
+                    {
+                        self.expand_conditional(raw, q, stack, &mut out)?;
+                    } else {
+                        out.push('{');
+                        out.push_str(raw);
+                        out.push('}');
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        Ok(out)
+    }
+
+    /// Append the conditional key `raw`, whose condition ends at `q`, to `out` with any includes
+    /// in its branches expanded.
+    fn expand_conditional(
+        &self,
+        raw: &str,
+        q: usize,
+        stack: &mut Vec<KeyString>,
+        out: &mut String,
+    ) -> Result<(), Error> {
+        out.push('{');
+        out.push_str(&raw[..=q]);
+        if let Some(cond) = split_conditional(raw) {
+            branch_into(&self.expand_includes(&cond.then, stack)?, out)?;
+            out.push(':');
+            branch_into(&self.expand_includes(&cond.otherwise, stack)?, out)?;
+        }
+        out.push('}');
+        Ok(())
+    }
+
+    /// Resolve inheritance for the template with the given name, returning the flattened template
+    /// source.
+    fn resolve(&self, name: &str) -> Result<String, Error> {
+        // Walk up the chain of parents, from the most derived template to the base
+        let mut chain: Vec<Parsed<'_>> = Vec::new();
+        let mut seen: Vec<&str> = Vec::new();
+        let mut cur = name;
+
+        loop {
+            if seen.contains(&cur) {
+                return Err(Error::TemplateCycle(cur.into()));
+            }
+            seen.push(cur);
+
+            let src = self
+                .templates
+                .get(cur)
+                .ok_or_else(|| Error::UnknownTemplate(cur.into()))?;
+            let tmpl = parse(src)?;
+            let parent = tmpl.extends;
+            chain.push(tmpl);
+            match parent {
+                Some(parent) => cur = parent,
+                None => break,
+            }
+        }
+
+        let (base, children) = chain.split_last().ok_or(Error::InvalidTag)?;
+
+        // The most derived definition of each block wins, so only insert blocks which haven't
+        // already been overridden further down the chain.
+        let mut overrides = FnvHashMap::default();
+        for child in children {
+            collect_blocks(&child.nodes, &mut overrides);
+        }
+
+        let mut out = String::new();
+        flatten(&base.nodes, &overrides, &mut out);
+        Ok(out)
+    }
+}
+
+enum Node<'a> {
+    Text(&'a str),
+    Block { name: &'a str, body: Vec<Node<'a>> },
+}
+
+struct Parsed<'a> {
+    extends: Option<&'a str>,
+    nodes: Vec<Node<'a>>,
+}
+
+enum Tag<'a> {
+    Extends(&'a str),
+    Block(&'a str),
+    EndBlock,
+}
+
+/// Append the template `tmpl` to `out` as the branch of a conditional key, escaping any literal
+/// text which would otherwise end the key or the branch.
+fn branch_into(tmpl: &str, out: &mut String) -> Result<(), Error> {
+    tokenize(tmpl, &CompileOptions::default(), |token| {
+        match token {
+            TemplateToken::Verbatim { text, .. } => {
+                for c in text.chars() {
+                    if matches!(c, '{' | '}' | ':' | '\\') {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+            }
+            TemplateToken::Key { span, .. } => {
+                out.push('{');
+                out.push_str(split_trim_markers(&tmpl[span.start + 1..span.end - 1]).0);
+                out.push('}');
+            }
+        }
+        Ok(())
+    })
+}
+
+fn parse_tag(inner: &str) -> Result<Tag<'_>, Error> {
+    let inner = inner.trim();
+    if inner == "endblock" {
+        return Ok(Tag::EndBlock);
+    }
+    let (kw, arg) = inner
+        .split_once(char::is_whitespace)
+        .ok_or(Error::InvalidTag)?;
+    let arg = arg.trim();
+    if arg.is_empty() || arg.contains(char::is_whitespace) {
+        return Err(Error::InvalidTag);
+    }
+    match kw {
+        "extends" => Ok(Tag::Extends(arg)),
+        "block" => Ok(Tag::Block(arg)),
+        _ => Err(Error::InvalidTag),
+    }
+}
+
+/// Split a template into text and block nodes. `{{` is skipped so that escaped braces are never
+/// treated as the start of a tag.
+///
+/// Each block name may only be defined once per template, which also guarantees that overrides
+/// can never recursively include themselves.
+fn parse(src: &str) -> Result<Parsed<'_>, Error> {
+    // Stack of (block name, nodes so far). The bottom entry is the top level of the template.
+    let mut stack: Vec<(&str, Vec<Node<'_>>)> = vec![("", Vec::new())];
+    let mut block_names: Vec<&str> = Vec::new();
+    let mut extends = None;
+    let mut text_start = 0;
+    let mut idx = 0;
+    let bytes = src.as_bytes();
+
+    while idx < bytes.len() {
+        if bytes[idx] != b'{' {
+            idx += 1;
+            continue;
+        }
+        match bytes.get(idx + 1) {
+            Some(b'{') => {
+                idx += 2;
+                continue;
+            }
+            Some(b'%') => {}
+            _ => {
+                idx += 1;
+                continue;
+            }
+        }
+
+        let inner_start = idx + 2;
+        let inner_len = src[inner_start..].find("%}").ok_or(Error::InvalidTag)?;
+        let tag_end = inner_start + inner_len + 2;
+        let tag = parse_tag(&src[inner_start..inner_start + inner_len])?;
+
+        let depth = stack.len();
+        let text = &src[text_start..idx];
+        let top = stack.last_mut().ok_or(Error::InvalidTag)?;
+        if !text.is_empty() {
+            top.1.push(Node::Text(text));
+        }
+
+        match tag {
+            Tag::Extends(parent) => {
+                // Only valid as the very first thing in the template, ignoring whitespace
+                if extends.is_some() || depth != 1 || !src[..idx].trim().is_empty() {
+                    return Err(Error::InvalidTag);
+                }
+                top.1.clear();
+                extends = Some(parent);
+            }
+            Tag::Block(name) => {
+                if block_names.contains(&name) {
+                    return Err(Error::InvalidTag);
+                }
+                block_names.push(name);
+                stack.push((name, Vec::new()));
+            }
+            Tag::EndBlock => {
+                if depth < 2 {
+                    return Err(Error::InvalidTag);
+                }
+                let (name, body) = stack.pop().ok_or(Error::InvalidTag)?;
+                let top = stack.last_mut().ok_or(Error::InvalidTag)?;
+                top.1.push(Node::Block { name, body });
+            }
+        }
+
+        idx = tag_end;
+        text_start = tag_end;
+    }
+
+    if stack.len() != 1 {
+        return Err(Error::InvalidTag);
+    }
+    let (_, mut nodes) = stack.pop().ok_or(Error::InvalidTag)?;
+    if text_start < src.len() {
+        nodes.push(Node::Text(&src[text_start..]));
+    }
+
+    Ok(Parsed { extends, nodes })
+}
+
+fn collect_blocks<'a>(nodes: &'a [Node<'a>], overrides: &mut FnvHashMap<&'a str, &'a [Node<'a>]>) {
+    for node in nodes {
+        if let Node::Block { name, body } = node {
+            overrides.entry(name).or_insert(body);
+            collect_blocks(body, overrides);
+        }
+    }
+}
+
+fn flatten(nodes: &[Node<'_>], overrides: &FnvHashMap<&str, &[Node<'_>]>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Block { name, body } => {
+                let body = overrides.get(name).copied().unwrap_or(body);
+                flatten(body, overrides, out);
+            }
+        }
+    }
+}
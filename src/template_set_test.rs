@@ -0,0 +1,167 @@
+use super::*;
+
+fn set() -> TemplateSet<String> {
+    let mut set = TemplateSet::new(fm! {
+        "name" => |e: &String| Some(e.to_string()),
+    });
+    set.add(
+        "base",
+        "<{% block head %}H{%block title%}T{%endblock%}{%endblock%}|{%block body%}B{%endblock%}>",
+    );
+    set
+}
+
+#[test]
+fn base_uses_defaults() {
+    let inp = String::from("x");
+    let fp = set().compile("base").unwrap();
+    assert_eq!(fp.render(&inp), Ok("<HT|B>".to_owned()));
+}
+
+#[test]
+fn child_overrides_blocks() {
+    let mut set = set();
+    set.add(
+        "child",
+        "{%extends base%}ignored{%block body%}hi {name}{%endblock%}",
+    );
+    let inp = String::from("x");
+    let fp = set.compile("child").unwrap();
+    assert_eq!(fp.render(&inp), Ok("<HT|hi x>".to_owned()));
+}
+
+#[test]
+fn grandchild_overrides_win() {
+    let mut set = set();
+    set.add(
+        "child",
+        "{%extends base%}{%block title%}C{%endblock%}{%block body%}c{%endblock%}",
+    );
+    set.add(
+        "grandchild",
+        "\n{%extends child%}{%block title%}G{%endblock%}",
+    );
+    let inp = String::from("x");
+    let fp = set.compile("grandchild").unwrap();
+    assert_eq!(fp.render(&inp), Ok("<HG|c>".to_owned()));
+}
+
+#[test]
+fn nested_override_replaces_inner_blocks() {
+    let mut set = set();
+    set.add(
+        "child",
+        "{%extends base%}{%block head%}[{%block title%}X{%endblock%}]{%endblock%}",
+    );
+    let inp = String::from("x");
+    let fp = set.compile("child").unwrap();
+    assert_eq!(fp.render(&inp), Ok("<[X]|B>".to_owned()));
+}
+
+#[test]
+fn escaped_braces_are_not_tags() {
+    let mut set = set();
+    set.add("esc", "{{%block x%}}");
+    let inp = String::from("x");
+    let fp = set.compile("esc").unwrap();
+    assert_eq!(fp.render(&inp), Ok("{%block x%}".to_owned()));
+}
+
+#[test]
+fn template_errors() {
+    let mut set = set();
+    set.add("a", "{%extends b%}");
+    set.add("b", "{%extends a%}");
+    set.add("orphan", "{%extends nope%}");
+    set.add("unclosed", "{%block x%}");
+    set.add("stray", "{%endblock%}");
+    set.add("dup", "{%block x%}{%endblock%}{%block x%}{%endblock%}");
+    set.add("late", "x{%extends base%}");
+    set.add("unknown", "{%frobnicate x%}");
+
+    assert_eq!(set.compile("a"), Err(Error::TemplateCycle("a".into())));
+    assert_eq!(
+        set.compile("orphan"),
+        Err(Error::UnknownTemplate("nope".into()))
+    );
+    assert_eq!(
+        set.compile("missing"),
+        Err(Error::UnknownTemplate("missing".into()))
+    );
+    for name in ["unclosed", "stray", "dup", "late", "unknown"] {
+        assert_eq!(set.compile(name), Err(Error::InvalidTag), "{name}");
+    }
+}
+
+#[test]
+fn includes_expand_recursively() {
+    let mut set = set();
+    set.add("sig", "{{{name}}}");
+    set.add("footer", "--{>sig}--");
+    set.add("page", "{{a}}{>footer}|{>base}");
+    let fp = set.compile("page").unwrap();
+    assert_eq!(
+        fp.render(&String::from("x")),
+        Ok("{a}--{x}--|<HT|B>".to_owned())
+    );
+}
+
+#[test]
+fn include_errors() {
+    let mut set = set();
+    set.add("a", "{>b}");
+    set.add("b", "x{>a}");
+    set.add("self", "{>self}");
+    set.add("missing", "{>nope}");
+    assert_eq!(
+        set.compile("a").map(|_| ()),
+        Err(Error::TemplateCycle("a".into()))
+    );
+    assert_eq!(
+        set.compile("self").map(|_| ()),
+        Err(Error::TemplateCycle("self".into()))
+    );
+    assert_eq!(
+        set.compile("missing").map(|_| ()),
+        Err(Error::UnknownTemplate("nope".into()))
+    );
+
+    // The same template may be included many times, as long as it's not recursive
+    set.add("twice", "{>base}{>base}");
+    assert!(set.compile("twice").is_ok());
+}
+
+#[test]
+fn include_keeps_conditionals() {
+    let mut set = TemplateSet::new(fm! {
+        "name" => |e: &String| Some(e.to_string()),
+    });
+    set.add("inc", "!");
+    set.add("page", r"{name?\{{name}\}:none}{>inc}");
+    let fp = set.compile("page").unwrap();
+    assert_eq!(fp.render(&String::from("x")), Ok("{x}!".to_owned()));
+}
+
+#[test]
+fn includes_expand_in_conditional_branches() {
+    let mut set = TemplateSet::new(fm! {
+        "name" => |e: &String| Some(e.to_string()),
+        "nothing" => |_: &String| None,
+    });
+    set.add("sig", r"a:b\ {{c}} {name}");
+    set.add("inc", "!");
+    set.add(
+        "page",
+        "{name?[{>sig}]:no}|{nothing?yes:no{>inc}}|{name?{name?<{>inc}>}}",
+    );
+    set.add("loop", "{name?{>loop}}");
+    let fp = set.compile("page").unwrap();
+    assert_eq!(
+        fp.render(&String::from("x")),
+        Ok(r"[a:b\ {c} x]|no!|<!>".to_owned())
+    );
+    assert_eq!(
+        set.compile("loop").map(|_| ()),
+        Err(Error::TemplateCycle("loop".into()))
+    );
+}
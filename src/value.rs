@@ -0,0 +1,377 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::fmt;
+#[cfg(feature = "time")]
+use time::format_description::{self, well_known::Rfc3339, OwnedFormatItem};
+#[cfg(feature = "time")]
+use time::OffsetDateTime;
+
+use crate::context::Memo;
+use crate::{FnvHashMap, FormatterCallback, KeyLookup, KeyString};
+
+/// A typed value returned by a callback in a `ValueMap<T>`, to be formatted at render time.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    /// A date and time, with the `time` feature.
+    #[cfg(feature = "time")]
+    DateTime(OffsetDateTime),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int(v) => write!(f, "{v}"),
+            Self::Float(v) => write!(f, "{v}"),
+            Self::Str(v) => f.write_str(v),
+            Self::Bool(v) => write!(f, "{v}"),
+            #[cfg(feature = "time")]
+            Self::DateTime(v) => match v.format(&Rfc3339) {
+                Ok(s) => f.write_str(&s),
+                Err(_) => Err(fmt::Error),
+            },
+        }
+    }
+}
+
+macro_rules! impl_from_int {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(v: $ty) -> Self {
+                    Self::Int(v.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(i8, i16, i32, i64, u8, u16, u32);
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Self {
+        Self::Float(v.into())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Self::Float(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Self::Bool(v)
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Self::Str(v)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Self::Str(v.into())
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<OffsetDateTime> for Value {
+    fn from(v: OffsetDateTime) -> Self {
+        Self::DateTime(v)
+    }
+}
+
+/// A callback returning a typed `Value`, as stored in a `ValueMap<T>`.
+pub type ValueCallback<T> = Arc<dyn Fn(&T) -> Option<Value> + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// A parsed format spec, following a subset of the syntax of `std::fmt`:
+/// `[[fill]align][+][0][width][.precision]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FormatSpec {
+    fill: char,
+    align: Option<Align>,
+    plus: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+fn to_align(c: char) -> Option<Align> {
+    match c {
+        '<' => Some(Align::Left),
+        '^' => Some(Align::Center),
+        '>' => Some(Align::Right),
+        _ => None,
+    }
+}
+
+/// Split leading ASCII digits from `s`, parsing them as a number.
+fn split_number(s: &str) -> Option<(Option<usize>, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if end == 0 {
+        return Some((None, s));
+    }
+    Some((Some(s[..end].parse().ok()?), &s[end..]))
+}
+
+impl FormatSpec {
+    fn parse(spec: &str) -> Option<Self> {
+        let mut out = Self {
+            fill: ' ',
+            align: None,
+            plus: false,
+            zero: false,
+            width: 0,
+            precision: None,
+        };
+        let mut rest = spec;
+
+        let mut chars = rest.chars();
+        let first = chars.next();
+        let second = chars.next();
+        if let (Some(fill), Some(align)) = (first, second.and_then(to_align)) {
+            out.fill = fill;
+            out.align = Some(align);
+            rest = chars.as_str();
+        } else if let Some(align) = first.and_then(to_align) {
+            out.align = Some(align);
+            rest = &rest[1..];
+        }
+
+        if let Some(r) = rest.strip_prefix('+') {
+            out.plus = true;
+            rest = r;
+        }
+        if let Some(r) = rest.strip_prefix('0') {
+            out.zero = true;
+            rest = r;
+        }
+        let (width, r) = split_number(rest)?;
+        out.width = width.unwrap_or(0);
+        rest = r;
+        if let Some(r) = rest.strip_prefix('.') {
+            let (precision, r) = split_number(r)?;
+            out.precision = Some(precision?);
+            rest = r;
+        }
+
+        if rest.is_empty() {
+            Some(out)
+        } else {
+            None
+        }
+    }
+
+    fn format(&self, value: &Value) -> String {
+        let (sign, body, numeric) = match value {
+            Value::Int(v) => {
+                let sign = if *v < 0 { "-" } else { self.plus_sign() };
+                (sign, v.unsigned_abs().to_string(), true)
+            }
+            Value::Float(v) if v.is_nan() => ("", "NaN".into(), true),
+            Value::Float(v) => {
+                let sign = if v.is_sign_negative() {
+                    "-"
+                } else {
+                    self.plus_sign()
+                };
+                // f64::abs isn't in core on older toolchains
+                let abs = if v.is_sign_negative() { -v } else { *v };
+                let body = match self.precision {
+                    Some(p) => format!("{:.*}", p, abs),
+                    None => format!("{}", abs),
+                };
+                (sign, body, true)
+            }
+            Value::Str(v) => ("", self.truncate(v), false),
+            other => ("", self.truncate(&other.to_string()), false),
+        };
+
+        let len = sign.len() + body.chars().count();
+        let pad = self.width.saturating_sub(len);
+        let mut out = String::with_capacity(len.saturating_add(pad));
+        if numeric && self.zero && self.align.is_none() {
+            out.push_str(sign);
+            out.extend(core::iter::repeat('0').take(pad));
+            out.push_str(&body);
+            return out;
+        }
+
+        let default = if numeric { Align::Right } else { Align::Left };
+        let (before, after) = match self.align.unwrap_or(default) {
+            Align::Left => (0, pad),
+            Align::Center => (pad / 2, pad - pad / 2),
+            Align::Right => (pad, 0),
+        };
+        out.extend(core::iter::repeat(self.fill).take(before));
+        out.push_str(sign);
+        out.push_str(&body);
+        out.extend(core::iter::repeat(self.fill).take(after));
+        out
+    }
+
+    fn plus_sign(&self) -> &'static str {
+        if self.plus {
+            "+"
+        } else {
+            ""
+        }
+    }
+
+    /// Truncate `s` to the precision, in characters, like `std::fmt` does for strings.
+    fn truncate(&self, s: &str) -> String {
+        match self.precision {
+            Some(p) => s.chars().take(p).collect(),
+            None => s.into(),
+        }
+    }
+}
+
+/// How a `ValueMap<T>` key's value is formatted, depending on the spec after the `:`.
+enum Spec {
+    Std(FormatSpec),
+    #[cfg(feature = "time")]
+    DateTime(OwnedFormatItem),
+}
+
+impl Spec {
+    fn parse(spec: &str) -> Option<Self> {
+        #[cfg(feature = "time")]
+        if spec.contains('%') {
+            return format_description::parse_strftime_owned(spec)
+                .ok()
+                .map(Self::DateTime);
+        }
+        FormatSpec::parse(spec).map(Self::Std)
+    }
+
+    fn format(&self, value: &Value) -> Option<String> {
+        match (self, value) {
+            (Self::Std(spec), value) => Some(spec.format(value)),
+            #[cfg(feature = "time")]
+            (Self::DateTime(items), Value::DateTime(v)) => v.format(items).ok(),
+            #[cfg(feature = "time")]
+            (Self::DateTime(_), _) => None,
+        }
+    }
+}
+
+/// A map of keys to callbacks returning typed `Value`s rather than strings, which are formatted at
+/// render time according to a format spec in the template.
+///
+/// A key may be followed by `:` and a spec, using a subset of the syntax of `std::fmt`:
+/// `[[fill]align][+][0][width][.precision]`. For example, `{size:08}` zero-pads to eight
+/// characters, `{ratio:.2}` shows two decimal places, `{name:>10}` right-aligns, and `{name:.3}`
+/// truncates to three characters. Without a spec, values are formatted with `Display`. With the
+/// `time` feature, a spec containing `%` is a `strftime` format for `Value::DateTime`, and
+/// produces no data for other values.
+///
+/// Keys with an invalid spec are unknown. `ValueMap<T>` implements `KeyLookup<T>`, so it can be
+/// combined with other sources of callbacks using a tuple.
+///
+/// # Example
+///
+/// ```
+/// use funcfmt::{Render, ToFormatPieces, ValueMap};
+///
+/// struct File { size: u32, ratio: f64 }
+///
+/// let mut vmap = ValueMap::new();
+/// vmap.insert("size", |f: &File| Some(f.size));
+/// vmap.insert("ratio", |f: &File| Some(f.ratio));
+///
+/// let fp = vmap.to_format_pieces("[{size:08}] [{ratio:.2}] [{size:<6}] [{ratio}]").unwrap();
+/// let data = File { size: 1234, ratio: 0.5 };
+/// assert_eq!(fp.render(&data), Ok("[00001234] [0.50] [1234  ] [0.5]".to_string()));
+/// ```
+pub struct ValueMap<T> {
+    map: FnvHashMap<KeyString, ValueCallback<T>>,
+    /// Formatters created for each key with its spec, along with the callback each calls.
+    formatters: Memo<(ValueCallback<T>, FormatterCallback<T>)>,
+}
+
+impl<T> ValueMap<T> {
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Self {
+            map: FnvHashMap::default(),
+            formatters: Memo::new(),
+        }
+    }
+
+    /// Register `cb` for `key`, replacing any existing callback. The callback can return anything
+    /// which converts into a `Value`.
+    pub fn insert<K, V, F>(&mut self, key: K, cb: F)
+    where
+        K: Into<KeyString>,
+        V: Into<Value>,
+        F: Fn(&T) -> Option<V> + Send + Sync + 'static,
+    {
+        let cb: ValueCallback<T> = Arc::new(move |data| cb(data).map(Into::into));
+        self.map.insert(key.into(), cb);
+    }
+
+    /// The callback registered for `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&ValueCallback<T>> {
+        self.map.get(key)
+    }
+}
+
+impl<T> Default for ValueMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for ValueMap<T> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            formatters: self.formatters.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for ValueMap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.map.keys()).finish()
+    }
+}
+
+impl<T: 'static> KeyLookup<T> for ValueMap<T> {
+    fn lookup(&self, key: &str) -> Option<FormatterCallback<T>> {
+        let (name, spec) = match key.split_once(':') {
+            Some((name, spec)) => (name, Some(Spec::parse(spec)?)),
+            None => (key, None),
+        };
+        let cb = self.map.get(name)?;
+        let (_, formatter) = self.formatters.get_or_insert(
+            key,
+            |(inner, _)| Arc::ptr_eq(inner, cb),
+            || {
+                let inner = Arc::clone(cb);
+                let formatter: FormatterCallback<T> = match spec {
+                    Some(spec) => Arc::new(move |data| spec.format(&inner(data)?)),
+                    None => Arc::new(move |data| Some(inner(data)?.to_string())),
+                };
+                (Arc::clone(cb), formatter)
+            },
+        );
+        Some(formatter)
+    }
+}
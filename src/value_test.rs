@@ -0,0 +1,96 @@
+use super::*;
+
+struct Row {
+    n: i64,
+    f: f64,
+    s: &'static str,
+    b: bool,
+}
+
+fn vmap() -> ValueMap<Row> {
+    let mut vmap = ValueMap::new();
+    vmap.insert("n", |r: &Row| Some(r.n));
+    vmap.insert("f", |r: &Row| Some(r.f));
+    vmap.insert("s", |r: &Row| Some(r.s));
+    vmap.insert("b", |r: &Row| Some(r.b));
+    vmap.insert("none", |_: &Row| None::<i64>);
+    vmap
+}
+
+#[test]
+fn format_specs() {
+    let row = Row {
+        n: -42,
+        f: 1.23456,
+        s: "héllo",
+        b: true,
+    };
+    for (tmpl, expected) in [
+        ("{n}", "-42"),
+        ("{n:06}", "-00042"),
+        ("{n:+}", "-42"),
+        ("{n:5}", "  -42"),
+        ("{n:<5}|", "-42  |"),
+        ("{n:*^7}", "**-42**"),
+        ("{f}", "1.23456"),
+        ("{f:.2}", "1.23"),
+        ("{f:+08.3}", "+001.235"),
+        ("{s}", "héllo"),
+        ("{s:.2}", "hé"),
+        ("{s:>7}", "  héllo"),
+        ("{s:07}", "héllo  "),
+        ("{b:>6}", "  true"),
+    ] {
+        let fp = vmap().to_format_pieces(tmpl).unwrap();
+        assert_eq!(fp.render(&row).unwrap(), expected, "{tmpl}");
+    }
+
+    let fp = vmap().to_format_pieces("{n:+}").unwrap();
+    assert_eq!(fp.render(&Row { n: 7, ..row }).unwrap(), "+7");
+}
+
+#[test]
+fn invalid_specs_are_unknown() {
+    for key in ["n:x", "n:.", "n:5.2.1", "missing", "missing:5"] {
+        assert_eq!(
+            vmap().to_format_pieces(format!("{{{key}}}")).map(|_| ()),
+            Err(Error::UnknownKey(key.into())),
+            "{key}"
+        );
+    }
+    let fp = vmap().to_format_pieces("{none:05}").unwrap();
+    let row = Row {
+        n: 0,
+        f: 0.0,
+        s: "",
+        b: false,
+    };
+    assert_eq!(fp.render(&row), Err(Error::NoData("none:05".into())));
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn datetime_specs() {
+    let mut vmap = ValueMap::new();
+    vmap.insert("when", |t: &time::OffsetDateTime| Some(*t));
+    vmap.insert("n", |_: &time::OffsetDateTime| Some(1));
+    let t = time::OffsetDateTime::from_unix_timestamp(1_706_933_106).unwrap();
+
+    let fp = vmap.to_format_pieces("{when:%Y-%m-%d} {when}").unwrap();
+    assert_eq!(fp.render(&t).unwrap(), "2024-02-03 2024-02-03T04:05:06Z");
+
+    let fp = vmap.to_format_pieces("{n:%Y}").unwrap();
+    assert_eq!(fp.render(&t), Err(Error::NoData("n:%Y".into())));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn refresh_keeps_formatters() {
+    let mut vmap = vmap();
+    let mut fp = vmap.to_format_pieces("{n:04} {f:.2} {s}").unwrap();
+    assert!(fp.refresh(&vmap).unwrap().is_empty());
+
+    vmap.insert("n", |r: &Row| Some(r.n + 1));
+    assert_eq!(fp.refresh(&vmap).unwrap().updated, vec!["n:04"]);
+    assert!(fp.refresh(&vmap).unwrap().is_empty());
+}